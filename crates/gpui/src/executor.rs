@@ -1,23 +1,58 @@
+pub mod barrier;
+pub mod cancellation;
+pub mod channel;
+pub mod debounce;
+pub mod instant;
+pub mod mutex;
+pub mod oneshot;
+pub mod rwlock;
+pub mod semaphore;
+pub mod task_group;
+pub mod task_local;
+pub mod task_set;
+pub mod throttle;
+pub mod watch;
+
+pub use barrier::Barrier;
+pub use cancellation::CancellationToken;
+pub use debounce::Debouncer;
+pub use instant::Instant;
+pub use task_group::TaskGroup;
+pub use task_set::TaskSet;
+pub use throttle::{ThrottleEdges, Throttler};
+
 use crate::{AppContext, PlatformDispatcher};
-use futures::{channel::mpsc, pin_mut, FutureExt};
+use async_task::Runnable;
+use futures::{
+    channel::mpsc,
+    future::{self, Either},
+    pin_mut, FutureExt,
+};
+use semaphore::Semaphore;
 use smol::prelude::*;
 use std::{
+    any::Any,
+    convert::Infallible,
     fmt::Debug,
     marker::PhantomData,
     mem,
     num::NonZeroUsize,
+    panic::{self, AssertUnwindSafe, Location},
     pin::Pin,
     rc::Rc,
     sync::{
-        atomic::{AtomicBool, AtomicUsize, Ordering::SeqCst},
+        atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering::SeqCst},
         Arc,
     },
     task::{Context, Poll},
     time::Duration,
 };
+use thiserror::Error;
 use util::TryFutureExt;
 use waker_fn::waker_fn;
 
+#[cfg(any(test, feature = "test-support"))]
+use crate::SchedulingMode;
 #[cfg(any(test, feature = "test-support"))]
 use rand::rngs::StdRng;
 
@@ -26,6 +61,8 @@ use rand::rngs::StdRng;
 #[derive(Clone)]
 pub struct BackgroundExecutor {
     dispatcher: Arc<dyn PlatformDispatcher>,
+    /// Set once [`BackgroundExecutor::shutdown`] has completed, shared between clones.
+    closed: Arc<AtomicBool>,
 }
 
 /// A pointer to the executor that is currently running,
@@ -42,6 +79,10 @@ pub struct ForegroundExecutor {
 ///
 /// If you drop a task it will be cancelled immediately. Calling [`Task::detach`] allows
 /// the task to continue running, but with no way to return a value.
+///
+/// If the task panics, the panic is resumed in whoever awaits it. If it's detached or dropped
+/// without its output being read, the panic is re-raised instead: in tests, the next time the
+/// dispatcher runs, and otherwise right away.
 #[must_use]
 #[derive(Debug)]
 pub enum Task<T> {
@@ -49,7 +90,7 @@ pub enum Task<T> {
     Ready(Option<T>),
 
     /// A task that is currently running.
-    Spawned(async_task::Task<T>),
+    Spawned(async_task::Task<std::thread::Result<T>>, TaskHandle),
 }
 
 impl<T> Task<T> {
@@ -62,9 +103,264 @@ impl<T> Task<T> {
     pub fn detach(self) {
         match self {
             Task::Ready(_) => {}
-            Task::Spawned(task) => task.detach(),
+            Task::Spawned(task, handle) => {
+                let panic = handle.detach();
+                drop(handle);
+                task.detach();
+                // Nothing will read the task's output, so surface its panic here.
+                if let Some(panic) = panic {
+                    panic.raise();
+                }
+            }
+        }
+    }
+
+    /// Whether the task has completed, without polling it. In tests, this is whether the
+    /// task's final runnable has run.
+    pub fn is_finished(&self) -> bool {
+        match self {
+            Task::Ready(_) => true,
+            Task::Spawned(task, _) => task.is_finished(),
+        }
+    }
+
+    /// Take the task's output if it has completed, or get the task back otherwise, for
+    /// harvesting results opportunistically, such as while rendering. Unlike awaiting the
+    /// task, this never schedules it to run.
+    pub fn now_or_never(mut self) -> Result<T, Self> {
+        if !self.is_finished() {
+            return Err(self);
+        }
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        match self.poll_result(&mut cx) {
+            Poll::Ready(result) => Ok(resume_panic(result)),
+            Poll::Pending => Err(self),
+        }
+    }
+
+    fn poll_result(&mut self, cx: &mut Context) -> Poll<std::thread::Result<T>> {
+        match self {
+            Task::Ready(val) => Poll::Ready(Ok(val.take().unwrap())),
+            Task::Spawned(task, handle) => Pin::new(task)
+                .poll(cx)
+                .map(|result| handle.take_panic(result)),
+        }
+    }
+
+    /// In tests, make this task's runnable the next one the dispatcher runs, regardless of
+    /// the rng, to script an exact interleaving. Does nothing if the task isn't waiting to
+    /// run, or outside of tests.
+    pub fn run_next(&self) {
+        if let Task::Spawned(_, handle) = self {
+            handle.run_next();
+        }
+    }
+
+    /// Cancel the task, returning its output if it had already completed.
+    /// In tests, any runnable of the task that is still queued is removed
+    /// from the dispatcher, so the task never runs again.
+    pub async fn cancel(self) -> Option<T> {
+        match self {
+            Task::Ready(val) => val,
+            Task::Spawned(task, handle) => {
+                handle.cancel();
+                task.cancel()
+                    .await
+                    .map(|result| resume_panic(handle.take_panic(result)))
+            }
+        }
+    }
+}
+
+/// A [`Task`] for fallible work, see [`BackgroundExecutor::spawn_fallible`].
+///
+/// Awaiting it yields the result of the work, and cancelling it yields [`TaskCancelled`]
+/// through the same error type, so callers handle both the same way.
+#[must_use]
+#[derive(Debug)]
+pub struct TryTask<T, E> {
+    task: Task<Result<T, E>>,
+}
+
+impl<T, E> TryTask<T, E> {
+    /// Cancel the task, returning its result if it had already completed, or
+    /// [`TaskCancelled`] otherwise. See [`Task::cancel`].
+    pub async fn cancel(self) -> Result<T, E>
+    where
+        E: From<TaskCancelled>,
+    {
+        self.task
+            .cancel()
+            .await
+            .unwrap_or_else(|| Err(TaskCancelled.into()))
+    }
+
+    /// Run the task to completion in the background, see [`Task::detach`].
+    pub fn detach(self) {
+        self.task.detach()
+    }
+}
+
+impl<T, E> Future for TryTask<T, E> {
+    type Output = Result<T, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        unsafe { self.map_unchecked_mut(|this| &mut this.task) }.poll(cx)
+    }
+}
+
+/// The error a [`TryTask`] resolves to when it's cancelled before completing.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Error)]
+#[error("task was cancelled")]
+pub struct TaskCancelled;
+
+/// Tracks whether a spawned [`Task`] has been detached and, in tests, identifies
+/// its runnable so that the test dispatcher can find it in its queues.
+///
+/// Dropping the handle, along with its task, reports the task's panic if the output
+/// holding it was never read, see [`TaskPanic::report_dropped`].
+#[doc(hidden)]
+pub struct TaskHandle {
+    state: Arc<parking_lot::Mutex<TaskState>>,
+    #[cfg(any(test, feature = "test-support"))]
+    runnable: Option<(Arc<dyn PlatformDispatcher>, crate::TaskId)>,
+}
+
+impl TaskHandle {
+    #[cfg(any(test, feature = "test-support"))]
+    fn new(
+        dispatcher: &Arc<dyn PlatformDispatcher>,
+        runnable: &Runnable,
+        state: Arc<parking_lot::Mutex<TaskState>>,
+    ) -> Self {
+        Self {
+            state,
+            runnable: dispatcher
+                .as_test()
+                .map(|_| (dispatcher.clone(), crate::TaskId::of(runnable))),
+        }
+    }
+
+    #[cfg(not(any(test, feature = "test-support")))]
+    fn new(
+        _: &Arc<dyn PlatformDispatcher>,
+        _: &Runnable,
+        state: Arc<parking_lot::Mutex<TaskState>>,
+    ) -> Self {
+        Self { state }
+    }
+
+    /// Swap the placeholder error that a panicked task's future returns for its panic.
+    fn take_panic<T>(&self, result: std::thread::Result<T>) -> std::thread::Result<T> {
+        result.map_err(|placeholder| {
+            self.state
+                .lock()
+                .panic
+                .take()
+                .map_or(placeholder, |panic| panic.payload)
+        })
+    }
+
+    fn cancel(&self) {
+        #[cfg(any(test, feature = "test-support"))]
+        if let Some((dispatcher, task)) = &self.runnable {
+            dispatcher.as_test().unwrap().cancel_runnable(*task);
         }
     }
+
+    fn run_next(&self) {
+        #[cfg(any(test, feature = "test-support"))]
+        if let Some((dispatcher, task)) = &self.runnable {
+            dispatcher.as_test().unwrap().promote_runnable(*task);
+        }
+    }
+
+    /// Mark the task as detached, so that a later panic is raised right away, and return its
+    /// panic if it has already panicked, as nothing will read its output now.
+    fn detach(&self) -> Option<TaskPanic> {
+        let mut state = self.state.lock();
+        state.detached = true;
+        state.panic.take()
+    }
+}
+
+impl Drop for TaskHandle {
+    fn drop(&mut self) {
+        // Nothing can read the task's output once its handle is gone, so treat it as detached.
+        if let Some(panic) = self.detach() {
+            panic.report_dropped();
+        }
+    }
+}
+
+/// What a spawned task's future shares with its [`TaskHandle`], to decide who raises its panic.
+#[derive(Default)]
+struct TaskState {
+    detached: bool,
+    /// The panic of a task that hasn't been detached, until its output is read.
+    panic: Option<TaskPanic>,
+}
+
+/// A panic caught in a spawned task, with what's needed to re-raise it.
+struct TaskPanic {
+    payload: Box<dyn Any + Send>,
+    #[cfg_attr(not(any(test, feature = "test-support")), allow(dead_code))]
+    dispatcher: Arc<dyn PlatformDispatcher>,
+    label: Option<TaskLabel>,
+    location: &'static Location<'static>,
+}
+
+impl TaskPanic {
+    /// Re-raise the panic of a task that was detached. In tests, it's handed to the test
+    /// dispatcher to re-raise the next time it runs. Otherwise it's resumed right away, unless
+    /// the thread is already panicking.
+    fn raise(self) {
+        #[cfg(any(test, feature = "test-support"))]
+        if let Some(test) = self.dispatcher.as_test() {
+            test.record_detached_panic(self.label, self.location, self.payload);
+            return;
+        }
+        if !std::thread::panicking() {
+            panic::resume_unwind(self.payload);
+        }
+    }
+
+    /// Report the panic of a task that was dropped without its output being read. In tests,
+    /// it's handed to the test dispatcher to re-raise the next time it runs. Otherwise it's
+    /// only logged, as this happens in a destructor, where resuming it could abort the
+    /// process or interrupt unrelated cleanup.
+    fn report_dropped(self) {
+        #[cfg(any(test, feature = "test-support"))]
+        if let Some(test) = self.dispatcher.as_test() {
+            test.record_detached_panic(self.label, self.location, self.payload);
+            return;
+        }
+        log::error!(
+            "dropped task {} spawned at {} panicked: {}",
+            self.label
+                .and_then(|label| label.name())
+                .unwrap_or("(unlabeled)"),
+            self.location,
+            panic_message(&*self.payload)
+        );
+    }
+}
+
+/// The message of a panic's payload, if it's a string.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+impl Debug for TaskHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TaskHandle").finish_non_exhaustive()
+    }
 }
 
 impl<E, T> Task<Result<T, E>>
@@ -87,17 +383,193 @@ impl<T> Future for Task<T> {
     type Output = T;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        match unsafe { self.get_unchecked_mut() } {
-            Task::Ready(val) => Poll::Ready(val.take().unwrap()),
-            Task::Spawned(task) => task.poll(cx),
+        unsafe { self.get_unchecked_mut() }
+            .poll_result(cx)
+            .map(resume_panic)
+    }
+}
+
+fn resume_panic<T>(result: std::thread::Result<T>) -> T {
+    result.unwrap_or_else(|payload| panic::resume_unwind(payload))
+}
+
+/// Catches a panic in a spawned future so that it can be resumed in the task's awaiter.
+/// The panic is held in the task's state until its output is read, or re-raised when its
+/// handle is dropped. A detached task has no awaiter, so its panic is re-raised right away,
+/// see [`TaskPanic::raise`]. Either way, the future returns a placeholder error.
+async fn catch_panic<R>(
+    future: impl Future<Output = R>,
+    dispatcher: Arc<dyn PlatformDispatcher>,
+    label: Option<TaskLabel>,
+    location: &'static Location<'static>,
+    state: Arc<parking_lot::Mutex<TaskState>>,
+) -> std::thread::Result<R> {
+    let payload = match AssertUnwindSafe(future).catch_unwind().await {
+        Ok(value) => return Ok(value),
+        Err(payload) => payload,
+    };
+    let panic = TaskPanic {
+        payload,
+        dispatcher,
+        label,
+        location,
+    };
+    let mut state = state.lock();
+    if state.detached {
+        drop(state);
+        panic.raise();
+    } else {
+        state.panic = Some(panic);
+    }
+    Err(Box::new(()))
+}
+
+/// The priority of a background task. In tests, higher priority tasks are far more
+/// likely to be picked by the scheduler. Real platforms map these to the closest
+/// available OS scheduling class.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default)]
+pub enum Priority {
+    /// Work that should preempt everything else, like handling input.
+    High,
+    /// The priority of tasks spawned with [`BackgroundExecutor::spawn`].
+    #[default]
+    Normal,
+    /// Work that can wait, like prefetching.
+    Low,
+}
+
+impl Priority {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Priority::High,
+            1 => Priority::Normal,
+            _ => Priority::Low,
+        }
+    }
+}
+
+crate::task_local! {
+    static CURRENT_TASK_PRIORITY: Arc<TaskPriority>;
+}
+
+/// The priority a background task is scheduled with, which is raised while it holds a
+/// [`mutex::Mutex`] that a higher priority task is waiting for, so that it gets to release it.
+pub(crate) struct TaskPriority {
+    base: Priority,
+    effective: AtomicU8,
+    /// The priority the task was boosted to by each lock it holds, keyed by the lock's address.
+    boosts: parking_lot::Mutex<Vec<(usize, Priority)>>,
+    /// Identifies the task's runnable to the test dispatcher. This must not hold on to the
+    /// task, as the task holds on to this through its schedule function.
+    #[cfg(any(test, feature = "test-support"))]
+    runnable: std::sync::OnceLock<(Arc<dyn PlatformDispatcher>, crate::TaskId)>,
+}
+
+impl TaskPriority {
+    fn new(base: Priority) -> Self {
+        Self {
+            base,
+            effective: AtomicU8::new(base as u8),
+            boosts: parking_lot::Mutex::new(Vec::new()),
+            #[cfg(any(test, feature = "test-support"))]
+            runnable: std::sync::OnceLock::new(),
+        }
+    }
+
+    /// The priority of the background task being polled, if any.
+    pub(crate) fn current() -> Option<Arc<TaskPriority>> {
+        CURRENT_TASK_PRIORITY.try_with(Arc::clone)
+    }
+
+    pub(crate) fn effective(&self) -> Priority {
+        Priority::from_u8(self.effective.load(SeqCst))
+    }
+
+    /// Schedule the task with at least the given priority until [`TaskPriority::restore`] is
+    /// called for the same lock. In tests, a runnable of the task that's already queued is
+    /// moved up too.
+    pub(crate) fn boost(&self, lock: usize, priority: Priority) {
+        let mut boosts = self.boosts.lock();
+        match boosts.iter_mut().find(|(id, _)| *id == lock) {
+            Some((_, boost)) => *boost = (*boost).min(priority),
+            None => boosts.push((lock, priority)),
+        }
+        let previous = Priority::from_u8(self.effective.fetch_min(priority as u8, SeqCst));
+        drop(boosts);
+        if priority < previous {
+            self.reprioritize(priority);
+        }
+    }
+
+    /// Drop the boost the given lock gave the task, going back to the highest priority of
+    /// the task's other boosts, or to the priority it was spawned with if it has none.
+    pub(crate) fn restore(&self, lock: usize) {
+        self.set_boost(lock, None);
+    }
+
+    /// Replace the boost the given lock gives the task, such as when the highest priority task
+    /// waiting for it gives up, unlike [`TaskPriority::boost`], which only ever raises it.
+    pub(crate) fn set_boost(&self, lock: usize, priority: Option<Priority>) {
+        let mut boosts = self.boosts.lock();
+        boosts.retain(|(id, _)| *id != lock);
+        boosts.extend(priority.map(|priority| (lock, priority)));
+        let effective = boosts
+            .iter()
+            .map(|(_, boost)| *boost)
+            .fold(self.base, Priority::min);
+        let previous = Priority::from_u8(self.effective.swap(effective as u8, SeqCst));
+        drop(boosts);
+        if effective != previous {
+            self.reprioritize(effective);
+        }
+    }
+
+    /// In tests, move a runnable of the task that's already queued to the given priority.
+    #[cfg_attr(not(any(test, feature = "test-support")), allow(unused_variables))]
+    fn reprioritize(&self, priority: Priority) {
+        #[cfg(any(test, feature = "test-support"))]
+        if let Some((dispatcher, task)) = self.runnable.get() {
+            dispatcher
+                .as_test()
+                .unwrap()
+                .reprioritize_runnable(*task, priority);
         }
     }
 }
 
+/// The error returned when a task is rejected because the dispatcher's background queue
+/// is full, see [`BackgroundExecutor::try_spawn`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Error)]
+#[error("the background queue is full")]
+pub struct Overloaded;
+
+/// The error returned by [`BackgroundExecutor::wait_for`] when the condition still doesn't
+/// hold once the timeout has elapsed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Error)]
+#[error("the condition did not hold within the timeout")]
+pub struct Timeout;
+
+/// Counters describing the health of a dispatcher, see
+/// [`BackgroundExecutor::dispatcher_metrics`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DispatcherMetrics {
+    /// The number of runnables dispatched, including timers.
+    pub dispatched: usize,
+    /// The number of runnables that have been run.
+    pub run: usize,
+    /// The number of runnables waiting to run, not counting timers that aren't due.
+    pub queue_depth: usize,
+    /// The largest `queue_depth` observed.
+    pub max_queue_depth: usize,
+}
+
 /// A task label is an opaque identifier that you can use to
 /// refer to a task in tests.
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
-pub struct TaskLabel(NonZeroUsize);
+pub struct TaskLabel {
+    id: NonZeroUsize,
+    name: Option<&'static str>,
+}
 
 impl Default for TaskLabel {
     fn default() -> Self {
@@ -109,7 +581,24 @@ impl TaskLabel {
     /// Construct a new task label.
     pub fn new() -> Self {
         static NEXT_TASK_LABEL: AtomicUsize = AtomicUsize::new(1);
-        Self(NEXT_TASK_LABEL.fetch_add(1, SeqCst).try_into().unwrap())
+        Self {
+            id: NEXT_TASK_LABEL.fetch_add(1, SeqCst).try_into().unwrap(),
+            name: None,
+        }
+    }
+
+    /// Construct a new task label with a human readable name. In tests, the
+    /// name of the most recently run task is available via `TestDispatcher::last_run_label`.
+    pub fn named(name: &'static str) -> Self {
+        Self {
+            name: Some(name),
+            ..Self::new()
+        }
+    }
+
+    /// The name given to this label by [`TaskLabel::named`], if any.
+    pub fn name(&self) -> Option<&'static str> {
+        self.name
     }
 }
 
@@ -122,21 +611,51 @@ type AnyFuture<R> = Pin<Box<dyn 'static + Send + Future<Output = R>>>;
 /// In tests this is simulated by running tasks one by one in a deterministic
 /// (but arbitrary) order controlled by the `SEED` environment variable.
 impl BackgroundExecutor {
+    /// Create an executor that runs background work and timers on the given tokio runtime
+    /// rather than the platform's threads, so that code written against gpui's executors can
+    /// run in tools that don't start a gpui app. The runtime needs its time driver enabled.
+    ///
+    /// The calling thread acts as the main thread, and work dispatched to it only runs while
+    /// it's blocked on the executor, such as in [`BackgroundExecutor::block_on_main`].
+    #[cfg(feature = "tokio")]
+    pub fn with_tokio(handle: tokio::runtime::Handle) -> Self {
+        Self::new(Arc::new(crate::platform::TokioDispatcher::new(handle)))
+    }
+
     #[doc(hidden)]
     pub fn new(dispatcher: Arc<dyn PlatformDispatcher>) -> Self {
-        Self { dispatcher }
+        Self {
+            dispatcher,
+            closed: Default::default(),
+        }
     }
 
     /// Enqueues the given future to be run to completion on a background thread.
+    #[track_caller]
     pub fn spawn<R>(&self, future: impl Future<Output = R> + Send + 'static) -> Task<R>
     where
         R: Send + 'static,
     {
-        self.spawn_internal::<R>(Box::pin(future), None)
+        self.spawn_internal::<R>(Box::pin(future), None, Priority::Normal, None)
+    }
+
+    /// Enqueues the given future to be run to completion on a background thread
+    /// with the given priority.
+    #[track_caller]
+    pub fn spawn_with_priority<R>(
+        &self,
+        priority: Priority,
+        future: impl Future<Output = R> + Send + 'static,
+    ) -> Task<R>
+    where
+        R: Send + 'static,
+    {
+        self.spawn_internal::<R>(Box::pin(future), None, priority, None)
     }
 
     /// Enqueues the given future to be run to completion on a background thread.
     /// The given label can be used to control the priority of the task in tests.
+    #[track_caller]
     pub fn spawn_labeled<R>(
         &self,
         label: TaskLabel,
@@ -145,26 +664,195 @@ impl BackgroundExecutor {
     where
         R: Send + 'static,
     {
-        self.spawn_internal::<R>(Box::pin(future), Some(label))
+        self.spawn_internal::<R>(Box::pin(future), Some(label), Priority::Normal, None)
+    }
+
+    /// Enqueues the given future to be run to completion on a background thread, as part of
+    /// the given group, which cancels it along with the group's other tasks.
+    #[track_caller]
+    pub fn spawn_in(&self, group: &TaskGroup, future: impl Future<Output = ()> + Send + 'static) {
+        group.push(self.spawn(future));
+    }
+
+    /// Runs the given future to completion on a background thread, with no way to await it.
+    /// If the future is dropped before completing, for example because the executor shut
+    /// down, a warning is logged. In tests, the labels of detached tasks that haven't
+    /// completed yet are reported by `TestDispatcher::leaked_detached_tasks`.
+    #[track_caller]
+    pub fn spawn_detached(
+        &self,
+        label: TaskLabel,
+        future: impl Future<Output = ()> + Send + 'static,
+    ) {
+        let mut guard = DetachedTaskGuard::new(&self.dispatcher, label, Location::caller());
+        self.spawn_labeled(label, async move {
+            future.await;
+            guard.completed = true;
+        })
+        .detach();
+    }
+
+    /// Enqueues the given fallible future to be run to completion on a background thread.
+    /// The returned task resolves to the future's result, or to [`TaskCancelled`] if it's
+    /// cancelled with [`TryTask::cancel`].
+    #[track_caller]
+    pub fn spawn_fallible<T, E>(
+        &self,
+        future: impl Future<Output = Result<T, E>> + Send + 'static,
+    ) -> TryTask<T, E>
+    where
+        T: Send + 'static,
+        E: Send + 'static,
+    {
+        TryTask {
+            task: self.spawn(future),
+        }
+    }
+
+    /// Like [`BackgroundExecutor::spawn`], but fails if the dispatcher's background queue is
+    /// full, so that the caller can shed load. Only the test dispatcher has a capacity, see
+    /// `TestDispatcher::set_background_capacity`, so this always succeeds elsewhere.
+    #[track_caller]
+    pub fn try_spawn<R>(
+        &self,
+        future: impl Future<Output = R> + Send + 'static,
+    ) -> Result<Task<R>, Overloaded>
+    where
+        R: Send + 'static,
+    {
+        // Only the first dispatch can be rejected, as dropping a runnable when it's woken
+        // would cancel a task that's already been accepted.
+        let dispatcher = self.dispatcher.clone();
+        self.try_spawn_internal::<R, Overloaded>(
+            Box::pin(future),
+            None,
+            Priority::Normal,
+            move |runnable| dispatcher.try_dispatch(runnable, None, Priority::Normal),
+        )
+    }
+
+    /// Runs the given function on a dedicated thread pool, for expensive synchronous work
+    /// that would otherwise hold up the tasks sharing the background threads.
+    /// In tests, the function runs inline the next time the task is polled, so scheduling
+    /// stays deterministic.
+    #[track_caller]
+    pub fn spawn_blocking<R>(&self, f: impl FnOnce() -> R + Send + 'static) -> Task<R>
+    where
+        R: Send + 'static,
+    {
+        #[cfg(any(test, feature = "test-support"))]
+        if self.dispatcher.as_test().is_some() {
+            return self.spawn(async move { f() });
+        }
+
+        self.spawn(smol::unblock(f))
+    }
+
+    /// Enqueues the given future to be run to completion on a background thread,
+    /// once `delay` has elapsed. Cancelling the task before then removes it from the
+    /// dispatcher's pending timers in tests.
+    #[track_caller]
+    pub fn spawn_after<R>(
+        &self,
+        delay: Duration,
+        future: impl Future<Output = R> + Send + 'static,
+    ) -> Task<R>
+    where
+        R: Send + 'static,
+    {
+        self.spawn_internal::<R>(Box::pin(future), None, Priority::Normal, Some(delay))
+    }
+
+    /// Enqueues the given future to be run to completion on the main thread, for futures
+    /// that aren't `Send`. It's never moved to a background thread. Must be called on
+    /// the main thread, see [`ForegroundExecutor::spawn`]. As the future isn't `Send`, this
+    /// is checked in every build, unlike [`BackgroundExecutor::assert_main_thread`].
+    #[track_caller]
+    pub fn spawn_local<R>(&self, future: impl Future<Output = R> + 'static) -> Task<R>
+    where
+        R: 'static,
+    {
+        assert!(
+            self.is_main_thread(),
+            "spawn_local must be called on the main thread, but ran on a background thread{}",
+            self.waiting_backtrace_message()
+        );
+        ForegroundExecutor::new(self.dispatcher.clone()).spawn(future)
     }
 
+    #[track_caller]
     fn spawn_internal<R: Send + 'static>(
         &self,
         future: AnyFuture<R>,
         label: Option<TaskLabel>,
+        priority: Priority,
+        delay: Option<Duration>,
     ) -> Task<R> {
         let dispatcher = self.dispatcher.clone();
-        let (runnable, task) =
-            async_task::spawn(future, move |runnable| dispatcher.dispatch(runnable, label));
-        runnable.schedule();
-        Task::Spawned(task)
+        let task = self.try_spawn_internal::<R, Infallible>(future, label, priority, |runnable| {
+            if let Some(delay) = delay {
+                dispatcher.dispatch_after(delay, runnable);
+            } else {
+                runnable.schedule();
+            }
+            Ok(())
+        });
+        match task {
+            Ok(task) => task,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Spawn a task, handing its runnable to `dispatch_first` to be scheduled for the first
+    /// time, and failing with its error if it rejects the runnable. Later wakeups are
+    /// dispatched with the task's priority. Once the executor has shut down, the runnable is
+    /// dropped instead, so the task is cancelled without ever running.
+    #[track_caller]
+    fn try_spawn_internal<R: Send + 'static, E>(
+        &self,
+        future: AnyFuture<R>,
+        label: Option<TaskLabel>,
+        priority: Priority,
+        dispatch_first: impl FnOnce(Runnable) -> Result<(), E>,
+    ) -> Result<Task<R>, E> {
+        let state = Arc::<parking_lot::Mutex<TaskState>>::default();
+        let task_priority = Arc::new(TaskPriority::new(priority));
+        let future = catch_panic(
+            CURRENT_TASK_PRIORITY.scope(task_priority.clone(), future),
+            self.dispatcher.clone(),
+            label,
+            Location::caller(),
+            state.clone(),
+        );
+        let dispatcher = self.dispatcher.clone();
+        let (runnable, task) = async_task::spawn(future, {
+            let task_priority = task_priority.clone();
+            move |runnable| dispatcher.dispatch(runnable, label, task_priority.effective())
+        });
+        #[cfg(any(test, feature = "test-support"))]
+        if self.dispatcher.as_test().is_some() {
+            task_priority
+                .runnable
+                .set((self.dispatcher.clone(), crate::TaskId::of(&runnable)))
+                .ok();
+        }
+        let handle = TaskHandle::new(&self.dispatcher, &runnable, state);
+        if self.closed.load(SeqCst) {
+            drop(runnable);
+        } else {
+            dispatch_first(runnable)?;
+        }
+        Ok(Task::Spawned(task, handle))
     }
 
     /// Used by the test harness to run an async test in a synchronous fashion.
     #[cfg(any(test, feature = "test-support"))]
     #[track_caller]
     pub fn block_test<R>(&self, future: impl Future<Output = R>) -> R {
-        if let Ok(value) = self.block_internal(false, future, usize::MAX) {
+        if let Ok(value) = self.block_internal(false, future, usize::MAX, None) {
+            if let Some(test) = self.dispatcher.as_test() {
+                test.raise_detached_panic();
+            }
             value
         } else {
             unreachable!()
@@ -173,21 +861,93 @@ impl BackgroundExecutor {
 
     /// Block the current thread until the given future resolves.
     /// Consider using `block_with_timeout` instead.
+    ///
+    /// In tests, blocking from within a runnable on the main thread also runs the main
+    /// thread's work while waiting, so the future can depend on it.
     pub fn block<R>(&self, future: impl Future<Output = R>) -> R {
-        if let Ok(value) = self.block_internal(true, future, usize::MAX) {
+        if let Ok(value) = self.block_internal(true, future, usize::MAX, None) {
+            value
+        } else {
+            unreachable!()
+        }
+    }
+
+    /// Block the main thread until the given future resolves, for driving a root future
+    /// from an application's entry point. Unlike [`BackgroundExecutor::block`], tasks spawned
+    /// on the main thread keep running while it waits, so the UI stays responsive.
+    /// Panics if called from any other thread.
+    #[track_caller]
+    pub fn block_on_main<R>(&self, future: impl Future<Output = R>) -> R {
+        if !self.is_main_thread() {
+            panic!(
+                "block_on_main must be called on the main thread{}",
+                self.waiting_backtrace_message()
+            );
+        }
+        if let Ok(value) = self.block_internal(false, future, usize::MAX, None) {
             value
         } else {
             unreachable!()
         }
     }
 
+    /// Run the given function on the main thread, blocking the current thread until
+    /// it returns. When called from the main thread, the function runs immediately.
+    ///
+    /// This deadlocks if the main thread is itself blocked on the calling thread. In tests,
+    /// where parking is forbidden by default, that surfaces as a panic instead of a hang.
+    pub fn run_on_main_blocking<R>(&self, f: impl FnOnce() -> R + Send + 'static) -> R
+    where
+        R: Send + 'static,
+    {
+        if self.dispatcher.is_main_thread() {
+            return f();
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let (runnable, task) = async_task::spawn(
+            async move {
+                tx.send(f()).ok();
+            },
+            {
+                let dispatcher = self.dispatcher.clone();
+                move |runnable| dispatcher.dispatch_on_main_thread(runnable, None)
+            },
+        );
+        runnable.schedule();
+        task.detach();
+
+        // Don't restrict the test dispatcher to background work, as we're waiting on the main thread.
+        match self.block_internal(false, rx, usize::MAX, None) {
+            Ok(Ok(value)) => value,
+            Ok(Err(_)) => panic!("main thread dropped the function before running it"),
+            Err(()) => unreachable!(),
+        }
+    }
+
+    /// Block until `future` resolves, giving up after `max_ticks`. In tests, once nothing else
+    /// can run, the clock is moved forward through pending timers up to `clock_deadline`,
+    /// giving up when it's reached.
     #[track_caller]
+    #[cfg_attr(not(any(test, feature = "test-support")), allow(unused_variables))]
     pub(crate) fn block_internal<R>(
         &self,
         background_only: bool,
         future: impl Future<Output = R>,
         mut max_ticks: usize,
+        clock_deadline: Option<Duration>,
     ) -> Result<R, ()> {
+        // A block nested within a main thread runnable drives the main thread's queues too, so
+        // that the future can wait on main thread work. The guard restores the main thread flag
+        // once it returns.
+        #[cfg(any(test, feature = "test-support"))]
+        let block_guard = self.dispatcher.as_test().map(|test| test.enter_block());
+        #[cfg(any(test, feature = "test-support"))]
+        let background_only = background_only
+            && !block_guard
+                .as_ref()
+                .map_or(false, |guard| guard.in_main_thread_runnable());
+
         pin_mut!(future);
         let unparker = self.dispatcher.unparker();
         let awoken = Arc::new(AtomicBool::new(false));
@@ -217,13 +977,17 @@ impl BackgroundExecutor {
 
                         #[cfg(any(test, feature = "test-support"))]
                         if let Some(test) = self.dispatcher.as_test() {
-                            if !test.parking_allowed() {
-                                let mut backtrace_message = String::new();
-                                if let Some(backtrace) = test.waiting_backtrace() {
-                                    backtrace_message =
-                                        format!("\nbacktrace of waiting future:\n{:?}", backtrace);
+                            if let Some(deadline) = clock_deadline {
+                                if test.skip_to_next_timer(deadline) {
+                                    continue;
                                 }
-                                panic!("parked with nothing left to run\n{:?}", backtrace_message)
+                                return Err(());
+                            }
+                            if !test.parking_allowed() {
+                                panic!(
+                                    "deadlock: blocked on a future that can never make progress{}",
+                                    self.waiting_backtrace_message()
+                                )
                             }
                         }
 
@@ -235,7 +999,14 @@ impl BackgroundExecutor {
     }
 
     /// Block the current thread until the given future resolves
-    /// or `duration` has elapsed.
+    /// or `duration` has elapsed. On timeout, the future is returned so that
+    /// the caller can keep waiting on it asynchronously.
+    ///
+    /// In tests, a timeout is simulated by giving up after a random number of ticks
+    /// (see `set_block_on_ticks`), or once `duration` has elapsed on the simulated clock,
+    /// so that a future that never resolves can't hang the test. Whenever nothing else can
+    /// run while blocked, the clock is moved forward to the next pending timer, up to the
+    /// timeout.
     pub fn block_with_timeout<R>(
         &self,
         duration: Duration,
@@ -254,6 +1025,7 @@ impl BackgroundExecutor {
         #[cfg(not(any(test, feature = "test-support")))]
         let max_ticks = usize::MAX;
 
+        let deadline = self.now() + duration;
         let mut timer = self.timer(duration).fuse();
 
         let timeout = async {
@@ -262,20 +1034,122 @@ impl BackgroundExecutor {
                 _ = timer => Err(()),
             }
         };
-        match self.block_internal(true, timeout, max_ticks) {
+        match self.block_internal(true, timeout, max_ticks, Some(deadline)) {
             Ok(Ok(value)) => Ok(value),
             _ => Err(future),
         }
     }
 
-    /// Scoped lets you start a number of tasks and waits
+    /// Waits for either of the given futures to complete, dropping the other one.
+    /// In tests, when both futures are ready at the same time, the winner is chosen
+    /// by the dispatcher's rng so that the outcome is determined by the seed.
+    pub fn select<A, B>(&self, a: A, b: B) -> impl Future<Output = Either<A::Output, B::Output>>
+    where
+        A: Future,
+        B: Future,
+    {
+        #[cfg(any(test, feature = "test-support"))]
+        let dispatcher = self.dispatcher.clone();
+        async move {
+            pin_mut!(a);
+            pin_mut!(b);
+            future::poll_fn(|cx| {
+                #[cfg(any(test, feature = "test-support"))]
+                let poll_b_first = dispatcher
+                    .as_test()
+                    .map_or(false, |test| test.gen_index(2) == 1);
+                #[cfg(not(any(test, feature = "test-support")))]
+                let poll_b_first = false;
+
+                if poll_b_first {
+                    if let Poll::Ready(b) = b.as_mut().poll(cx) {
+                        return Poll::Ready(Either::Right(b));
+                    }
+                }
+                if let Poll::Ready(a) = a.as_mut().poll(cx) {
+                    return Poll::Ready(Either::Left(a));
+                }
+                if !poll_b_first {
+                    if let Poll::Ready(b) = b.as_mut().poll(cx) {
+                        return Poll::Ready(Either::Right(b));
+                    }
+                }
+                Poll::Pending
+            })
+            .await
+        }
+    }
+
+    /// Waits for the first of the given futures to complete, dropping the others, along
+    /// with any timers they created. In tests, when several are ready at the same time,
+    /// the winner is chosen by the dispatcher's rng. Panics if `futures` is empty.
+    pub fn race<T>(
+        &self,
+        mut futures: Vec<Pin<Box<dyn Future<Output = T>>>>,
+    ) -> impl Future<Output = T> {
+        assert!(!futures.is_empty(), "can't race an empty set of futures");
+        #[cfg(any(test, feature = "test-support"))]
+        let dispatcher = self.dispatcher.clone();
+        future::poll_fn(move |cx| {
+            let len = futures.len();
+            #[cfg(any(test, feature = "test-support"))]
+            let start = dispatcher.as_test().map_or(0, |test| test.gen_index(len));
+            #[cfg(not(any(test, feature = "test-support")))]
+            let start = 0;
+
+            for offset in 0..len {
+                let ix = (start + offset) % len;
+                if let Poll::Ready(output) = futures[ix].as_mut().poll(cx) {
+                    // Drop the losers right away, rather than whenever this future is dropped.
+                    futures.clear();
+                    return Poll::Ready(output);
+                }
+            }
+            Poll::Pending
+        })
+    }
+
+    /// Waits for all of the given tasks to complete, returning their outputs in the
+    /// order the tasks were given. If any of them panics, the panic is resumed once
+    /// all of the others have completed.
+    pub fn join_all<T>(
+        &self,
+        tasks: impl IntoIterator<Item = Task<T>>,
+    ) -> impl Future<Output = Vec<T>> {
+        let mut tasks = tasks.into_iter().map(Some).collect::<Vec<_>>();
+        let mut results = tasks.iter().map(|_| None).collect::<Vec<_>>();
+        future::poll_fn(move |cx| {
+            for (task, result) in tasks.iter_mut().zip(&mut results) {
+                if let Some(pending) = task {
+                    if let Poll::Ready(output) = pending.poll_result(cx) {
+                        *result = Some(output);
+                        *task = None;
+                    }
+                }
+            }
+            if tasks.iter().any(Option::is_some) {
+                return Poll::Pending;
+            }
+
+            let results = mem::take(&mut results)
+                .into_iter()
+                .map(Option::unwrap)
+                .collect::<std::thread::Result<Vec<_>>>();
+            Poll::Ready(resume_panic(results))
+        })
+    }
+
+    /// Scoped lets you start a number of tasks and waits
     /// for all of them to complete before returning.
-    pub async fn scoped<'scope, F>(&self, scheduler: F)
+    /// The tasks may borrow from the enclosing stack, and the value returned by
+    /// `scheduler` is returned once they've all completed. In tests, if the tasks can't
+    /// make progress while parking is forbidden, this panics with the deadlock backtrace.
+    pub async fn scoped<'scope, F, R>(&self, scheduler: F) -> R
     where
-        F: FnOnce(&mut Scope<'scope>),
+        F: FnOnce(&mut Scope<'scope>) -> R,
     {
         let mut scope = Scope::new(self.clone());
-        (scheduler)(&mut scope);
+        let result = (scheduler)(&mut scope);
         let spawned = mem::take(&mut scope.futures)
             .into_iter()
             .map(|f| self.spawn(f))
@@ -283,18 +1157,299 @@ impl BackgroundExecutor {
         for task in spawned {
             task.await;
         }
+        result
+    }
+
+    /// Like [`BackgroundExecutor::scoped`], but stops early once `token` is cancelled, for
+    /// bounding a group of related work, such as all the requests for a document that's been
+    /// closed. Cancelling the token cancels the tasks that are still running, like
+    /// [`Task::cancel`], and resolves to [`TaskCancelled`] once they've been dropped. The
+    /// tasks can observe the cancellation themselves through the same token, to stop at a
+    /// point of their choosing. In tests, the queued runnables of the cancelled tasks are
+    /// removed from the dispatcher.
+    pub async fn cancelable_scope<'scope, F, R>(
+        &self,
+        token: CancellationToken,
+        scheduler: F,
+    ) -> Result<R, TaskCancelled>
+    where
+        F: FnOnce(&mut Scope<'scope>) -> R,
+    {
+        let mut scope = Scope::new(self.clone());
+        let result = (scheduler)(&mut scope);
+        let mut spawned = mem::take(&mut scope.futures)
+            .into_iter()
+            .map(|f| self.spawn(f))
+            .collect::<Vec<_>>();
+        let completed = async {
+            for task in &mut spawned {
+                task.await;
+            }
+        };
+        let outcome = self.select(completed, token.cancelled()).await;
+        match outcome {
+            Either::Left(()) => Ok(result),
+            Either::Right(()) => {
+                // The tasks may borrow from the enclosing stack, so wait for their futures to
+                // be dropped before returning.
+                for task in spawned {
+                    task.cancel().await;
+                }
+                Err(TaskCancelled)
+            }
+        }
+    }
+
+    /// Runs `f` over `items` on background threads, with at most `limit` of the resulting
+    /// futures running at once, and returns their outputs in the order of `items`. In tests,
+    /// which of the waiting futures runs next is chosen by the dispatcher's rng.
+    #[track_caller]
+    pub fn map_concurrent<I, F, Fut, T>(
+        &self,
+        items: I,
+        limit: usize,
+        f: F,
+    ) -> impl Future<Output = Vec<T>>
+    where
+        I: IntoIterator,
+        F: FnMut(I::Item) -> Fut,
+        Fut: Future<Output = T> + Send + 'static,
+        T: Send + 'static,
+    {
+        assert!(limit > 0, "map_concurrent needs a limit of at least 1");
+        future::join_all(self.spawn_limited(items, limit, f))
     }
 
-    /// Returns a task that will complete after the given duration.
+    /// Runs `f` over `items` on background threads, with at most `limit` of the resulting
+    /// futures running at once, and resolves once they've all completed. Like
+    /// [`BackgroundExecutor::map_concurrent`], but for work whose output isn't needed. If any
+    /// of the futures panics, the first panic is resumed once the rest have completed.
+    #[track_caller]
+    pub fn par_for_each<I, F, Fut>(&self, items: I, limit: usize, f: F) -> impl Future<Output = ()>
+    where
+        I: IntoIterator,
+        F: FnMut(I::Item) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        assert!(limit > 0, "par_for_each needs a limit of at least 1");
+        let tasks = self.spawn_limited(items, limit, f);
+        async move {
+            let mut first_panic = None;
+            for mut task in tasks {
+                if let Err(payload) = future::poll_fn(|cx| task.poll_result(cx)).await {
+                    first_panic.get_or_insert(payload);
+                }
+            }
+            if let Some(payload) = first_panic {
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+
+    /// Spawns a task for each of `items` that runs the future `f` returns for it, with at
+    /// most `limit` of those futures running at once.
+    #[track_caller]
+    fn spawn_limited<I, F, Fut, T>(&self, items: I, limit: usize, mut f: F) -> Vec<Task<T>>
+    where
+        I: IntoIterator,
+        F: FnMut(I::Item) -> Fut,
+        Fut: Future<Output = T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let semaphore = Arc::new(Semaphore::new(self, limit));
+        items
+            .into_iter()
+            .map(|item| {
+                let future = f(item);
+                let semaphore = semaphore.clone();
+                self.spawn(async move {
+                    let _permit = semaphore.acquire(1).await;
+                    future.await
+                })
+            })
+            .collect()
+    }
+
+    /// Yields once, letting the scheduler run other tasks before this one resumes.
+    pub fn yield_now(&self) -> impl Future<Output = ()> {
+        YieldNow { count: 1 }
+    }
+
+    /// Drives `stream` to completion and collects its items in the order they're yielded.
+    /// This yields to the executor after each item, so that a stream that's always ready
+    /// doesn't hold up other tasks, and in tests the dispatcher can interleave other work
+    /// between its items. Streams driven by timers, like [`BackgroundExecutor::interval`],
+    /// yield as the clock is advanced.
+    pub fn collect_stream<S: Stream>(&self, stream: S) -> impl Future<Output = Vec<S::Item>> {
+        let executor = self.clone();
+        async move {
+            pin_mut!(stream);
+            let mut items = Vec::new();
+            while let Some(item) = stream.next().await {
+                items.push(item);
+                executor.yield_now().await;
+            }
+            items
+        }
+    }
+
+    /// Waits for the work that's ready to run to drain, for deferring something until after
+    /// the next render. In tests, this resolves once the dispatcher has no work queued, so
+    /// work queued while waiting delays it further, and every pending `next_tick` resolves
+    /// once each time `run_until_parked` runs out of work. Elsewhere, this yields once,
+    /// resuming after the work that was already queued.
+    pub fn next_tick(&self) -> impl Future<Output = ()> {
+        NextTick {
+            dispatcher: self.dispatcher.clone(),
+            idle_count: None,
+        }
+    }
+
+    /// Run `f` once there's no work ready to run, for deferred low priority maintenance like
+    /// flushing caches while the UI is quiet. In tests, idle callbacks run in the order they
+    /// were registered each time the dispatcher runs out of work, before the clock is advanced
+    /// to fire any timers. A callback that queues more work holds the remaining ones back
+    /// until that work has drained too.
+    ///
+    /// Elsewhere, this is only best-effort: platform dispatchers can't report whether their
+    /// queues are empty, so `f` just runs as low priority background work, which may overlap
+    /// with other work. Don't rely on it for anything that must wait for the queues to drain.
+    pub fn on_idle(&self, f: impl FnOnce() + Send + 'static) {
+        #[cfg(any(test, feature = "test-support"))]
+        if let Some(test) = self.dispatcher.as_test() {
+            test.on_idle(Box::new(f));
+            return;
+        }
+
+        self.spawn_with_priority(Priority::Low, async move { f() })
+            .detach();
+    }
+
+    /// Wait for the work that's already been dispatched to run, for tearing down on quit.
+    /// Nothing happens until the returned future is polled. Once it completes, tasks spawned
+    /// on this executor or its clones are cancelled without running, and awaiting them panics.
+    ///
+    /// In tests, polling it runs everything that's queued, including pending timers, and the
+    /// dispatcher then discards anything dispatched later, including by other executors. It
+    /// must be polled outside of a runnable, such as by `block_test`.
+    ///
+    /// Elsewhere, it waits for a low priority task to run, which only approximates draining:
+    /// platform dispatchers don't guarantee that the work queued before it has finished, and
+    /// tasks that were already spawned may still be woken and run afterwards.
+    pub fn shutdown(&self) -> impl Future<Output = ()> {
+        let executor = self.clone();
+        async move {
+            #[cfg(any(test, feature = "test-support"))]
+            if let Some(test) = executor.dispatcher.as_test() {
+                test.shutdown();
+                executor.closed.store(true, SeqCst);
+                return;
+            }
+
+            executor.spawn_with_priority(Priority::Low, async {}).await;
+            executor.closed.store(true, SeqCst);
+        }
+    }
+
+    /// Returns a timer that will complete after the given duration.
     /// Depending on other concurrent tasks the elapsed duration may be longer
     /// than requested.
-    pub fn timer(&self, duration: Duration) -> Task<()> {
-        let (runnable, task) = async_task::spawn(async move {}, {
+    pub fn timer(&self, duration: Duration) -> Timer {
+        let (runnable, task) = async_task::spawn(async move { Ok(()) }, {
             let dispatcher = self.dispatcher.clone();
             move |runnable| dispatcher.dispatch_after(duration, runnable)
         });
+        let handle = TaskHandle::new(&self.dispatcher, &runnable, Default::default());
+        let task = Task::Spawned(task, handle);
+        #[cfg(any(test, feature = "test-support"))]
+        if let Some(test) = self.dispatcher.as_test() {
+            let token = test.dispatch_after_with_token(duration, runnable);
+            return Timer {
+                task,
+                token: Some((self.dispatcher.clone(), token)),
+            };
+        }
         runnable.schedule();
-        Task::Spawned(task)
+        Timer {
+            task,
+            #[cfg(any(test, feature = "test-support"))]
+            token: None,
+        }
+    }
+
+    /// Waits until the given duration has elapsed. In tests, a sleeping task is parked until
+    /// `advance_clock` moves the clock up to its deadline, at which point it's woken once.
+    pub fn sleep(&self, duration: Duration) -> impl Future<Output = ()> {
+        self.timer(duration)
+    }
+
+    /// Runs `f` once `latency` has elapsed, to stand in for blocking I/O, such as a network
+    /// request or a disk read, with a realistic completion time. In tests, `f` runs on the
+    /// simulated clock, so `advance_clock(latency)` resolves the returned future exactly once.
+    /// Dropping the future before then cancels the pending timer, and `f` never runs.
+    #[track_caller]
+    pub fn simulate_io<R>(
+        &self,
+        latency: Duration,
+        f: impl FnOnce() -> R + Send + 'static,
+    ) -> impl Future<Output = R>
+    where
+        R: Send + 'static,
+    {
+        SimulatedIo {
+            task: self.spawn_after(latency, async move { f() }),
+        }
+    }
+
+    /// Returns a stream that yields each time another `period` has elapsed since it was
+    /// created. If the stream isn't polled for several periods, it yields once for each
+    /// of them when it's next polled. In tests, advancing the clock by `3 * period` yields
+    /// exactly three items.
+    pub fn interval(&self, period: Duration) -> Interval {
+        assert!(!period.is_zero(), "interval period must be non-zero");
+        Interval {
+            executor: self.clone(),
+            period,
+            next_tick: self.now() + period,
+            timer: None,
+        }
+    }
+
+    /// Returns a stream that yields the last value passed to [`Debouncer::trigger`] once
+    /// `duration` has passed without another trigger.
+    pub fn debounce<T>(&self, duration: Duration) -> Debouncer<T> {
+        Debouncer::new(self, duration)
+    }
+
+    /// Returns a stream that yields at most one of the values passed to
+    /// [`Throttler::trigger`] on each of the given edges of every `duration`-long window.
+    pub fn throttle<T>(&self, duration: Duration, edges: ThrottleEdges) -> Throttler<T> {
+        Throttler::new(self, duration, edges)
+    }
+
+    /// The current time, consistent with `timer`. In tests, this is the simulated
+    /// time, which only moves forward when the clock is advanced.
+    pub fn now(&self) -> Duration {
+        self.dispatcher.now()
+    }
+
+    /// The current time as an [`Instant`], for measuring durations in a way that tests can
+    /// control. Prefer this to `std::time::Instant::now`, which ignores the simulated clock.
+    pub fn instant_now(&self) -> Instant {
+        Instant::from_dispatcher_time(self.now())
+    }
+
+    /// Log a warning whenever a task takes longer than `threshold` to run on the main
+    /// thread, to catch jank. In tests, tasks take no time to run unless they're given
+    /// a simulated cost with `TestDispatcher::set_run_cost`.
+    pub fn set_jank_threshold(&self, threshold: Duration) {
+        self.dispatcher.set_jank_threshold(threshold);
+    }
+
+    /// Counters describing how much work the dispatcher has handled, for diagnostics.
+    /// Returns `None` if the platform's dispatcher doesn't keep them.
+    pub fn dispatcher_metrics(&self) -> Option<DispatcherMetrics> {
+        self.dispatcher.metrics()
     }
 
     /// in tests, start_waiting lets you indicate which task is waiting (for debugging only)
@@ -333,6 +1488,13 @@ impl BackgroundExecutor {
         self.dispatcher.as_test().unwrap().tick(false)
     }
 
+    /// in tests, panic if any task is queued, any timer is pending, or a task is being polled.
+    #[cfg(any(test, feature = "test-support"))]
+    #[track_caller]
+    pub fn assert_idle(&self) {
+        self.dispatcher.as_test().unwrap().assert_idle()
+    }
+
     /// in tests, run all tasks that are ready to run. If after doing so
     /// the test still has outstanding tasks, this will panic. (See also `allow_parking`)
     #[cfg(any(test, feature = "test-support"))]
@@ -340,6 +1502,104 @@ impl BackgroundExecutor {
         self.dispatcher.as_test().unwrap().run_until_parked()
     }
 
+    /// in tests, run all tasks, letting every timer fire, until there's nothing left to run.
+    /// Returns the simulated time at which that happened. See
+    /// [`TestDispatcher::run_to_completion`](crate::TestDispatcher::run_to_completion).
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn run_to_completion(&self) -> Duration {
+        self.dispatcher.as_test().unwrap().run_to_completion()
+    }
+
+    /// in tests, run tasks until the given future resolves, advancing the clock to the next
+    /// timer whenever nothing else can run. Panics if the future can't make progress, or if
+    /// it hasn't resolved once the clock has moved forward by more than `max_time`.
+    #[cfg(any(test, feature = "test-support"))]
+    #[track_caller]
+    pub fn run_until<R>(&self, future: impl Future<Output = R>, max_time: Option<Duration>) -> R {
+        let test = self.dispatcher.as_test().unwrap();
+        let deadline = max_time.map(|max_time| test.now() + max_time);
+        pin_mut!(future);
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        loop {
+            if let Poll::Ready(result) = future.as_mut().poll(&mut cx) {
+                return result;
+            }
+            if test.tick(false) {
+                continue;
+            }
+
+            match test.next_timer_deadline() {
+                Some(next_timer) if deadline.map_or(true, |deadline| next_timer <= deadline) => {
+                    if test.advance_clock_to_next_timer().is_none() {
+                        panic!(
+                            "run_until: the clock is frozen, so the future can never resolve{}",
+                            self.waiting_backtrace_message()
+                        );
+                    }
+                }
+                Some(_) => panic!(
+                    "run_until: the future did not resolve within {:?}{}",
+                    max_time.unwrap(),
+                    self.waiting_backtrace_message()
+                ),
+                None => panic!(
+                    "run_until: the future can never resolve, as there's nothing left to run{}",
+                    self.waiting_backtrace_message()
+                ),
+            }
+        }
+    }
+
+    /// in tests, check `condition` once the work that's ready has run, and then again each time
+    /// the clock has been advanced by `poll_every`, running the work that becomes ready, until it
+    /// holds or `timeout` has elapsed. Returns how much simulated time it took to hold. If the
+    /// clock doesn't move when advanced, because it's frozen or has a drift of 0, this times
+    /// out as soon as that happens, as the timeout could never be reached.
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn wait_for(
+        &self,
+        mut condition: impl FnMut() -> bool,
+        poll_every: Duration,
+        timeout: Duration,
+    ) -> Result<Duration, Timeout> {
+        assert!(
+            !poll_every.is_zero(),
+            "wait_for must poll at a nonzero interval"
+        );
+        let test = self.dispatcher.as_test().unwrap();
+        let start = test.now();
+        test.run_until_parked();
+        let mut stalled = false;
+        loop {
+            let elapsed = test.now() - start;
+            if condition() {
+                return Ok(elapsed);
+            }
+            if elapsed >= timeout || stalled {
+                return Err(Timeout);
+            }
+            let before = test.now();
+            test.advance_clock(poll_every.min(timeout - elapsed));
+            stalled = test.now() == before;
+        }
+    }
+
+    /// in tests, poll `future` once, panicking if it's ready, to check that something doesn't
+    /// happen yet, such as a guarded operation proceeding before its precondition holds.
+    /// The future is handed back so that it can be driven to completion afterwards.
+    #[cfg(any(test, feature = "test-support"))]
+    #[track_caller]
+    pub fn assert_pending<'a, F>(&self, mut future: Pin<&'a mut F>) -> Pin<&'a mut F>
+    where
+        F: Future + ?Sized,
+    {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        if future.as_mut().poll(&mut cx).is_ready() {
+            panic!("expected the future to be pending, but it was ready");
+        }
+        future
+    }
+
     /// in tests, prevents `run_until_parked` from panicking if there are outstanding tasks.
     /// This is useful when you are integrating other (non-GPUI) futures, like disk access, that
     /// do take real async time to run.
@@ -360,6 +1620,13 @@ impl BackgroundExecutor {
         self.dispatcher.as_test().unwrap().rng()
     }
 
+    /// in tests, returns an rng seeded by the `SEED` environment variable, for generating data
+    /// like ids deterministically. Each call returns a different rng, none of which affect scheduling.
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn data_rng(&self) -> StdRng {
+        self.dispatcher.as_test().unwrap().data_rng()
+    }
+
     /// How many CPUs are available to the dispatcher
     pub fn num_cpus(&self) -> usize {
         num_cpus::get()
@@ -370,6 +1637,67 @@ impl BackgroundExecutor {
         self.dispatcher.is_main_thread()
     }
 
+    /// Panic if not called on the main thread. This is checked in debug builds and
+    /// always in tests, and does nothing in release builds.
+    #[track_caller]
+    pub fn assert_main_thread(&self) {
+        if self.thread_assertions_enabled() && !self.is_main_thread() {
+            panic!(
+                "expected to be on the main thread, but running on a background thread{}",
+                self.waiting_backtrace_message()
+            );
+        }
+    }
+
+    /// Panic if called on the main thread, for work that must stay off of it. This is
+    /// checked in debug builds and always in tests, and does nothing in release builds.
+    #[track_caller]
+    pub fn assert_not_main_thread(&self) {
+        if self.thread_assertions_enabled() && self.is_main_thread() {
+            panic!(
+                "expected to be on a background thread, but running on the main thread{}",
+                self.waiting_backtrace_message()
+            );
+        }
+    }
+
+    /// Pick which of `len` waiters to wake. In tests this is driven by the dispatcher's rng,
+    /// unless it's in `SchedulingMode::Fifo`. Otherwise it's the one that has waited longest.
+    #[cfg_attr(not(any(test, feature = "test-support")), allow(unused_variables))]
+    pub(crate) fn choose_waiter(&self, len: usize) -> usize {
+        #[cfg(any(test, feature = "test-support"))]
+        if len > 1 {
+            if let Some(test) = self.dispatcher.as_test() {
+                if test.scheduling() == SchedulingMode::Fifo {
+                    return 0;
+                }
+                return test.gen_index(len);
+            }
+        }
+        0
+    }
+
+    fn thread_assertions_enabled(&self) -> bool {
+        #[cfg(any(test, feature = "test-support"))]
+        if self.dispatcher.as_test().is_some() {
+            return true;
+        }
+        cfg!(debug_assertions)
+    }
+
+    /// In tests, the backtrace recorded by `start_waiting`, formatted to be appended to a panic message.
+    fn waiting_backtrace_message(&self) -> String {
+        #[cfg(any(test, feature = "test-support"))]
+        if let Some(backtrace) = self
+            .dispatcher
+            .as_test()
+            .and_then(|test| test.waiting_backtrace())
+        {
+            return format!("\nbacktrace of waiting future:\n{:?}", backtrace);
+        }
+        String::new()
+    }
+
     #[cfg(any(test, feature = "test-support"))]
     /// in tests, control the number of ticks that `block_with_timeout` will run before timing out.
     pub fn set_block_on_ticks(&self, range: std::ops::RangeInclusive<usize>) {
@@ -388,22 +1716,291 @@ impl ForegroundExecutor {
     }
 
     /// Enqueues the given Task to run on the main thread at some point in the future.
+    #[track_caller]
     pub fn spawn<R>(&self, future: impl Future<Output = R> + 'static) -> Task<R>
     where
         R: 'static,
     {
+        self.spawn_internal::<R>(Box::pin(future), None)
+    }
+
+    /// Enqueues the given Task to run on the main thread at some point in the future.
+    /// The given label is reported by the test dispatcher when the task runs.
+    #[track_caller]
+    pub fn spawn_labeled<R>(
+        &self,
+        label: TaskLabel,
+        future: impl Future<Output = R> + 'static,
+    ) -> Task<R>
+    where
+        R: 'static,
+    {
+        self.spawn_internal::<R>(Box::pin(future), Some(label))
+    }
+
+    /// Enqueues the given Task to run on the main thread as part of the given group, which
+    /// cancels it along with the group's other tasks.
+    #[track_caller]
+    pub fn spawn_in(&self, group: &TaskGroup, future: impl Future<Output = ()> + 'static) {
+        group.push(self.spawn(future));
+    }
+
+    /// Enqueues the given Task on the foreground queue of the test dispatcher with the given
+    /// id, see `TestDispatcher::fork`, so that it runs as part of that queue's work rather
+    /// than this executor's.
+    #[cfg(any(test, feature = "test-support"))]
+    #[track_caller]
+    pub fn spawn_on<R>(&self, id: usize, future: impl Future<Output = R> + 'static) -> Task<R>
+    where
+        R: 'static,
+    {
+        let dispatcher = self.dispatcher.as_test().unwrap().with_id(id);
+        ForegroundExecutor::new(Arc::new(dispatcher)).spawn(future)
+    }
+
+    #[track_caller]
+    fn spawn_internal<R: 'static>(
+        &self,
+        future: AnyLocalFuture<R>,
+        label: Option<TaskLabel>,
+    ) -> Task<R> {
+        let state = Arc::<parking_lot::Mutex<TaskState>>::default();
+        let future = catch_panic(
+            future,
+            self.dispatcher.clone(),
+            label,
+            Location::caller(),
+            state.clone(),
+        );
+        #[cfg(any(test, feature = "test-support"))]
+        let future = MainThreadOnly {
+            future,
+            dispatcher: self.dispatcher.clone(),
+        };
         let dispatcher = self.dispatcher.clone();
-        fn inner<R: 'static>(
-            dispatcher: Arc<dyn PlatformDispatcher>,
-            future: AnyLocalFuture<R>,
-        ) -> Task<R> {
-            let (runnable, task) = async_task::spawn_local(future, move |runnable| {
-                dispatcher.dispatch_on_main_thread(runnable)
-            });
-            runnable.schedule();
-            Task::Spawned(task)
+        let (runnable, task) = async_task::spawn_local(future, move |runnable| {
+            dispatcher.dispatch_on_main_thread(runnable, label)
+        });
+        let handle = TaskHandle::new(&self.dispatcher, &runnable, state);
+        runnable.schedule();
+        Task::Spawned(task, handle)
+    }
+}
+
+/// Timer is a future that completes once its duration has elapsed, see [`BackgroundExecutor::timer`].
+///
+/// Dropping a timer before it fires cancels it. In tests, this also removes it from the
+/// dispatcher's pending timers, so that it is no longer considered by `advance_clock`.
+#[must_use]
+pub struct Timer {
+    task: Task<()>,
+    /// In tests, the token of the timer's entry in the dispatcher, for cancelling it.
+    #[cfg(any(test, feature = "test-support"))]
+    token: Option<(Arc<dyn PlatformDispatcher>, crate::TimerToken)>,
+}
+
+impl Future for Timer {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut self.task).poll(cx)
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        #[cfg(any(test, feature = "test-support"))]
+        if let Some((dispatcher, token)) = &self.token {
+            if dispatcher.as_test().unwrap().cancel_timer(*token) {
+                return;
+            }
+        }
+        if let Task::Spawned(_, handle) = &self.task {
+            handle.cancel();
+        }
+    }
+}
+
+/// The future returned by [`BackgroundExecutor::simulate_io`], which cancels its pending
+/// timer when dropped, like [`Timer`].
+struct SimulatedIo<R> {
+    task: Task<R>,
+}
+
+impl<R> Future for SimulatedIo<R> {
+    type Output = R;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut self.task).poll(cx)
+    }
+}
+
+impl<R> Drop for SimulatedIo<R> {
+    fn drop(&mut self) {
+        if let Task::Spawned(_, handle) = &self.task {
+            handle.cancel();
+        }
+    }
+}
+
+/// Interval is a stream that yields at a fixed period, see [`BackgroundExecutor::interval`].
+///
+/// Dropping it cancels its pending timer, which in tests removes it from the dispatcher.
+#[must_use]
+pub struct Interval {
+    executor: BackgroundExecutor,
+    period: Duration,
+    next_tick: Duration,
+    timer: Option<Timer>,
+}
+
+impl Stream for Interval {
+    type Item = ();
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            let now = this.executor.now();
+            if now >= this.next_tick {
+                this.timer = None;
+                this.next_tick += this.period;
+                return Poll::Ready(Some(()));
+            }
+
+            // Re-arm for whatever is left of the period, as the clock may have moved
+            // since the previous tick was yielded.
+            let timer = this
+                .timer
+                .get_or_insert_with(|| this.executor.timer(this.next_tick - now));
+            match Pin::new(timer).poll(cx) {
+                Poll::Ready(()) => this.timer = None,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Tracks a task spawned with [`BackgroundExecutor::spawn_detached`], warning if it's dropped
+/// before completing.
+struct DetachedTaskGuard {
+    name: &'static str,
+    location: &'static Location<'static>,
+    completed: bool,
+    #[cfg(any(test, feature = "test-support"))]
+    registration: Option<(Arc<dyn PlatformDispatcher>, usize)>,
+}
+
+impl DetachedTaskGuard {
+    #[cfg_attr(not(any(test, feature = "test-support")), allow(unused_variables))]
+    fn new(
+        dispatcher: &Arc<dyn PlatformDispatcher>,
+        label: TaskLabel,
+        location: &'static Location<'static>,
+    ) -> Self {
+        let name = label.name().unwrap_or("(unlabeled)");
+        Self {
+            name,
+            location,
+            completed: false,
+            #[cfg(any(test, feature = "test-support"))]
+            registration: dispatcher.as_test().map(|test| {
+                (
+                    dispatcher.clone(),
+                    test.register_detached_task(name, location),
+                )
+            }),
+        }
+    }
+}
+
+impl Drop for DetachedTaskGuard {
+    fn drop(&mut self) {
+        #[cfg(any(test, feature = "test-support"))]
+        if let Some((dispatcher, id)) = &self.registration {
+            dispatcher.as_test().unwrap().unregister_detached_task(*id);
+        }
+        if !self.completed {
+            log::warn!(
+                "detached task {:?} spawned at {} was dropped before completing",
+                self.name,
+                self.location
+            );
+        }
+    }
+}
+
+struct NextTick {
+    #[cfg_attr(not(any(test, feature = "test-support")), allow(dead_code))]
+    dispatcher: Arc<dyn PlatformDispatcher>,
+    /// The dispatcher's idle count when this started waiting, or 0 outside of tests.
+    idle_count: Option<usize>,
+}
+
+impl Future for NextTick {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        #[cfg(any(test, feature = "test-support"))]
+        if let Some(test) = self.dispatcher.as_test() {
+            let current = test.idle_count();
+            if self.idle_count.map_or(false, |waiting| current > waiting) {
+                return Poll::Ready(());
+            }
+            self.idle_count.get_or_insert(current);
+            test.wake_when_idle(cx.waker());
+            return Poll::Pending;
+        }
+
+        if self.idle_count.is_some() {
+            Poll::Ready(())
+        } else {
+            self.idle_count = Some(0);
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+/// Wraps the future of a task spawned on the main thread, asserting that it's only polled on
+/// the main thread. The test dispatcher runs its background runnables with `is_main_thread`
+/// unset, so a `!Send` task whose runnable is routed to the background panics there rather
+/// than silently running off the main thread.
+#[cfg(any(test, feature = "test-support"))]
+struct MainThreadOnly<F> {
+    future: F,
+    dispatcher: Arc<dyn PlatformDispatcher>,
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl<F: Future> Future for MainThreadOnly<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // SAFETY: The future is never moved out of `self`, and `dispatcher` isn't pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        debug_assert!(
+            this.dispatcher.is_main_thread(),
+            "a task spawned on the main thread was run in the background"
+        );
+        unsafe { Pin::new_unchecked(&mut this.future) }.poll(cx)
+    }
+}
+
+pub(crate) struct YieldNow {
+    pub(crate) count: usize,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if self.count > 0 {
+            self.count -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        } else {
+            Poll::Ready(())
         }
-        inner::<R>(dispatcher, Box::pin(future))
     }
 }
 
@@ -459,3 +2056,725 @@ impl<'a> Drop for Scope<'a> {
         self.executor.block(self.rx.next());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestDispatcher;
+    use collections::BTreeSet;
+
+    #[test]
+    fn test_map_concurrent_respects_limit() {
+        const LIMIT: usize = 3;
+
+        let max_active = Arc::new(AtomicUsize::new(0));
+        for seed in 0..10 {
+            let dispatcher = TestDispatcher::new(seed);
+            let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+            let active = Arc::new(AtomicUsize::new(0));
+            let outputs = executor.block_test(executor.map_concurrent(0..10, LIMIT, |item| {
+                let executor = executor.clone();
+                let active = active.clone();
+                let max_active = max_active.clone();
+                async move {
+                    let now_active = active.fetch_add(1, SeqCst) + 1;
+                    max_active.fetch_max(now_active, SeqCst);
+                    executor.simulate_random_delay().await;
+                    active.fetch_sub(1, SeqCst);
+                    item * 2
+                }
+            }));
+            assert_eq!(outputs, (0..10).map(|item| item * 2).collect::<Vec<_>>());
+            assert!(max_active.load(SeqCst) <= LIMIT, "seed {seed}");
+        }
+        // The limit was reached with some seed.
+        assert_eq!(max_active.load(SeqCst), LIMIT);
+    }
+
+    #[test]
+    fn test_par_for_each_settles_before_panicking() {
+        const LIMIT: usize = 2;
+
+        for seed in 0..10 {
+            let dispatcher = TestDispatcher::new(seed);
+            let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+            let active = Arc::new(AtomicUsize::new(0));
+            let completed = Arc::new(AtomicUsize::new(0));
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                executor.block_test(executor.par_for_each(0..6, LIMIT, |item| {
+                    let executor = executor.clone();
+                    let active = active.clone();
+                    let completed = completed.clone();
+                    async move {
+                        assert!(active.fetch_add(1, SeqCst) < LIMIT, "seed {seed}");
+                        executor.simulate_random_delay().await;
+                        active.fetch_sub(1, SeqCst);
+                        if item == 0 {
+                            panic!("item 0 failed");
+                        }
+                        completed.fetch_add(1, SeqCst);
+                    }
+                }))
+            }));
+            assert!(result.is_err(), "seed {seed}");
+            assert_eq!(completed.load(SeqCst), 5, "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn test_panic_in_dropped_task_fails_the_run() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let task = executor.spawn(async { panic!("held task failed") });
+        dispatcher.run_until_parked();
+
+        // The panic is held for whoever awaits the task, until the task is dropped.
+        drop(task);
+        let payload =
+            panic::catch_unwind(AssertUnwindSafe(|| dispatcher.run_until_parked())).unwrap_err();
+        let message = payload.downcast_ref::<String>().unwrap();
+        assert!(message.contains("held task failed"), "{message}");
+    }
+
+    #[test]
+    fn test_block_with_timeout_uses_the_simulated_clock() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        dispatcher.set_block_on_ticks(100..=100);
+        let task = executor.spawn({
+            let executor = executor.clone();
+            async move {
+                executor.timer(Duration::from_millis(500)).await;
+                7
+            }
+        });
+        let result = executor.block_with_timeout(Duration::from_secs(1), task);
+        assert_eq!(result.ok(), Some(7));
+        assert_eq!(executor.now(), Duration::from_millis(500));
+
+        let result = executor.block_with_timeout(Duration::from_secs(1), future::pending::<()>());
+        assert!(result.is_err());
+        assert_eq!(executor.now(), Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn test_shutdown_drains_when_polled() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let ran = Arc::new(AtomicUsize::new(0));
+        let spawn = || {
+            let ran = ran.clone();
+            executor
+                .spawn(async move {
+                    ran.fetch_add(1, SeqCst);
+                })
+                .detach();
+        };
+
+        spawn();
+        let shutdown = executor.shutdown();
+        assert_eq!(ran.load(SeqCst), 0);
+        executor.block_test(shutdown);
+        assert_eq!(ran.load(SeqCst), 1);
+
+        spawn();
+        dispatcher.run_until_parked();
+        assert_eq!(ran.load(SeqCst), 1);
+    }
+
+    #[test]
+    fn test_spawning_after_shutdown_is_rejected() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        executor.block_test(executor.shutdown());
+
+        let dispatched = dispatcher.metrics().unwrap().dispatched;
+        let ran = Arc::new(AtomicBool::new(false));
+        executor
+            .spawn({
+                let ran = ran.clone();
+                async move { ran.store(true, SeqCst) }
+            })
+            .detach();
+        assert!(executor.try_spawn(async {}).is_ok());
+        // The executor rejects the runnables itself, before the dispatcher sees them.
+        assert_eq!(dispatcher.metrics().unwrap().dispatched, dispatched);
+
+        dispatcher.run_until_parked();
+        assert!(!ran.load(SeqCst));
+    }
+
+    #[test]
+    fn test_try_spawn_tracks_task_priority() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let task = executor
+            .try_spawn(async { TaskPriority::current().map(|priority| priority.effective()) })
+            .unwrap();
+        assert_eq!(executor.block_test(task), Some(Priority::Normal));
+    }
+
+    #[test]
+    fn test_dropping_a_timer_cancels_only_that_timer() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let first = executor.timer(Duration::from_secs(1));
+        let second = executor.timer(Duration::from_secs(1));
+        let fired = executor.timer(Duration::from_millis(1));
+        dispatcher.advance_clock(Duration::from_millis(1));
+        assert_eq!(dispatcher.pending_timer_count(), 2);
+
+        drop(fired);
+        drop(second);
+        assert_eq!(dispatcher.pending_timer_count(), 1);
+        dispatcher.advance_clock(Duration::from_secs(1));
+        executor.block_test(first);
+    }
+
+    #[test]
+    fn test_finished_tasks_release_the_dispatcher() {
+        let test = TestDispatcher::new(0);
+        let dispatcher: Arc<dyn PlatformDispatcher> = Arc::new(test.clone());
+        let executor = BackgroundExecutor::new(dispatcher.clone());
+        let strong_count = Arc::strong_count(&dispatcher);
+
+        let awaited = executor.spawn(async { 7 });
+        let dropped = executor.spawn(async {});
+        drop(dropped);
+        assert_eq!(executor.block_test(awaited), 7);
+        test.run_until_parked();
+        assert_eq!(Arc::strong_count(&dispatcher), strong_count);
+    }
+
+    #[test]
+    fn test_now_or_never_does_not_schedule() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let task = executor.spawn({
+            let executor = executor.clone();
+            async move {
+                executor.timer(Duration::from_secs(1)).await;
+                7
+            }
+        });
+        dispatcher.run_until_parked();
+
+        let metrics = executor.dispatcher_metrics();
+        assert!(!task.is_finished());
+        let task = task.now_or_never().unwrap_err();
+        assert_eq!(executor.dispatcher_metrics(), metrics);
+
+        dispatcher.advance_clock(Duration::from_secs(1));
+        assert!(task.is_finished());
+        assert_eq!(task.now_or_never().ok(), Some(7));
+        assert!(Task::ready(()).is_finished());
+    }
+
+    #[test]
+    fn test_wait_for_polls_on_the_simulated_clock() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let done = Arc::new(AtomicBool::new(false));
+        executor
+            .spawn({
+                let executor = executor.clone();
+                let done = done.clone();
+                async move {
+                    executor.timer(Duration::from_millis(250)).await;
+                    done.store(true, SeqCst);
+                }
+            })
+            .detach();
+
+        let poll_every = Duration::from_millis(100);
+        let timeout = Duration::from_secs(1);
+        assert_eq!(
+            executor.wait_for(|| done.load(SeqCst), poll_every, timeout),
+            Ok(Duration::from_millis(300))
+        );
+        assert_eq!(
+            executor.wait_for(|| false, poll_every, timeout),
+            Err(Timeout)
+        );
+        assert_eq!(executor.now(), Duration::from_millis(1300));
+
+        dispatcher.freeze_clock();
+        assert_eq!(
+            executor.wait_for(|| false, poll_every, timeout),
+            Err(Timeout)
+        );
+        assert_eq!(executor.now(), Duration::from_millis(1300));
+    }
+
+    #[test]
+    fn test_simulate_io_completes_after_latency() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let latency = Duration::from_millis(200);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let read = |calls: &Arc<AtomicUsize>| {
+            let calls = calls.clone();
+            move || calls.fetch_add(1, SeqCst) + 1
+        };
+
+        let task = executor.spawn(executor.simulate_io(latency, read(&calls)));
+        dispatcher.advance_clock(latency / 2);
+        assert_eq!(calls.load(SeqCst), 0);
+        assert!(!task.is_finished());
+        dispatcher.advance_clock(latency / 2);
+        assert_eq!(task.now_or_never().ok(), Some(1));
+
+        drop(executor.simulate_io(latency, read(&calls)));
+        dispatcher.advance_clock(latency);
+        assert_eq!(calls.load(SeqCst), 1);
+        dispatcher.assert_idle();
+    }
+
+    #[test]
+    fn test_cancelable_scope_cancels_remaining_tasks() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let token = CancellationToken::new();
+        let completed = Arc::new(AtomicUsize::new(0));
+        let scope = executor.spawn({
+            let executor = executor.clone();
+            let token = token.clone();
+            let completed = completed.clone();
+            async move {
+                executor
+                    .cancelable_scope(token, |scope| {
+                        for secs in 1..=3 {
+                            let executor = &executor;
+                            let completed = &completed;
+                            scope.spawn(async move {
+                                executor.timer(Duration::from_secs(secs)).await;
+                                completed.fetch_add(1, SeqCst);
+                            });
+                        }
+                        "scheduled"
+                    })
+                    .await
+            }
+        });
+
+        dispatcher.advance_clock(Duration::from_secs(1));
+        assert_eq!(completed.load(SeqCst), 1);
+        token.cancel();
+        dispatcher.run_until_parked();
+        assert_eq!(scope.now_or_never().ok(), Some(Err(TaskCancelled)));
+        assert_eq!(dispatcher.pending_timer_count(), 0);
+        assert_eq!(completed.load(SeqCst), 1);
+
+        let scope = executor.cancelable_scope(CancellationToken::new(), |_| "scheduled");
+        assert_eq!(executor.block_test(scope), Ok("scheduled"));
+    }
+
+    #[test]
+    fn test_assert_pending_leaves_future_usable() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let (tx, rx) = oneshot::channel();
+        pin_mut!(rx);
+        let rx = executor.assert_pending(rx);
+        tx.send(7).unwrap();
+        assert_eq!(executor.block_test(rx), Ok(7));
+
+        let ready = future::ready(());
+        pin_mut!(ready);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            executor.assert_pending(ready);
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_collect_stream_of_interval() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let period = Duration::from_millis(100);
+        let ticks = executor.spawn({
+            let executor = executor.clone();
+            async move {
+                let interval = executor.interval(period).map(|()| executor.now()).take(3);
+                executor.collect_stream(interval).await
+            }
+        });
+        dispatcher.advance_clock(period * 3);
+        assert_eq!(executor.block_test(ticks), [period, period * 2, period * 3]);
+    }
+
+    #[test]
+    #[should_panic(expected = "spawn_local must be called on the main thread")]
+    fn test_spawn_local_off_the_main_thread_panics() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let task = executor.spawn({
+            let executor = executor.clone();
+            async move { executor.spawn_local(async {}).detach() }
+        });
+        executor.block_test(task);
+    }
+
+    #[test]
+    fn test_collect_stream_yields_between_items() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let mut items = executor.spawn(executor.collect_stream(futures::stream::iter(0..3)));
+        assert_eq!(dispatcher.poll_n(1), 1);
+        executor.assert_pending(Pin::new(&mut items));
+        assert_eq!(executor.block_test(items), [0, 1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "deadlock: blocked on a future that can never make progress")]
+    fn test_blocking_on_a_stuck_future_reports_a_deadlock() {
+        let executor = BackgroundExecutor::new(Arc::new(TestDispatcher::new(0)));
+        // The sender is kept alive, but nothing will ever send on it.
+        let (_tx, rx) = futures::channel::oneshot::channel::<()>();
+        executor.spawn(async {}).detach();
+        executor.block_test(rx).ok();
+    }
+
+    #[test]
+    fn test_timer_fires_at_its_deadline_unless_dropped() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let fired = Arc::new(AtomicBool::new(false));
+        executor
+            .spawn({
+                let timer = executor.timer(Duration::from_secs(1));
+                let fired = fired.clone();
+                async move {
+                    timer.await;
+                    fired.store(true, SeqCst);
+                }
+            })
+            .detach();
+        dispatcher.advance_clock(Duration::from_millis(999));
+        assert!(!fired.load(SeqCst));
+        dispatcher.advance_clock(Duration::from_millis(1));
+        assert!(fired.load(SeqCst));
+
+        let dropped = executor.timer(Duration::from_secs(1));
+        drop(dropped);
+        assert_eq!(dispatcher.advance_clock_to_next_timer(), None);
+    }
+
+    #[test]
+    fn test_select_breaks_ties_by_seed() {
+        fn winner(seed: u64) -> Either<(), ()> {
+            let executor = BackgroundExecutor::new(Arc::new(TestDispatcher::new(seed)));
+            executor.block_test(executor.select(future::ready(()), future::ready(())))
+        }
+
+        let winners = (0..20).map(winner).collect::<Vec<_>>();
+        assert_eq!((0..20).map(winner).collect::<Vec<_>>(), winners);
+        assert!(winners.contains(&Either::Left(())));
+        assert!(winners.contains(&Either::Right(())));
+
+        let executor = BackgroundExecutor::new(Arc::new(TestDispatcher::new(0)));
+        for _ in 0..10 {
+            let selected = executor.block_test(executor.select(future::pending::<()>(), async {}));
+            assert_eq!(selected, Either::Right(()));
+        }
+    }
+
+    #[test]
+    fn test_cancel_removes_the_queued_runnable() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let ran = Arc::new(AtomicBool::new(false));
+        let task = executor.spawn({
+            let ran = ran.clone();
+            async move { ran.store(true, SeqCst) }
+        });
+        assert_eq!(dispatcher.pending_task_count(), 1);
+
+        let output = executor.block_test(task.cancel());
+        assert_eq!(output, None);
+        assert_eq!(dispatcher.pending_task_count(), 0);
+        assert!(!ran.load(SeqCst));
+
+        let task = executor.spawn(async { 1 });
+        dispatcher.run_until_parked();
+        assert_eq!(executor.block_test(task.cancel()), Some(1));
+    }
+
+    #[test]
+    fn test_run_on_main_blocking_from_a_background_task() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let task = executor.spawn({
+            let executor = executor.clone();
+            async move {
+                let on_main_thread = executor.run_on_main_blocking({
+                    let executor = executor.clone();
+                    move || executor.is_main_thread()
+                });
+                (on_main_thread, executor.is_main_thread())
+            }
+        });
+        assert_eq!(executor.block_test(task), (true, false));
+
+        // On the main thread, the function runs right away.
+        assert!(executor.is_main_thread());
+        assert_eq!(executor.run_on_main_blocking(|| 1), 1);
+        assert_eq!(dispatcher.pending_task_count(), 0);
+    }
+
+    #[test]
+    fn test_spawn_blocking_runs_when_polled() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let ran = Arc::new(AtomicBool::new(false));
+        let task = executor.spawn_blocking({
+            let ran = ran.clone();
+            move || {
+                ran.store(true, SeqCst);
+                2 + 2
+            }
+        });
+        assert!(!ran.load(SeqCst));
+        assert_eq!(dispatcher.pending_task_count(), 1);
+
+        assert_eq!(executor.block_test(task), 4);
+        assert!(ran.load(SeqCst));
+    }
+
+    #[test]
+    fn test_scoped_tasks_borrow_from_the_stack() {
+        let executor = BackgroundExecutor::new(Arc::new(TestDispatcher::new(0)));
+        let mut results = vec![0; 4];
+        let scheduled = executor.block_test(executor.scoped(|scope| {
+            for (ix, result) in results.iter_mut().enumerate() {
+                scope.spawn(async move { *result = ix * 10 });
+            }
+            "scheduled"
+        }));
+        assert_eq!(scheduled, "scheduled");
+        assert_eq!(results, [0, 10, 20, 30]);
+    }
+
+    #[test]
+    fn test_yield_now_lets_other_tasks_run() {
+        let dispatcher = TestDispatcher::new(0);
+        dispatcher.set_scheduling(SchedulingMode::Fifo);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let events = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        for name in ["a", "b"] {
+            executor
+                .spawn({
+                    let executor = executor.clone();
+                    let events = events.clone();
+                    async move {
+                        events.lock().push(format!("{name} before"));
+                        executor.yield_now().await;
+                        events.lock().push(format!("{name} after"));
+                    }
+                })
+                .detach();
+        }
+
+        dispatcher.run_until_parked();
+        assert_eq!(
+            *events.lock(),
+            ["a before", "b before", "a after", "b after"]
+        );
+    }
+
+    #[test]
+    fn test_thread_affinity_assertions() {
+        let executor = BackgroundExecutor::new(Arc::new(TestDispatcher::new(0)));
+        executor.assert_main_thread();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| executor.assert_not_main_thread()));
+        assert!(result.is_err());
+
+        let task = executor.spawn({
+            let executor = executor.clone();
+            async move {
+                executor.assert_not_main_thread();
+                panic::catch_unwind(AssertUnwindSafe(|| executor.assert_main_thread())).is_err()
+            }
+        });
+        assert!(executor.block_test(task));
+    }
+
+    #[test]
+    fn test_now_is_the_simulated_time() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        assert_eq!(executor.now(), Duration::ZERO);
+
+        let task = executor.spawn({
+            let executor = executor.clone();
+            async move {
+                let start = executor.now();
+                executor.timer(Duration::from_secs(5)).await;
+                executor.now() - start
+            }
+        });
+        dispatcher.advance_clock(Duration::from_secs(2));
+        assert_eq!(executor.now(), Duration::from_secs(2));
+        dispatcher.advance_clock(Duration::from_secs(3));
+        assert_eq!(executor.block_test(task), Duration::from_secs(5));
+        assert_eq!(executor.now(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_spawn_after_starts_once_the_delay_elapses() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let started = Arc::new(AtomicBool::new(false));
+        let task = executor.spawn_after(Duration::from_secs(1), {
+            let started = started.clone();
+            async move { started.store(true, SeqCst) }
+        });
+        dispatcher.advance_clock(Duration::from_millis(999));
+        assert!(!started.load(SeqCst));
+        dispatcher.advance_clock(Duration::from_millis(1));
+        assert!(started.load(SeqCst));
+        executor.block_test(task);
+
+        let cancelled = executor.spawn_after(Duration::from_secs(1), async {});
+        assert_eq!(dispatcher.pending_timer_count(), 1);
+        assert_eq!(executor.block_test(cancelled.cancel()), None);
+        assert_eq!(dispatcher.pending_timer_count(), 0);
+    }
+
+    #[test]
+    fn test_join_all_returns_outputs_in_the_order_given() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let tasks = [3, 1, 2].map(|secs| {
+            executor.spawn({
+                let executor = executor.clone();
+                async move {
+                    executor.timer(Duration::from_secs(secs)).await;
+                    secs
+                }
+            })
+        });
+        let done = Arc::new(AtomicBool::new(false));
+        let joined = executor.spawn({
+            let joined = executor.join_all(tasks);
+            let done = done.clone();
+            async move {
+                let outputs = joined.await;
+                done.store(true, SeqCst);
+                outputs
+            }
+        });
+        dispatcher.advance_clock(Duration::from_secs(2));
+        assert!(!done.load(SeqCst));
+        dispatcher.advance_clock(Duration::from_secs(1));
+        assert!(done.load(SeqCst));
+        assert_eq!(executor.block_test(joined), vec![3, 1, 2]);
+    }
+
+    #[test]
+    fn test_interval_ticks_once_per_elapsed_period() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let task = executor.spawn({
+            let mut interval = executor.interval(Duration::from_secs(2));
+            let ticks = ticks.clone();
+            async move {
+                while interval.next().await.is_some() {
+                    ticks.fetch_add(1, SeqCst);
+                }
+            }
+        });
+        dispatcher.advance_clock(Duration::from_secs(6));
+        assert_eq!(ticks.load(SeqCst), 3);
+        dispatcher.advance_clock(Duration::from_secs(5));
+        assert_eq!(ticks.load(SeqCst), 5);
+
+        // Polling late yields once for each period that was missed.
+        let mut interval = executor.interval(Duration::from_secs(1));
+        dispatcher.advance_clock(Duration::from_secs(3));
+        for _ in 0..3 {
+            assert_eq!(executor.block_test(interval.next()), Some(()));
+        }
+
+        drop(task);
+        drop(interval);
+        dispatcher.run_until_parked();
+        assert_eq!(dispatcher.pending_timer_count(), 0);
+    }
+
+    #[test]
+    fn test_race_returns_the_first_to_finish() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let fast = executor.timer(Duration::from_secs(1));
+        let slow = executor.timer(Duration::from_secs(3));
+        let race = executor.race(vec![
+            Box::pin(async move {
+                slow.await;
+                "slow"
+            }),
+            Box::pin(async move {
+                fast.await;
+                "fast"
+            }),
+        ]);
+        dispatcher.advance_clock(Duration::from_secs(1));
+        assert_eq!(executor.block_test(race), "fast");
+        assert_eq!(dispatcher.pending_timer_count(), 0);
+
+        let winners = (0..20)
+            .map(|seed| {
+                let executor = BackgroundExecutor::new(Arc::new(TestDispatcher::new(seed)));
+                executor.block_test(executor.race(vec![
+                    Box::pin(async { "first" }),
+                    Box::pin(async { "second" }),
+                ]))
+            })
+            .collect::<BTreeSet<_>>();
+        assert_eq!(winners, BTreeSet::from(["first", "second"]));
+    }
+
+    #[test]
+    fn test_run_until_stops_once_the_future_resolves() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let later = executor.spawn_after(Duration::from_secs(10), async {});
+        let task = executor.spawn({
+            let executor = executor.clone();
+            async move {
+                executor.timer(Duration::from_secs(3)).await;
+                executor.now()
+            }
+        });
+        assert_eq!(executor.run_until(task, None), Duration::from_secs(3));
+        assert_eq!(executor.now(), Duration::from_secs(3));
+        assert_eq!(dispatcher.pending_timer_count(), 1);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            executor.run_until(later, Some(Duration::from_secs(5)))
+        }));
+        let message = panic_message(&*result.unwrap_err()).to_string();
+        assert!(
+            message.starts_with("run_until: the future did not resolve within 5s"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn test_unfinished_detached_tasks_are_reported() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        executor.spawn_detached(TaskLabel::named("quick"), async {});
+        executor.spawn_detached(TaskLabel::named("waiting"), {
+            let executor = executor.clone();
+            async move { executor.timer(Duration::from_secs(1)).await }
+        });
+        assert_eq!(dispatcher.leaked_detached_tasks(), ["quick", "waiting"]);
+
+        dispatcher.run_until_parked();
+        assert_eq!(dispatcher.leaked_detached_tasks(), ["waiting"]);
+        dispatcher.advance_clock(Duration::from_secs(1));
+        assert!(dispatcher.leaked_detached_tasks().is_empty());
+    }
+}
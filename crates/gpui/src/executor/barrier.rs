@@ -0,0 +1,187 @@
+//! An async barrier, for lining up several tasks at a rendezvous point.
+//!
+//! Each task that calls [`Barrier::wait`] is parked until the barrier's count of tasks have
+//! arrived, and then they're all released. Releasing wakes the waiting tasks, which schedules
+//! them as runnables rather than running them inline. In tests, the order in which they're
+//! woken is picked by the dispatcher's rng, or is first come first served in
+//! `SchedulingMode::Fifo`, so the interleavings after the rendezvous can be explored by seed.
+//! The barrier can be reused once it has released its tasks.
+
+use crate::BackgroundExecutor;
+use std::{
+    fmt,
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use util::post_inc;
+
+/// A reusable barrier for async code, see the [module docs](self).
+pub struct Barrier {
+    executor: BackgroundExecutor,
+    count: usize,
+    state: parking_lot::Mutex<State>,
+}
+
+struct State {
+    waiters: Vec<(usize, Waker)>,
+    next_waiter_id: usize,
+    generation: usize,
+}
+
+impl Barrier {
+    /// Create a barrier that releases tasks once `count` of them are waiting. The executor is
+    /// used to choose the order in which they're woken in tests.
+    pub fn new(executor: &BackgroundExecutor, count: usize) -> Self {
+        assert!(count > 0, "a barrier must wait for at least one task");
+        Self {
+            executor: executor.clone(),
+            count,
+            state: parking_lot::Mutex::new(State {
+                waiters: Vec::new(),
+                next_waiter_id: 0,
+                generation: 0,
+            }),
+        }
+    }
+
+    /// Wait until the barrier's count of tasks are waiting, including this one.
+    pub fn wait(&self) -> BarrierWait<'_> {
+        BarrierWait {
+            barrier: self,
+            waiter: None,
+        }
+    }
+}
+
+impl fmt::Debug for Barrier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Barrier")
+            .field("count", &self.count)
+            .field("waiting", &self.state.lock().waiters.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// The future returned by [`Barrier::wait`].
+#[must_use]
+pub struct BarrierWait<'a> {
+    barrier: &'a Barrier,
+    /// The generation this task is waiting in, and its id within it.
+    waiter: Option<(usize, usize)>,
+}
+
+impl Future for BarrierWait<'_> {
+    type Output = BarrierWaitResult;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let barrier = self.barrier;
+        let mut state = barrier.state.lock();
+        if let Some((generation, id)) = self.waiter {
+            if state.generation != generation {
+                self.waiter = None;
+                return Poll::Ready(BarrierWaitResult { is_leader: false });
+            }
+            if let Some((_, waker)) = state.waiters.iter_mut().find(|(ix, _)| *ix == id) {
+                if !waker.will_wake(cx.waker()) {
+                    *waker = cx.waker().clone();
+                }
+            }
+            return Poll::Pending;
+        }
+
+        if state.waiters.len() + 1 < barrier.count {
+            let id = post_inc(&mut state.next_waiter_id);
+            state.waiters.push((id, cx.waker().clone()));
+            self.waiter = Some((state.generation, id));
+            return Poll::Pending;
+        }
+
+        // This is the last task to arrive, so release the others.
+        state.generation += 1;
+        let mut waiters = mem::take(&mut state.waiters);
+        drop(state);
+        while !waiters.is_empty() {
+            let ix = barrier.executor.choose_waiter(waiters.len());
+            let (_, waker) = waiters.remove(ix);
+            waker.wake();
+        }
+        Poll::Ready(BarrierWaitResult { is_leader: true })
+    }
+}
+
+impl Drop for BarrierWait<'_> {
+    fn drop(&mut self) {
+        let Some((generation, id)) = self.waiter else {
+            return;
+        };
+        let mut state = self.barrier.state.lock();
+        if state.generation == generation {
+            state.waiters.retain(|(waiter_id, _)| *waiter_id != id);
+        }
+    }
+}
+
+/// What a task learns when it's released by a [`Barrier`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BarrierWaitResult {
+    is_leader: bool,
+}
+
+impl BarrierWaitResult {
+    /// Whether this task was the last to arrive, which released the others. Exactly one
+    /// task is the leader each time the barrier releases.
+    pub fn is_leader(&self) -> bool {
+        self.is_leader
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ForegroundExecutor, TestDispatcher};
+    use collections::BTreeSet;
+    use parking_lot::Mutex;
+    use std::{rc::Rc, sync::Arc};
+
+    #[test]
+    fn test_released_tasks_run_in_seeded_order() {
+        const TASKS: usize = 3;
+
+        let mut orders = BTreeSet::new();
+        for seed in 0..50 {
+            let dispatcher = TestDispatcher::new(seed);
+            let background = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+            let foreground = ForegroundExecutor::new(Arc::new(dispatcher.clone()));
+            let barrier = Rc::new(Barrier::new(&background, TASKS));
+            let order = Arc::new(Mutex::new(Vec::new()));
+            let leaders = Arc::new(Mutex::new(0));
+            for ix in 0..TASKS {
+                let barrier = barrier.clone();
+                let order = order.clone();
+                let leaders = leaders.clone();
+                foreground
+                    .spawn(async move {
+                        order.lock().push(None);
+                        if barrier.wait().await.is_leader() {
+                            *leaders.lock() += 1;
+                        } else {
+                            order.lock().push(Some(ix));
+                        }
+                    })
+                    .detach();
+            }
+            dispatcher.run_until_parked();
+
+            // Every task arrived before any was released.
+            let order = order.lock().clone();
+            assert_eq!(order[..TASKS], [None; TASKS]);
+            assert_eq!(*leaders.lock(), 1);
+            orders.insert(order[TASKS..].to_vec());
+        }
+
+        // Both of the waiting tasks were released first for some seed.
+        assert_eq!(orders.len(), 2);
+    }
+}
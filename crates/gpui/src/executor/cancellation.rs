@@ -0,0 +1,213 @@
+//! A token for cooperatively cancelling async work.
+//!
+//! Work that should stop early checks [`CancellationToken::is_cancelled`], or awaits
+//! [`CancellationToken::cancelled`] alongside its real work with `BackgroundExecutor::select`.
+//! Cancelling wakes every waiting task, which schedules it as a runnable rather than running it
+//! inline, so in tests the waiters resume the next time the dispatcher runs, in the order in
+//! which they started waiting.
+
+use parking_lot::Mutex;
+use std::{
+    fmt,
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::{Arc, Weak},
+    task::{Context, Poll, Waker},
+};
+use util::post_inc;
+
+/// A cloneable handle for cancelling work, see the [module docs](self).
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    cancelled: bool,
+    waiters: Vec<(usize, Waker)>,
+    next_waiter_id: usize,
+    children: Vec<Weak<Mutex<State>>>,
+}
+
+impl CancellationToken {
+    /// Create a token that hasn't been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a token that's cancelled along with this one, but can also be cancelled
+    /// on its own without affecting this one.
+    pub fn child_token(&self) -> Self {
+        let child = Self::new();
+        let mut state = self.state.lock();
+        if state.cancelled {
+            child.state.lock().cancelled = true;
+        } else {
+            state.children.retain(|child| child.strong_count() > 0);
+            state.children.push(Arc::downgrade(&child.state));
+        }
+        child
+    }
+
+    /// Cancel this token and all of its children, waking every task waiting in
+    /// [`CancellationToken::cancelled`]. Cancelling again does nothing.
+    pub fn cancel(&self) {
+        cancel(&self.state);
+    }
+
+    /// Whether this token, or one of its ancestors, has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.state.lock().cancelled
+    }
+
+    /// Wait until this token is cancelled.
+    pub fn cancelled(&self) -> Cancelled<'_> {
+        Cancelled {
+            token: self,
+            waiter_id: None,
+        }
+    }
+}
+
+fn cancel(state: &Mutex<State>) {
+    let mut state = state.lock();
+    if mem::replace(&mut state.cancelled, true) {
+        return;
+    }
+    let waiters = mem::take(&mut state.waiters);
+    let children = mem::take(&mut state.children);
+    drop(state);
+
+    for (_, waker) in waiters {
+        waker.wake();
+    }
+    for child in children {
+        if let Some(child) = child.upgrade() {
+            cancel(&child);
+        }
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish_non_exhaustive()
+    }
+}
+
+/// The future returned by [`CancellationToken::cancelled`].
+#[must_use]
+pub struct Cancelled<'a> {
+    token: &'a CancellationToken,
+    waiter_id: Option<usize>,
+}
+
+impl Future for Cancelled<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.token.state.lock();
+        if state.cancelled {
+            self.waiter_id = None;
+            return Poll::Ready(());
+        }
+
+        let id = *self
+            .waiter_id
+            .get_or_insert_with(|| post_inc(&mut state.next_waiter_id));
+        if let Some((_, waker)) = state
+            .waiters
+            .iter_mut()
+            .find(|(waiter_id, _)| *waiter_id == id)
+        {
+            if !waker.will_wake(cx.waker()) {
+                *waker = cx.waker().clone();
+            }
+        } else {
+            state.waiters.push((id, cx.waker().clone()));
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Cancelled<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.waiter_id {
+            self.token
+                .state
+                .lock()
+                .waiters
+                .retain(|(waiter_id, _)| *waiter_id != id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BackgroundExecutor, TestDispatcher};
+    use futures::{task::noop_waker_ref, FutureExt as _};
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+    #[test]
+    fn test_cancelling_a_token_cancels_its_descendants() {
+        let parent = CancellationToken::new();
+        let child = parent.child_token();
+        let grandchild = child.child_token();
+        let sibling = parent.child_token();
+
+        child.cancel();
+        assert!(child.is_cancelled());
+        assert!(grandchild.is_cancelled());
+        assert!(!parent.is_cancelled());
+        assert!(!sibling.is_cancelled());
+
+        parent.cancel();
+        assert!(sibling.is_cancelled());
+        assert!(parent.child_token().is_cancelled());
+    }
+
+    #[test]
+    fn test_cancel_wakes_pending_waiters() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let token = CancellationToken::new();
+        let woken = Arc::new(AtomicUsize::new(0));
+        for token in [token.clone(), token.child_token()] {
+            let woken = woken.clone();
+            executor
+                .spawn(async move {
+                    token.cancelled().await;
+                    woken.fetch_add(1, SeqCst);
+                })
+                .detach();
+        }
+
+        dispatcher.run_until_parked();
+        assert_eq!(woken.load(SeqCst), 0);
+        token.cancel();
+        dispatcher.run_until_parked();
+        assert_eq!(woken.load(SeqCst), 2);
+    }
+
+    #[test]
+    fn test_dropping_cancelled_removes_its_waiter() {
+        let token = CancellationToken::new();
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut first = token.cancelled();
+        let mut second = token.cancelled();
+        assert!(first.poll_unpin(&mut cx).is_pending());
+        assert!(second.poll_unpin(&mut cx).is_pending());
+        // Polling again doesn't register the waiter twice.
+        assert!(first.poll_unpin(&mut cx).is_pending());
+        assert_eq!(token.state.lock().waiters.len(), 2);
+
+        drop(first);
+        assert_eq!(token.state.lock().waiters.len(), 1);
+        token.cancel();
+        assert!(second.poll_unpin(&mut cx).is_ready());
+    }
+}
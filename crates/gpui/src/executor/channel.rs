@@ -0,0 +1,358 @@
+//! Async channels that wake their peers through the executor.
+//!
+//! A blocked sender or receiver is resumed by waking the task that's waiting on it, so in tests
+//! the wakeup is scheduled by the `TestDispatcher` like any other runnable. Given a fixed seed,
+//! producer/consumer flows interleave the same way on every run, and `run_until_parked` delivers
+//! every message that can be delivered.
+//!
+//! However the tasks involved are scheduled, messages are received in the order they were sent.
+//! A sender waiting for capacity only adds its message once there's room, so the messages of a
+//! single producer are never reordered, even when it's resumed after the consumer has run.
+
+use futures::Stream;
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    fmt, future, mem,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+
+/// Create a channel that holds at most `capacity` messages. Sending on a full channel waits
+/// until the receiver makes room.
+pub fn bounded<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "bounded channel capacity must be non-zero");
+    channel(Some(capacity))
+}
+
+/// Create a channel that holds any number of messages. Sending never waits.
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+    channel(None)
+}
+
+fn channel<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let state = Arc::new(Mutex::new(State {
+        queue: VecDeque::new(),
+        capacity,
+        sender_count: 1,
+        receiver_alive: true,
+        receiver_waker: None,
+        sender_wakers: Vec::new(),
+    }));
+    (
+        Sender {
+            state: state.clone(),
+        },
+        Receiver { state },
+    )
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    capacity: Option<usize>,
+    sender_count: usize,
+    receiver_alive: bool,
+    receiver_waker: Option<Waker>,
+    sender_wakers: Vec<Waker>,
+}
+
+impl<T> State<T> {
+    fn is_full(&self) -> bool {
+        self.capacity
+            .map_or(false, |capacity| self.queue.len() >= capacity)
+    }
+}
+
+/// The sending half of a channel. Cloning it creates another sender for the same channel.
+pub struct Sender<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Send a message, waiting for capacity if the channel is bounded and full.
+    /// Fails if the receiver has been dropped, returning the message.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = Some(value);
+        future::poll_fn(|cx| {
+            let mut state = self.state.lock();
+            let message = value.take().unwrap();
+            if !state.receiver_alive {
+                return Poll::Ready(Err(SendError(message)));
+            }
+            if state.is_full() {
+                value = Some(message);
+                if !state
+                    .sender_wakers
+                    .iter()
+                    .any(|waker| waker.will_wake(cx.waker()))
+                {
+                    state.sender_wakers.push(cx.waker().clone());
+                }
+                return Poll::Pending;
+            }
+            state.queue.push_back(message);
+            let receiver_waker = state.receiver_waker.take();
+            drop(state);
+            if let Some(waker) = receiver_waker {
+                waker.wake();
+            }
+            Poll::Ready(Ok(()))
+        })
+        .await
+    }
+
+    /// Send a message without waiting. Fails if the channel is full or the receiver
+    /// has been dropped, returning the message.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let mut state = self.state.lock();
+        if !state.receiver_alive {
+            return Err(TrySendError::Closed(value));
+        }
+        if state.is_full() {
+            return Err(TrySendError::Full(value));
+        }
+        state.queue.push_back(value);
+        let receiver_waker = state.receiver_waker.take();
+        drop(state);
+        if let Some(waker) = receiver_waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Returns true if the receiver has been dropped.
+    pub fn is_closed(&self) -> bool {
+        !self.state.lock().receiver_alive
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.state.lock().sender_count += 1;
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        state.sender_count -= 1;
+        let receiver_waker = if state.sender_count == 0 {
+            state.receiver_waker.take()
+        } else {
+            None
+        };
+        drop(state);
+        if let Some(waker) = receiver_waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender").finish_non_exhaustive()
+    }
+}
+
+/// The receiving half of a channel. Also usable as a [`Stream`] of messages.
+pub struct Receiver<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Receiver<T> {
+    /// Receive the next message, waiting until one is sent.
+    /// Returns `None` once every sender has been dropped and the channel is empty.
+    pub async fn recv(&mut self) -> Option<T> {
+        future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        let mut state = self.state.lock();
+        if let Some(value) = state.queue.pop_front() {
+            let sender_wakers = mem::take(&mut state.sender_wakers);
+            drop(state);
+            for waker in sender_wakers {
+                waker.wake();
+            }
+            Poll::Ready(Some(value))
+        } else if state.sender_count == 0 {
+            Poll::Ready(None)
+        } else {
+            state.receiver_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        state.receiver_alive = false;
+        // Drop queued messages and wake senders outside the lock, as either may re-enter the channel.
+        let queue = mem::take(&mut state.queue);
+        let sender_wakers = mem::take(&mut state.sender_wakers);
+        drop(state);
+        drop(queue);
+        for waker in sender_wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver").finish_non_exhaustive()
+    }
+}
+
+/// The error returned by [`Sender::send`] when the receiver has been dropped.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendError").finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sending on a closed channel")
+    }
+}
+
+impl<T> std::error::Error for SendError<T> {}
+
+/// The error returned by [`Sender::try_send`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is bounded and has no spare capacity.
+    Full(T),
+    /// The receiver has been dropped.
+    Closed(T),
+}
+
+impl<T> TrySendError<T> {
+    /// Recover the message that couldn't be sent.
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(value) | TrySendError::Closed(value) => value,
+        }
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "Full(..)"),
+            TrySendError::Closed(_) => write!(f, "Closed(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "sending on a full channel"),
+            TrySendError::Closed(_) => write!(f, "sending on a closed channel"),
+        }
+    }
+}
+
+impl<T> std::error::Error for TrySendError<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BackgroundExecutor, TestDispatcher};
+    use futures::StreamExt;
+    use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+
+    #[test]
+    fn test_messages_are_received_in_send_order() {
+        const MESSAGE_COUNT: usize = 100;
+
+        for seed in 0..20 {
+            for capacity in [Some(1), Some(4), None] {
+                let dispatcher = TestDispatcher::new(seed);
+                let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+                let (tx, mut rx) = channel(capacity);
+                executor
+                    .spawn({
+                        let executor = executor.clone();
+                        async move {
+                            for sequence in 0..MESSAGE_COUNT {
+                                if sequence % 10 == 0 {
+                                    executor.simulate_random_delay().await;
+                                }
+                                tx.send(sequence).await.unwrap();
+                            }
+                        }
+                    })
+                    .detach();
+                let received = executor.block_test({
+                    let executor = executor.clone();
+                    async move {
+                        let mut received = Vec::new();
+                        while let Some(sequence) = rx.next().await {
+                            received.push(sequence);
+                            executor.simulate_random_delay().await;
+                        }
+                        received
+                    }
+                });
+                assert_eq!(
+                    received,
+                    (0..MESSAGE_COUNT).collect::<Vec<_>>(),
+                    "seed {seed}, capacity {capacity:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_bounded_send_waits_for_capacity() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let (tx, mut rx) = bounded(1);
+        tx.try_send(1).unwrap();
+        assert_eq!(tx.try_send(2), Err(TrySendError::Full(2)));
+
+        let sent = Arc::new(AtomicBool::new(false));
+        executor
+            .spawn({
+                let sent = sent.clone();
+                async move {
+                    tx.send(2).await.unwrap();
+                    sent.store(true, SeqCst);
+                }
+            })
+            .detach();
+        dispatcher.run_until_parked();
+        assert!(!sent.load(SeqCst));
+
+        assert_eq!(executor.block_test(rx.recv()), Some(1));
+        dispatcher.run_until_parked();
+        assert!(sent.load(SeqCst));
+        assert_eq!(executor.block_test(rx.recv()), Some(2));
+        // The only sender was dropped once its task finished.
+        assert_eq!(executor.block_test(rx.recv()), None);
+
+        let (tx, rx) = unbounded();
+        drop(rx);
+        assert!(tx.is_closed());
+        assert_eq!(tx.try_send(1), Err(TrySendError::Closed(1)));
+    }
+}
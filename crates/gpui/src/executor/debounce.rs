@@ -0,0 +1,156 @@
+//! Debouncing, for reacting to a burst of events once it has settled, such as searching as
+//! the user types.
+//!
+//! Each [`Debouncer::trigger`] replaces the pending value and restarts the timer, dropping the
+//! previous one, which in tests removes it from the dispatcher. The stream yields the latest
+//! value once the timer fires, so advancing the clock by less than the duration between
+//! triggers yields nothing, and advancing past it yields the last value exactly once.
+
+use crate::{executor::Timer, BackgroundExecutor};
+use futures::Stream;
+use parking_lot::Mutex;
+use std::{
+    fmt,
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+/// A stream of the values passed to [`Debouncer::trigger`] that were followed by a quiet
+/// period, see the [module docs](self). Clones share the same pending value, so one can be
+/// triggered while another is polled. The stream never ends, and should only be polled
+/// through one clone at a time.
+pub struct Debouncer<T> {
+    executor: BackgroundExecutor,
+    duration: Duration,
+    state: Arc<Mutex<State<T>>>,
+}
+
+struct State<T> {
+    value: Option<T>,
+    timer: Option<Timer>,
+    waker: Option<Waker>,
+}
+
+impl<T> Debouncer<T> {
+    /// Create a debouncer that yields a value once `duration` has passed without another
+    /// trigger.
+    pub fn new(executor: &BackgroundExecutor, duration: Duration) -> Self {
+        Self {
+            executor: executor.clone(),
+            duration,
+            state: Arc::new(Mutex::new(State {
+                value: None,
+                timer: None,
+                waker: None,
+            })),
+        }
+    }
+
+    /// Replace the pending value with `value`, and restart the quiet period.
+    pub fn trigger(&self, value: T) {
+        let timer = self.executor.timer(self.duration);
+        let mut state = self.state.lock();
+        state.value = Some(value);
+        let previous_timer = mem::replace(&mut state.timer, Some(timer));
+        let waker = state.waker.take();
+        drop(state);
+
+        // Cancelling the timer takes the dispatcher's lock, so do it outside ours.
+        drop(previous_timer);
+        // Wake the stream so that it polls the new timer.
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Discard the pending value, if any, without yielding it.
+    pub fn cancel(&self) {
+        let mut state = self.state.lock();
+        state.value = None;
+        let timer = state.timer.take();
+        drop(state);
+        drop(timer);
+    }
+}
+
+impl<T> Clone for Debouncer<T> {
+    fn clone(&self) -> Self {
+        Self {
+            executor: self.executor.clone(),
+            duration: self.duration,
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Debouncer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Debouncer")
+            .field("duration", &self.duration)
+            .field("pending", &self.state.lock().value.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> Stream for Debouncer<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let mut state = self.state.lock();
+        if let Some(timer) = state.timer.as_mut() {
+            if Pin::new(timer).poll(cx).is_ready() {
+                state.timer = None;
+                if let Some(value) = state.value.take() {
+                    return Poll::Ready(Some(value));
+                }
+            }
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestDispatcher;
+    use futures::StreamExt;
+
+    #[test]
+    fn test_rapid_triggers_yield_the_last_value_once() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let debouncer = executor.debounce(Duration::from_millis(100));
+        let yielded = Arc::new(Mutex::new(Vec::new()));
+        executor
+            .spawn({
+                let mut debouncer = debouncer.clone();
+                let yielded = yielded.clone();
+                async move {
+                    while let Some(value) = debouncer.next().await {
+                        yielded.lock().push(value);
+                    }
+                }
+            })
+            .detach();
+
+        for value in 0..5 {
+            debouncer.trigger(value);
+            dispatcher.advance_clock(Duration::from_millis(99));
+            assert!(yielded.lock().is_empty());
+        }
+        dispatcher.advance_clock(Duration::from_millis(1));
+        assert_eq!(*yielded.lock(), [4]);
+
+        dispatcher.advance_clock(Duration::from_secs(1));
+        assert_eq!(*yielded.lock(), [4]);
+
+        debouncer.trigger(5);
+        dispatcher.advance_clock(Duration::from_millis(100));
+        assert_eq!(*yielded.lock(), [4, 5]);
+    }
+}
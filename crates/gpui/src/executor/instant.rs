@@ -0,0 +1,110 @@
+//! A point in time read from the executor's clock, for code that would otherwise call
+//! `std::time::Instant::now` directly.
+//!
+//! An [`Instant`] is taken with `BackgroundExecutor::instant_now`, and is consistent with
+//! `BackgroundExecutor::now` and the executor's timers. On real platforms it follows the
+//! system's monotonic clock, while in tests it's the simulated time, so the durations between
+//! instants only grow as the clock is advanced, and are the same for every run of a seed.
+//! Instants taken from different executors are comparable as long as they share a dispatcher.
+
+use std::{
+    fmt,
+    ops::{Add, AddAssign, Sub, SubAssign},
+    time::Duration,
+};
+
+/// A measurement of the executor's monotonic clock, see the [module docs](self).
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(Duration);
+
+impl Instant {
+    pub(crate) fn from_dispatcher_time(time: Duration) -> Self {
+        Self(time)
+    }
+
+    /// The time that has passed from `earlier` to this instant, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    /// The time that has passed from `earlier` to this instant, or `None` if `earlier` is
+    /// later.
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    /// The instant `duration` after this one, or `None` if it can't be represented.
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration).map(Self)
+    }
+
+    /// The instant `duration` before this one, or `None` if it's before the clock started.
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(duration).map(Self)
+    }
+}
+
+impl fmt::Debug for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Instant").field(&self.0).finish()
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{BackgroundExecutor, TestDispatcher};
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn test_elapsed_follows_simulated_clock() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let start = executor.instant_now();
+        assert_eq!(executor.instant_now() - start, Duration::ZERO);
+
+        dispatcher.advance_clock(Duration::from_millis(250));
+        let end = executor.instant_now();
+        assert_eq!(end - start, Duration::from_millis(250));
+        assert_eq!(start - end, Duration::ZERO);
+        assert_eq!(start + Duration::from_millis(250), end);
+        assert!(start < end);
+    }
+}
@@ -0,0 +1,428 @@
+//! An async mutex that waits through the executor instead of blocking the thread.
+//!
+//! A task waiting for the lock is parked like any other pending future, and is woken when
+//! the guard is dropped. Waking schedules the waiter as a runnable rather than running it
+//! inline, so dropping a guard from inside a runnable doesn't re-enter the dispatcher. In tests,
+//! when several tasks are waiting, the one that's woken is picked by the dispatcher's rng.
+//!
+//! To avoid priority inversion, a background task holding the lock inherits the priority of
+//! the highest priority task waiting for it until it releases the lock, or until the waiters
+//! that boosted it give up by dropping their [`Lock`] futures. Waiting from outside a
+//! background task, such as on the main thread, counts as [`Priority::High`]. On real
+//! platforms, the boost applies the next time the holder is scheduled, while in tests, a
+//! runnable of the holder that's already queued is moved up as well.
+
+use crate::{executor::TaskPriority, BackgroundExecutor, Priority};
+use std::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+use util::post_inc;
+
+/// A mutual exclusion primitive for async code, see the [module docs](self).
+pub struct Mutex<T> {
+    executor: BackgroundExecutor,
+    state: parking_lot::Mutex<State>,
+    value: UnsafeCell<T>,
+}
+
+struct State {
+    locked: bool,
+    /// The priority of the background task holding the lock, if it's held by one.
+    holder: Option<Arc<TaskPriority>>,
+    /// The waiting tasks, along with the priority each of them boosts the holder to.
+    waiters: Vec<(usize, Waker, Priority)>,
+    next_waiter_id: usize,
+}
+
+impl State {
+    /// The priority that the waiting tasks boost the holder to, if any are waiting.
+    fn waiter_boost(&self) -> Option<Priority> {
+        self.waiters.iter().map(|(_, _, priority)| *priority).min()
+    }
+}
+
+// SAFETY: The value is only accessed through a `MutexGuard`, and `State::locked`
+// guarantees that at most one guard exists at a time.
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    /// Create a new mutex around the given value. The executor is used to choose
+    /// between waiting tasks in tests.
+    pub fn new(executor: &BackgroundExecutor, value: T) -> Self {
+        Self {
+            executor: executor.clone(),
+            state: parking_lot::Mutex::new(State {
+                locked: false,
+                holder: None,
+                waiters: Vec::new(),
+                next_waiter_id: 0,
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Acquire the lock, waiting until it's released if another task holds it.
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock {
+            mutex: self,
+            waiter_id: None,
+        }
+    }
+
+    /// Acquire the lock if no one else holds it.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            None
+        } else {
+            state.locked = true;
+            state.holder = TaskPriority::current();
+            Some(MutexGuard { mutex: self })
+        }
+    }
+
+    /// Access the value without locking, as this mutex is borrowed mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Consume the mutex, returning its value.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    /// Identifies this lock among the ones whose boosts a task holds, see [`TaskPriority::boost`].
+    fn boost_id(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Remove one of the waiting tasks, so that it can be woken to take the lock.
+    fn take_waiter(&self, state: &mut State) -> Option<Waker> {
+        let len = state.waiters.len();
+        if len == 0 {
+            return None;
+        }
+        let ix = self.executor.choose_waiter(len);
+        Some(state.waiters.remove(ix).1)
+    }
+}
+
+impl<T> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mutex")
+            .field("locked", &self.state.lock().locked)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The future returned by [`Mutex::lock`].
+#[must_use]
+pub struct Lock<'a, T> {
+    mutex: &'a Mutex<T>,
+    waiter_id: Option<usize>,
+}
+
+impl<'a, T> Future for Lock<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mutex = self.mutex;
+        let mut state = mutex.state.lock();
+        if !state.locked {
+            state.locked = true;
+            state.holder = TaskPriority::current();
+            if let Some(id) = self.waiter_id.take() {
+                state.waiters.retain(|(waiter_id, _, _)| *waiter_id != id);
+            }
+            return Poll::Ready(MutexGuard { mutex });
+        }
+
+        let id = *self
+            .waiter_id
+            .get_or_insert_with(|| post_inc(&mut state.next_waiter_id));
+        let priority = TaskPriority::current().map_or(Priority::High, |waiter| waiter.effective());
+        if let Some((_, waker, waiter_priority)) = state
+            .waiters
+            .iter_mut()
+            .find(|(waiter_id, _, _)| *waiter_id == id)
+        {
+            if !waker.will_wake(cx.waker()) {
+                *waker = cx.waker().clone();
+            }
+            *waiter_priority = priority;
+        } else {
+            state.waiters.push((id, cx.waker().clone(), priority));
+        }
+        let holder = state.holder.clone();
+        drop(state);
+
+        // Boosting the holder takes the dispatcher's lock in tests, so do it outside ours.
+        if let Some(holder) = holder {
+            holder.boost(mutex.boost_id(), priority);
+        }
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Lock<'_, T> {
+    fn drop(&mut self) {
+        let Some(id) = self.waiter_id else {
+            return;
+        };
+        let mut state = self.mutex.state.lock();
+        if let Some(ix) = state
+            .waiters
+            .iter()
+            .position(|(waiter_id, _, _)| *waiter_id == id)
+        {
+            state.waiters.remove(ix);
+            // The holder may have been boosted for this waiter, which no longer needs it.
+            let holder = state.holder.clone();
+            let boost = state.waiter_boost();
+            drop(state);
+            if let Some(holder) = holder {
+                holder.set_boost(self.mutex.boost_id(), boost);
+            }
+        } else if !state.locked {
+            // This waiter was woken to take the lock, so pass that on to another one.
+            let waker = self.mutex.take_waiter(&mut state);
+            drop(state);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Grants access to the value of a [`Mutex`], releasing the lock when dropped.
+#[must_use]
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+// SAFETY: Sharing the guard only shares access to the value.
+unsafe impl<T: Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: This guard holds the lock.
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: This guard holds the lock.
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.mutex.state.lock();
+        state.locked = false;
+        let holder = state.holder.take();
+        let waker = self.mutex.take_waiter(&mut state);
+        drop(state);
+        if let Some(holder) = holder {
+            holder.restore(self.mutex.boost_id());
+        }
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{executor::YieldNow, SchedulingMode, TestDispatcher};
+
+    #[test]
+    fn test_lock_excludes_other_tasks() {
+        for seed in 0..20 {
+            let dispatcher = TestDispatcher::new(seed);
+            let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+            let mutex = Arc::new(Mutex::new(&executor, 0));
+            for _ in 0..5 {
+                let mutex = mutex.clone();
+                executor
+                    .spawn(async move {
+                        let mut guard = mutex.lock().await;
+                        let value = *guard;
+                        YieldNow { count: 2 }.await;
+                        *guard = value + 1;
+                    })
+                    .detach();
+            }
+            dispatcher.poll_n(1);
+            assert!(mutex.try_lock().is_none(), "seed {seed}");
+
+            dispatcher.run_until_parked();
+            assert_eq!(*mutex.try_lock().unwrap(), 5, "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn test_lock_holder_inherits_waiter_priority() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        dispatcher.set_scheduling(SchedulingMode::Fifo);
+        let mutex = Arc::new(Mutex::new(&executor, ()));
+        let events = Arc::new(parking_lot::Mutex::new(Vec::new()));
+
+        executor
+            .spawn_with_priority(Priority::Low, {
+                let mutex = mutex.clone();
+                let events = events.clone();
+                async move {
+                    let _guard = mutex.lock().await;
+                    events.lock().push("low locked");
+                    YieldNow { count: 1 }.await;
+                }
+            })
+            .detach();
+        // Let the low priority task take the lock, leaving it queued to run again.
+        dispatcher.poll_n(1);
+
+        for _ in 0..3 {
+            let events = events.clone();
+            executor
+                .spawn(async move {
+                    YieldNow { count: 1 }.await;
+                    events.lock().push("normal");
+                })
+                .detach();
+        }
+        executor
+            .spawn_with_priority(Priority::High, {
+                let events = events.clone();
+                async move {
+                    let _guard = mutex.lock().await;
+                    events.lock().push("high locked");
+                }
+            })
+            .detach();
+
+        dispatcher.run_until_parked();
+        assert_eq!(
+            *events.lock(),
+            ["low locked", "high locked", "normal", "normal", "normal"]
+        );
+    }
+
+    #[test]
+    fn test_releasing_one_lock_keeps_the_boost_of_another() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        dispatcher.set_scheduling(SchedulingMode::Fifo);
+        let a = Arc::new(Mutex::new(&executor, ()));
+        let b = Arc::new(Mutex::new(&executor, ()));
+        let events = Arc::new(parking_lot::Mutex::new(Vec::new()));
+
+        executor
+            .spawn_with_priority(Priority::Low, {
+                let (a, b) = (a.clone(), b.clone());
+                let events = events.clone();
+                async move {
+                    let guard_a = a.lock().await;
+                    let guard_b = b.lock().await;
+                    events.lock().push("low locked");
+                    YieldNow { count: 1 }.await;
+                    // Nobody is waiting for `b`, but the high priority task is still waiting
+                    // for `a`, so this task should keep its boost.
+                    drop(guard_b);
+                    YieldNow { count: 1 }.await;
+                    drop(guard_a);
+                }
+            })
+            .detach();
+        dispatcher.poll_n(1);
+
+        for _ in 0..3 {
+            let events = events.clone();
+            executor
+                .spawn(async move {
+                    YieldNow { count: 1 }.await;
+                    events.lock().push("normal");
+                })
+                .detach();
+        }
+        executor
+            .spawn_with_priority(Priority::High, {
+                let events = events.clone();
+                async move {
+                    let _guard = a.lock().await;
+                    events.lock().push("high locked");
+                }
+            })
+            .detach();
+
+        dispatcher.run_until_parked();
+        assert_eq!(
+            *events.lock(),
+            ["low locked", "high locked", "normal", "normal", "normal"]
+        );
+    }
+
+    #[test]
+    fn test_waiter_giving_up_removes_its_boost() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        dispatcher.set_scheduling(SchedulingMode::Fifo);
+        let mutex = Arc::new(Mutex::new(&executor, ()));
+        let events = Arc::new(parking_lot::Mutex::new(Vec::new()));
+
+        executor
+            .spawn_with_priority(Priority::Low, {
+                let mutex = mutex.clone();
+                let events = events.clone();
+                async move {
+                    let _guard = mutex.lock().await;
+                    events.lock().push("low locked");
+                    YieldNow { count: 1 }.await;
+                    events.lock().push("low done");
+                }
+            })
+            .detach();
+        dispatcher.poll_n(1);
+
+        for _ in 0..2 {
+            let events = events.clone();
+            executor
+                .spawn(async move { events.lock().push("normal") })
+                .detach();
+        }
+        executor
+            .spawn_with_priority(Priority::High, {
+                let events = events.clone();
+                async move {
+                    let mut lock = mutex.lock();
+                    assert!(futures::poll!(&mut lock).is_pending());
+                    drop(lock);
+                    events.lock().push("high gave up");
+                }
+            })
+            .detach();
+
+        dispatcher.run_until_parked();
+        assert_eq!(
+            *events.lock(),
+            ["low locked", "high gave up", "normal", "normal", "low done"]
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! A channel for sending a single value between tasks, such as the reply to a request.
+//!
+//! Awaiting the [`Receiver`] parks its task like any other pending future, and sending wakes
+//! it, which schedules it as a runnable rather than resuming it inline. In tests, the receiver
+//! resumes the next time the `TestDispatcher` picks its runnable, so request/reply flows
+//! interleave with other work the same way for a given seed, and advance with `advance_clock`
+//! like everything else. Dropping the [`Sender`] without sending resolves the receiver to
+//! [`Canceled`].
+
+use parking_lot::Mutex;
+use std::{
+    fmt,
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+use thiserror::Error;
+
+/// Create a channel for sending a single value.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let state = Arc::new(Mutex::new(State {
+        value: None,
+        sender_alive: true,
+        receiver_alive: true,
+        receiver_waker: None,
+    }));
+    (
+        Sender {
+            state: state.clone(),
+        },
+        Receiver { state },
+    )
+}
+
+struct State<T> {
+    value: Option<T>,
+    sender_alive: bool,
+    receiver_alive: bool,
+    receiver_waker: Option<Waker>,
+}
+
+/// The sending half of a oneshot channel.
+pub struct Sender<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Send the value, waking the receiver. Fails if the receiver has been dropped,
+    /// returning the value.
+    pub fn send(self, value: T) -> Result<(), T> {
+        let mut state = self.state.lock();
+        if !state.receiver_alive {
+            return Err(value);
+        }
+        state.value = Some(value);
+        // Dropping the sender wakes the receiver.
+        drop(state);
+        Ok(())
+    }
+
+    /// Returns true if the receiver has been dropped.
+    pub fn is_canceled(&self) -> bool {
+        !self.state.lock().receiver_alive
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        state.sender_alive = false;
+        let receiver_waker = state.receiver_waker.take();
+        drop(state);
+        if let Some(waker) = receiver_waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender").finish_non_exhaustive()
+    }
+}
+
+/// The receiving half of a oneshot channel, which resolves to the value once it's sent.
+#[must_use]
+pub struct Receiver<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Receiver<T> {
+    /// Take the value if it has been sent, without waiting.
+    pub fn try_recv(&mut self) -> Result<Option<T>, Canceled> {
+        let mut state = self.state.lock();
+        match state.value.take() {
+            Some(value) => Ok(Some(value)),
+            None if state.sender_alive => Ok(None),
+            None => Err(Canceled),
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, Canceled>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        if let Some(value) = state.value.take() {
+            Poll::Ready(Ok(value))
+        } else if !state.sender_alive {
+            Poll::Ready(Err(Canceled))
+        } else {
+            state.receiver_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        state.receiver_alive = false;
+        // Drop the value outside the lock, as dropping it may re-enter the channel.
+        let value = mem::take(&mut state.value);
+        drop(state);
+        drop(value);
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver").finish_non_exhaustive()
+    }
+}
+
+/// The error a [`Receiver`] resolves to when its sender was dropped without sending.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Error)]
+#[error("oneshot sender was dropped without sending")]
+pub struct Canceled;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BackgroundExecutor, TestDispatcher};
+
+    #[test]
+    fn test_send_and_cancel() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+
+        let (tx, rx) = channel();
+        let reply = executor.spawn(rx);
+        executor
+            .spawn(async move {
+                tx.send(7).unwrap();
+            })
+            .detach();
+        assert_eq!(executor.block_test(reply), Ok(7));
+
+        let (tx, mut rx) = channel::<()>();
+        assert_eq!(rx.try_recv(), Ok(None));
+        drop(tx);
+        assert_eq!(executor.block_test(rx), Err(Canceled));
+
+        let (tx, rx) = channel();
+        drop(rx);
+        assert!(tx.is_canceled());
+        assert_eq!(tx.send(7), Err(7));
+    }
+}
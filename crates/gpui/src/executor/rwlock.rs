@@ -0,0 +1,419 @@
+//! An async reader-writer lock that waits through the executor instead of blocking the thread.
+//!
+//! Waiting tasks are parked like any other pending future and woken as runnables when the
+//! lock is released. When the lock becomes free and both readers and writers are waiting,
+//! one waiter is picked, by the dispatcher's rng in tests, and either every waiting reader
+//! or that single writer is woken. Woken waiters are handed the lock before they run, so
+//! nobody can take it from them in between.
+//!
+//! New readers can't join the ones holding the lock while a writer is waiting, so that a
+//! steady stream of readers can't starve writers.
+
+use crate::BackgroundExecutor;
+use std::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    mem,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use util::post_inc;
+
+/// A reader-writer lock for async code, see the [module docs](self).
+pub struct RwLock<T> {
+    executor: BackgroundExecutor,
+    state: parking_lot::Mutex<State>,
+    value: UnsafeCell<T>,
+}
+
+struct State {
+    readers: usize,
+    writer: bool,
+    waiters: Vec<Waiter>,
+    /// The ids of waiters that have been handed the lock but haven't been polled since.
+    granted: Vec<usize>,
+    next_waiter_id: usize,
+}
+
+impl State {
+    fn writer_waiting(&self) -> bool {
+        self.waiters.iter().any(|waiter| waiter.write)
+    }
+}
+
+struct Waiter {
+    id: usize,
+    write: bool,
+    waker: Waker,
+}
+
+// SAFETY: The value is only accessed through guards, and `State` guarantees that a
+// write guard never coexists with any other guard.
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    /// Create a new lock around the given value. The executor is used to choose
+    /// between waiting tasks in tests.
+    pub fn new(executor: &BackgroundExecutor, value: T) -> Self {
+        Self {
+            executor: executor.clone(),
+            state: parking_lot::Mutex::new(State {
+                readers: 0,
+                writer: false,
+                waiters: Vec::new(),
+                granted: Vec::new(),
+                next_waiter_id: 0,
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Acquire shared access, waiting while a writer holds the lock or is waiting for it.
+    pub fn read(&self) -> Acquire<'_, T, RwLockReadGuard<'_, T>> {
+        Acquire {
+            lock: self,
+            write: false,
+            waiter_id: None,
+            guard: |lock| RwLockReadGuard { lock },
+        }
+    }
+
+    /// Acquire exclusive access, waiting while anyone else holds the lock.
+    pub fn write(&self) -> Acquire<'_, T, RwLockWriteGuard<'_, T>> {
+        Acquire {
+            lock: self,
+            write: true,
+            waiter_id: None,
+            guard: |lock| RwLockWriteGuard { lock },
+        }
+    }
+
+    /// Access the value without locking, as this lock is borrowed mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Consume the lock, returning its value.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    /// Hand the lock to the waiters that can now acquire it, returning their wakers.
+    fn take_wakeups(&self, state: &mut State) -> Vec<Waker> {
+        if state.writer || state.waiters.is_empty() {
+            return Vec::new();
+        }
+
+        // While readers hold the lock, only other readers can join them, and only if no
+        // writer is waiting.
+        if state.readers == 0 {
+            let ix = self.executor.choose_waiter(state.waiters.len());
+            if state.waiters[ix].write {
+                let waiter = state.waiters.remove(ix);
+                state.writer = true;
+                state.granted.push(waiter.id);
+                return vec![waiter.waker];
+            }
+        } else if state.writer_waiting() {
+            return Vec::new();
+        }
+        let (readers, writers) = mem::take(&mut state.waiters)
+            .into_iter()
+            .partition::<Vec<_>, _>(|waiter| !waiter.write);
+        state.waiters = writers;
+        state.readers += readers.len();
+        state.granted.extend(readers.iter().map(|waiter| waiter.id));
+        readers.into_iter().map(|waiter| waiter.waker).collect()
+    }
+
+    fn release(&self, write: bool) {
+        let mut state = self.state.lock();
+        if write {
+            state.writer = false;
+        } else {
+            state.readers -= 1;
+        }
+        let wakers = self.take_wakeups(&mut state);
+        drop(state);
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock();
+        f.debug_struct("RwLock")
+            .field("readers", &state.readers)
+            .field("writer", &state.writer)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The future returned by [`RwLock::read`] and [`RwLock::write`].
+#[must_use]
+pub struct Acquire<'a, T, G> {
+    lock: &'a RwLock<T>,
+    write: bool,
+    waiter_id: Option<usize>,
+    guard: fn(&'a RwLock<T>) -> G,
+}
+
+impl<'a, T, G> Future for Acquire<'a, T, G> {
+    type Output = G;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let lock = self.lock;
+        let mut state = lock.state.lock();
+        if let Some(id) = self.waiter_id {
+            if let Some(ix) = state.granted.iter().position(|granted| *granted == id) {
+                state.granted.swap_remove(ix);
+                self.waiter_id = None;
+                return Poll::Ready((self.guard)(lock));
+            }
+        }
+
+        let available = if self.write {
+            !state.writer && state.readers == 0
+        } else {
+            !state.writer && !state.writer_waiting()
+        };
+        if available {
+            if self.write {
+                state.writer = true;
+            } else {
+                state.readers += 1;
+            }
+            if let Some(id) = self.waiter_id.take() {
+                state.waiters.retain(|waiter| waiter.id != id);
+            }
+            return Poll::Ready((self.guard)(lock));
+        }
+
+        let id = *self
+            .waiter_id
+            .get_or_insert_with(|| post_inc(&mut state.next_waiter_id));
+        if let Some(waiter) = state.waiters.iter_mut().find(|waiter| waiter.id == id) {
+            if !waiter.waker.will_wake(cx.waker()) {
+                waiter.waker = cx.waker().clone();
+            }
+        } else {
+            state.waiters.push(Waiter {
+                id,
+                write: self.write,
+                waker: cx.waker().clone(),
+            });
+        }
+        Poll::Pending
+    }
+}
+
+impl<T, G> Drop for Acquire<'_, T, G> {
+    fn drop(&mut self) {
+        let Some(id) = self.waiter_id else {
+            return;
+        };
+        let mut state = self.lock.state.lock();
+        if let Some(ix) = state.granted.iter().position(|granted| *granted == id) {
+            // This waiter was handed the lock, so release it for the others.
+            state.granted.swap_remove(ix);
+            drop(state);
+            self.lock.release(self.write);
+            return;
+        }
+        state.waiters.retain(|waiter| waiter.id != id);
+        // If this was the last waiting writer, the readers behind it may be able to join
+        // the ones holding the lock.
+        let wakers = self.lock.take_wakeups(&mut state);
+        drop(state);
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// Grants shared access to the value of a [`RwLock`], releasing it when dropped.
+#[must_use]
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+// SAFETY: Sharing a read guard only shares access to the value.
+unsafe impl<T: Sync> Sync for RwLockReadGuard<'_, T> {}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: This guard holds a read lock, so there is no writer.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release(false);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// Grants exclusive access to the value of a [`RwLock`], releasing it when dropped.
+#[must_use]
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+// SAFETY: Sharing a write guard only shares access to the value.
+unsafe impl<T: Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: This guard holds the write lock.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: This guard holds the write lock.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release(true);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{executor::YieldNow, SchedulingMode, TestDispatcher};
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering::SeqCst},
+        Arc,
+    };
+
+    #[test]
+    fn test_writers_exclude_everyone_else() {
+        for seed in 0..20 {
+            let dispatcher = TestDispatcher::new(seed);
+            let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+            let lock = Arc::new(RwLock::new(&executor, ()));
+            // The number of readers holding the lock, and whether a writer holds it.
+            let holders = Arc::new(parking_lot::Mutex::new((0, false)));
+            let finished = Arc::new(AtomicUsize::new(0));
+            for i in 0..6 {
+                let (lock, holders, finished) = (lock.clone(), holders.clone(), finished.clone());
+                executor
+                    .spawn(async move {
+                        if i % 3 == 0 {
+                            let _guard = lock.write().await;
+                            assert_eq!(*holders.lock(), (0, false));
+                            holders.lock().1 = true;
+                            YieldNow { count: 2 }.await;
+                            holders.lock().1 = false;
+                        } else {
+                            let _guard = lock.read().await;
+                            assert!(!holders.lock().1);
+                            holders.lock().0 += 1;
+                            YieldNow { count: 2 }.await;
+                            holders.lock().0 -= 1;
+                        }
+                        finished.fetch_add(1, SeqCst);
+                    })
+                    .detach();
+            }
+
+            dispatcher.run_until_parked();
+            assert_eq!(finished.load(SeqCst), 6, "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn test_writer_makes_progress_while_readers_overlap() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        dispatcher.set_scheduling(SchedulingMode::Fifo);
+        let lock = Arc::new(RwLock::new(&executor, ()));
+        let written = Arc::new(AtomicBool::new(false));
+
+        // Each reader takes the lock again as soon as it releases it, so there's always
+        // another reader holding it.
+        for _ in 0..2 {
+            let (lock, written) = (lock.clone(), written.clone());
+            executor
+                .spawn(async move {
+                    while !written.load(SeqCst) {
+                        let _guard = lock.read().await;
+                        YieldNow { count: 1 }.await;
+                    }
+                })
+                .detach();
+        }
+        executor
+            .spawn(async move {
+                YieldNow { count: 1 }.await;
+                let _guard = lock.write().await;
+                written.store(true, SeqCst);
+            })
+            .detach();
+
+        assert!(dispatcher.run_until_parked_or(1000));
+    }
+
+    #[test]
+    fn test_wake_order_is_seeded() {
+        fn first_to_acquire(seed: u64) -> &'static str {
+            let dispatcher = TestDispatcher::new(seed);
+            let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+            let lock = Arc::new(RwLock::new(&executor, ()));
+            let order = Arc::new(parking_lot::Mutex::new(Vec::new()));
+            let guard = executor.block_test(lock.write());
+            for write in [false, true] {
+                let (lock, order) = (lock.clone(), order.clone());
+                executor
+                    .spawn(async move {
+                        if write {
+                            let _guard = lock.write().await;
+                            order.lock().push("write");
+                        } else {
+                            let _guard = lock.read().await;
+                            order.lock().push("read");
+                        }
+                    })
+                    .detach();
+            }
+            dispatcher.run_until_parked();
+            assert!(order.lock().is_empty());
+
+            drop(guard);
+            dispatcher.run_until_parked();
+            let order = order.lock();
+            assert_eq!(order.len(), 2);
+            order[0]
+        }
+
+        let firsts = (0..20).map(first_to_acquire).collect::<Vec<_>>();
+        assert_eq!((0..20).map(first_to_acquire).collect::<Vec<_>>(), firsts);
+        assert!(firsts.contains(&"read"));
+        assert!(firsts.contains(&"write"));
+    }
+}
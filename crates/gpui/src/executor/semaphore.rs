@@ -0,0 +1,273 @@
+//! An async semaphore for limiting how much work runs concurrently.
+//!
+//! A task waiting for permits is parked like any other pending future, and is woken when
+//! enough permits are released. Waking schedules the waiter as a runnable rather than running
+//! it inline, so the permits are reserved for it when it's woken, and nobody else can take
+//! them before it runs. In tests, when several tasks are waiting, the order in which they're woken is
+//! picked by the dispatcher's rng, or is first come first served in `SchedulingMode::Fifo`.
+
+use crate::BackgroundExecutor;
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use util::post_inc;
+
+/// A counting semaphore for async code, see the [module docs](self).
+pub struct Semaphore {
+    executor: BackgroundExecutor,
+    state: parking_lot::Mutex<State>,
+}
+
+struct State {
+    permits: usize,
+    waiters: Vec<Waiter>,
+    /// The ids of waiters that have been given their permits but haven't been polled since.
+    granted: Vec<usize>,
+    next_waiter_id: usize,
+}
+
+struct Waiter {
+    id: usize,
+    permits: usize,
+    waker: Waker,
+}
+
+impl Semaphore {
+    /// Create a semaphore with the given number of permits. The executor is used to
+    /// choose between waiting tasks in tests.
+    pub fn new(executor: &BackgroundExecutor, permits: usize) -> Self {
+        Self {
+            executor: executor.clone(),
+            state: parking_lot::Mutex::new(State {
+                permits,
+                waiters: Vec::new(),
+                granted: Vec::new(),
+                next_waiter_id: 0,
+            }),
+        }
+    }
+
+    /// Acquire `permits` permits, waiting until enough of them are released if they
+    /// aren't available. Waits forever if more permits are requested than the
+    /// semaphore was created with.
+    pub fn acquire(&self, permits: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            permits,
+            waiter_id: None,
+        }
+    }
+
+    /// Acquire `permits` permits if they're available.
+    pub fn try_acquire(&self, permits: usize) -> Option<SemaphorePermit<'_>> {
+        let mut state = self.state.lock();
+        if state.permits < permits {
+            return None;
+        }
+        state.permits -= permits;
+        Some(SemaphorePermit {
+            semaphore: self,
+            permits,
+        })
+    }
+
+    /// The number of permits that can be acquired without waiting.
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    /// Give the available permits to the waiting tasks that can use them, returning their
+    /// wakers.
+    fn take_waiters(&self, state: &mut State) -> Vec<Waker> {
+        let mut wakers = Vec::new();
+        loop {
+            let eligible = state
+                .waiters
+                .iter()
+                .enumerate()
+                .filter(|(_, waiter)| waiter.permits <= state.permits)
+                .map(|(ix, _)| ix)
+                .collect::<Vec<_>>();
+            if eligible.is_empty() {
+                break;
+            }
+            let ix = eligible[self.executor.choose_waiter(eligible.len())];
+            let waiter = state.waiters.remove(ix);
+            state.permits -= waiter.permits;
+            state.granted.push(waiter.id);
+            wakers.push(waiter.waker);
+        }
+        wakers
+    }
+
+    fn release(&self, permits: usize) {
+        let mut state = self.state.lock();
+        state.permits += permits;
+        let wakers = self.take_waiters(&mut state);
+        drop(state);
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Semaphore")
+            .field("permits", &self.state.lock().permits)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The future returned by [`Semaphore::acquire`].
+#[must_use]
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+    waiter_id: Option<usize>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let semaphore = self.semaphore;
+        let permits = self.permits;
+        let mut state = semaphore.state.lock();
+        if let Some(id) = self.waiter_id {
+            if let Some(ix) = state.granted.iter().position(|granted| *granted == id) {
+                state.granted.swap_remove(ix);
+                self.waiter_id = None;
+                return Poll::Ready(SemaphorePermit { semaphore, permits });
+            }
+        }
+
+        if state.permits >= permits {
+            state.permits -= permits;
+            if let Some(id) = self.waiter_id.take() {
+                state.waiters.retain(|waiter| waiter.id != id);
+            }
+            return Poll::Ready(SemaphorePermit { semaphore, permits });
+        }
+
+        let id = *self
+            .waiter_id
+            .get_or_insert_with(|| post_inc(&mut state.next_waiter_id));
+        if let Some(waiter) = state.waiters.iter_mut().find(|waiter| waiter.id == id) {
+            if !waiter.waker.will_wake(cx.waker()) {
+                waiter.waker = cx.waker().clone();
+            }
+        } else {
+            state.waiters.push(Waiter {
+                id,
+                permits,
+                waker: cx.waker().clone(),
+            });
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let Some(id) = self.waiter_id else {
+            return;
+        };
+        let mut state = self.semaphore.state.lock();
+        if let Some(ix) = state.granted.iter().position(|granted| *granted == id) {
+            // This waiter was given its permits, so pass them on to the others.
+            state.granted.swap_remove(ix);
+            drop(state);
+            self.semaphore.release(self.permits);
+        } else {
+            state.waiters.retain(|waiter| waiter.id != id);
+        }
+    }
+}
+
+/// Permits acquired from a [`Semaphore`], which are released when dropped.
+#[must_use]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// The number of permits held.
+    pub fn permits(&self) -> usize {
+        self.permits
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.release(self.permits);
+    }
+}
+
+impl fmt::Debug for SemaphorePermit<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SemaphorePermit")
+            .field("permits", &self.permits)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestDispatcher;
+    use futures::{task::noop_waker_ref, FutureExt as _};
+    use std::sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc,
+    };
+
+    #[test]
+    fn test_released_permits_are_reserved_for_woken_waiters() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let semaphore = Arc::new(Semaphore::new(&executor, 1));
+        let permit = semaphore.try_acquire(1).unwrap();
+        let acquired = Arc::new(AtomicBool::new(false));
+        executor
+            .spawn({
+                let semaphore = semaphore.clone();
+                let acquired = acquired.clone();
+                async move {
+                    let _permit = semaphore.acquire(1).await;
+                    acquired.store(true, SeqCst);
+                }
+            })
+            .detach();
+        dispatcher.run_until_parked();
+        assert!(!acquired.load(SeqCst));
+
+        drop(permit);
+        // The waiter hasn't run yet, but the permit is already its own.
+        assert_eq!(semaphore.available_permits(), 0);
+        assert!(semaphore.try_acquire(1).is_none());
+        dispatcher.run_until_parked();
+        assert!(acquired.load(SeqCst));
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_dropping_a_woken_waiter_releases_its_permits() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let semaphore = Semaphore::new(&executor, 2);
+        let permit = semaphore.try_acquire(2).unwrap();
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut acquire = semaphore.acquire(2);
+        assert!(acquire.poll_unpin(&mut cx).is_pending());
+
+        drop(permit);
+        assert_eq!(semaphore.available_permits(), 0);
+        drop(acquire);
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+}
@@ -0,0 +1,101 @@
+//! A group of tasks that are cancelled together, such as those spawned by a view that's being
+//! torn down.
+//!
+//! Tasks are added to a group with `BackgroundExecutor::spawn_in` or
+//! `ForegroundExecutor::spawn_in`, and the group holds on to them rather than the caller.
+//! Cancelling the group cancels each of its tasks like [`Task::cancel`], so in tests their
+//! queued runnables are removed from the dispatcher, along with any timers their futures
+//! created.
+
+use super::Task;
+use parking_lot::Mutex;
+use std::{fmt, mem};
+
+/// A set of tasks that are cancelled together, see the [module docs](self). Dropping the
+/// group cancels its tasks.
+#[derive(Default)]
+pub struct TaskGroup {
+    tasks: Mutex<Vec<Task<()>>>,
+}
+
+impl TaskGroup {
+    /// Create a group with no tasks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn push(&self, task: Task<()>) {
+        let mut tasks = self.tasks.lock();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(task);
+    }
+
+    /// The number of tasks in the group that haven't completed.
+    pub fn len(&self) -> usize {
+        let mut tasks = self.tasks.lock();
+        tasks.retain(|task| !task.is_finished());
+        tasks.len()
+    }
+
+    /// Whether every task in the group has completed.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Cancel every task in the group that hasn't completed.
+    pub fn cancel_all(&self) {
+        let tasks = mem::take(&mut *self.tasks.lock());
+        // Cancelling a task drops its future, which may spawn into this group, so do it
+        // outside the lock.
+        for task in tasks {
+            if let Task::Spawned(task, handle) = task {
+                // Dropping an idle task schedules it once more so that its future is dropped,
+                // so remove its runnable from the queues afterwards.
+                drop(task);
+                handle.cancel();
+            }
+        }
+    }
+}
+
+impl Drop for TaskGroup {
+    fn drop(&mut self) {
+        self.cancel_all();
+    }
+}
+
+impl fmt::Debug for TaskGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskGroup")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BackgroundExecutor, TestDispatcher};
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn test_cancel_all_removes_queued_work() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let group = TaskGroup::new();
+        for _ in 0..3 {
+            executor.spawn_in(&group, executor.sleep(Duration::from_secs(1)));
+        }
+        executor.spawn_in(&group, async {});
+        dispatcher.run_until_parked();
+        assert_eq!(group.len(), 3);
+
+        // One task is still queued to run.
+        executor.spawn_in(&group, async {});
+        assert_eq!(group.len(), 4);
+
+        group.cancel_all();
+        assert!(group.is_empty());
+        dispatcher.assert_idle();
+    }
+}
@@ -0,0 +1,166 @@
+//! Values that are scoped to a task rather than a thread, declared with [`task_local!`](crate::task_local).
+//!
+//! A value is attached to a future with [`LocalKey::scope`], and is visible to everything the
+//! future runs for as long as it's being polled. Since tasks take turns on the same threads,
+//! the value is swapped into a thread local around each poll and swapped back out afterwards,
+//! so each task only ever observes its own value, including under the `TestDispatcher`.
+
+use std::{
+    cell::RefCell,
+    fmt,
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Declare a task local, which can be read from within futures passed to [`LocalKey::scope`].
+///
+/// ```
+/// gpui::task_local! {
+///     static REQUEST_ID: u64;
+/// }
+/// ```
+#[macro_export]
+macro_rules! task_local {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty;) => {
+        $(#[$attr])*
+        $vis static $name: $crate::task_local::LocalKey<$t> = {
+            ::std::thread_local! {
+                static SLOT: ::std::cell::RefCell<::std::option::Option<$t>> =
+                    const { ::std::cell::RefCell::new(::std::option::Option::None) };
+            }
+            $crate::task_local::LocalKey::new(&SLOT)
+        };
+    };
+}
+
+/// A key for a task local value, see [`task_local!`](crate::task_local).
+pub struct LocalKey<T: 'static> {
+    slot: &'static std::thread::LocalKey<RefCell<Option<T>>>,
+}
+
+impl<T: 'static> LocalKey<T> {
+    #[doc(hidden)]
+    pub const fn new(slot: &'static std::thread::LocalKey<RefCell<Option<T>>>) -> Self {
+        Self { slot }
+    }
+
+    /// Run the given future with this task local set to `value` whenever it's polled.
+    /// Spawn the returned future to attach the value to a task.
+    pub fn scope<F: Future>(&'static self, value: T, future: F) -> TaskLocalFuture<T, F> {
+        TaskLocalFuture {
+            key: self,
+            value: Some(value),
+            future,
+        }
+    }
+
+    /// Call the given function with a reference to this task local's value.
+    /// Panics if called outside of a future passed to [`LocalKey::scope`].
+    #[track_caller]
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        self.try_with(f)
+            .expect("task local accessed outside of its scope")
+    }
+
+    /// Like [`LocalKey::with`], but returns `None` when called outside the task local's scope.
+    pub fn try_with<R>(&'static self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        self.slot.with(|slot| slot.borrow().as_ref().map(f))
+    }
+
+    /// Return a copy of this task local's value.
+    /// Panics if called outside of a future passed to [`LocalKey::scope`].
+    #[track_caller]
+    pub fn get(&'static self) -> T
+    where
+        T: Clone,
+    {
+        self.with(T::clone)
+    }
+
+    fn swap(&'static self, value: &mut Option<T>) {
+        self.slot
+            .with(|slot| mem::swap(&mut *slot.borrow_mut(), value));
+    }
+}
+
+impl<T: 'static> fmt::Debug for LocalKey<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalKey").finish_non_exhaustive()
+    }
+}
+
+/// A future that sets a task local while it's polled, see [`LocalKey::scope`].
+pub struct TaskLocalFuture<T: 'static, F> {
+    key: &'static LocalKey<T>,
+    value: Option<T>,
+    future: F,
+}
+
+impl<T: 'static, F: Future> Future for TaskLocalFuture<T, F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        struct Restore<'a, T: 'static> {
+            key: &'static LocalKey<T>,
+            value: &'a mut Option<T>,
+        }
+
+        impl<T: 'static> Drop for Restore<'_, T> {
+            fn drop(&mut self) {
+                self.key.swap(self.value);
+            }
+        }
+
+        // SAFETY: The future is never moved out of `self`, and `key` and `value` aren't pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        this.key.swap(&mut this.value);
+        // Swap the previous value back in once the future yields, even if it panics.
+        let _restore = Restore {
+            key: this.key,
+            value: &mut this.value,
+        };
+        future.poll(cx)
+    }
+}
+
+impl<T: 'static, F> fmt::Debug for TaskLocalFuture<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskLocalFuture").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{executor::YieldNow, BackgroundExecutor, TestDispatcher};
+    use std::sync::Arc;
+
+    crate::task_local! {
+        static REQUEST_ID: u64;
+    }
+
+    #[test]
+    fn test_each_task_sees_its_own_value() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let tasks = (0..3)
+            .map(|id| {
+                executor.spawn(REQUEST_ID.scope(id, async move {
+                    let mut observed = Vec::new();
+                    for _ in 0..3 {
+                        observed.push(REQUEST_ID.get());
+                        YieldNow { count: 1 }.await;
+                    }
+                    observed
+                }))
+            })
+            .collect::<Vec<_>>();
+
+        for (id, task) in tasks.into_iter().enumerate() {
+            assert_eq!(executor.block_test(task), [id as u64; 3]);
+        }
+        assert_eq!(REQUEST_ID.try_with(|id| *id), None);
+    }
+}
@@ -0,0 +1,193 @@
+//! A set of spawned tasks whose outputs are yielded as they complete, like
+//! `futures::stream::FuturesUnordered`, but in an order that's reproducible in tests.
+//!
+//! Each task in the set wakes it with its own waker, so outputs are yielded in the order in
+//! which the tasks completed, and tasks that had already completed when they were added are
+//! yielded in the order they were added. Under the `TestDispatcher`, tasks complete in the
+//! order its rng runs them in, so a seed always yields the same order. Tasks can be added
+//! while the set is being consumed, and dropping the set cancels the tasks that haven't
+//! completed, like [`Task::cancel`].
+
+use super::{resume_panic, Task};
+use futures::Stream;
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt, mem,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+use util::post_inc;
+use waker_fn::waker_fn;
+
+/// A stream of the outputs of a set of tasks, see the [module docs](self). The stream ends
+/// once every task in the set has been yielded.
+pub struct TaskSet<T> {
+    tasks: BTreeMap<usize, (Task<T>, Waker)>,
+    next_id: usize,
+    shared: Arc<Mutex<Shared>>,
+}
+
+#[derive(Default)]
+struct Shared {
+    /// The ids of the tasks that have woken the set, in the order they did so.
+    ready: VecDeque<usize>,
+    waker: Option<Waker>,
+}
+
+impl<T> TaskSet<T> {
+    /// Create a set with no tasks.
+    pub fn new() -> Self {
+        Self {
+            tasks: BTreeMap::new(),
+            next_id: 0,
+            shared: Arc::default(),
+        }
+    }
+
+    /// Add a task to the set, which yields its output once it completes.
+    pub fn push(&mut self, task: Task<T>) {
+        let id = post_inc(&mut self.next_id);
+        let waker = waker_fn({
+            let shared = self.shared.clone();
+            move || {
+                let mut shared = shared.lock();
+                shared.ready.push_back(id);
+                let waker = shared.waker.take();
+                drop(shared);
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            }
+        });
+        self.tasks.insert(id, (task, waker.clone()));
+        // Poll the task on the next poll of the set, so that it registers its waker.
+        waker.wake();
+    }
+
+    /// The number of tasks in the set whose output hasn't been yielded.
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Whether every task in the set has been yielded.
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+}
+
+impl<T> Default for TaskSet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The tasks are never pinned, as `Task` doesn't rely on being pinned.
+impl<T> Unpin for TaskSet<T> {}
+
+impl<T> Stream for TaskSet<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            let mut shared = this.shared.lock();
+            let Some(id) = shared.ready.pop_front() else {
+                if this.tasks.is_empty() {
+                    return Poll::Ready(None);
+                }
+                shared.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            };
+            drop(shared);
+
+            // A task that has already been yielded may have woken the set more than once.
+            let Some((task, waker)) = this.tasks.get_mut(&id) else {
+                continue;
+            };
+            if let Poll::Ready(result) = task.poll_result(&mut Context::from_waker(waker)) {
+                this.tasks.remove(&id);
+                return Poll::Ready(Some(resume_panic(result)));
+            }
+        }
+    }
+}
+
+impl<T> Drop for TaskSet<T> {
+    fn drop(&mut self) {
+        for (task, _) in mem::take(&mut self.tasks).into_values() {
+            if let Task::Spawned(task, handle) = task {
+                // Dropping an idle task schedules it once more so that its future is dropped,
+                // so remove its runnable from the queues afterwards.
+                drop(task);
+                handle.cancel();
+            }
+        }
+    }
+}
+
+impl<T> fmt::Debug for TaskSet<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskSet").field("len", &self.len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BackgroundExecutor, TestDispatcher};
+    use collections::BTreeSet;
+    use futures::StreamExt;
+    use std::time::Duration;
+
+    fn completion_order(seed: u64) -> Vec<usize> {
+        let dispatcher = TestDispatcher::new(seed);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let spawn = |ix: usize| {
+            let delay = executor.simulate_random_delay();
+            executor.spawn(async move {
+                delay.await;
+                ix
+            })
+        };
+        let mut set = TaskSet::new();
+        set.push(spawn(0));
+        set.push(spawn(1));
+        executor.block_test(async {
+            let mut order = Vec::new();
+            while let Some(ix) = set.next().await {
+                if ix < 2 {
+                    set.push(spawn(ix + 2));
+                }
+                order.push(ix);
+            }
+            order
+        })
+    }
+
+    #[test]
+    fn test_completion_order_is_seeded() {
+        let mut orders = BTreeSet::new();
+        for seed in 0..20 {
+            let order = completion_order(seed);
+            assert_eq!(order, completion_order(seed), "seed {seed}");
+            assert_eq!(order.iter().copied().collect::<BTreeSet<_>>().len(), 4);
+            orders.insert(order);
+        }
+        assert!(orders.len() > 1);
+    }
+
+    #[test]
+    fn test_drop_cancels_outstanding_tasks() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let mut set = TaskSet::new();
+        for _ in 0..3 {
+            set.push(executor.spawn(executor.sleep(Duration::from_secs(1))));
+        }
+        dispatcher.run_until_parked();
+        drop(set);
+        dispatcher.assert_idle();
+    }
+}
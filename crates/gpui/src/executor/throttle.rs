@@ -0,0 +1,181 @@
+//! Throttling, for reacting to a stream of events at a bounded rate while staying responsive,
+//! such as scrolling or resizing.
+//!
+//! The first [`Throttler::trigger`] opens a window lasting the throttle's duration, and the
+//! stream yields at most once on each edge of that window, see [`ThrottleEdges`]. A value
+//! yielded on the trailing edge opens another window, so values are always at least a window
+//! apart. Windows are timed with the executor's timers, so in tests they close as the clock
+//! is advanced.
+
+use crate::{executor::Timer, BackgroundExecutor};
+use futures::Stream;
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+/// Which edges of a window a [`Throttler`] yields values on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThrottleEdges {
+    /// Yield the value that opened the window, immediately.
+    Leading,
+    /// Yield the most recent value when the window closes.
+    Trailing,
+    /// Yield the value that opened the window, and then the most recent one when the
+    /// window closes, if there were any more.
+    Both,
+}
+
+impl ThrottleEdges {
+    fn leading(self) -> bool {
+        matches!(self, Self::Leading | Self::Both)
+    }
+
+    fn trailing(self) -> bool {
+        matches!(self, Self::Trailing | Self::Both)
+    }
+}
+
+/// A stream of some of the values passed to [`Throttler::trigger`], see the
+/// [module docs](self). Clones share the same windows, so one can be triggered while another
+/// is polled. The stream never ends, and should only be polled through one clone at a time.
+pub struct Throttler<T> {
+    executor: BackgroundExecutor,
+    duration: Duration,
+    edges: ThrottleEdges,
+    state: Arc<Mutex<State<T>>>,
+}
+
+struct State<T> {
+    ready: VecDeque<T>,
+    trailing: Option<T>,
+    window: Option<Timer>,
+    waker: Option<Waker>,
+}
+
+impl<T> Throttler<T> {
+    /// Create a throttler that yields at most once per `duration` on each of the given edges.
+    pub fn new(executor: &BackgroundExecutor, duration: Duration, edges: ThrottleEdges) -> Self {
+        Self {
+            executor: executor.clone(),
+            duration,
+            edges,
+            state: Arc::new(Mutex::new(State {
+                ready: VecDeque::new(),
+                trailing: None,
+                window: None,
+                waker: None,
+            })),
+        }
+    }
+
+    /// Offer `value` to the stream, opening a window if one isn't already open.
+    pub fn trigger(&self, value: T) {
+        let mut state = self.state.lock();
+        if state.window.is_none() {
+            state.window = Some(self.executor.timer(self.duration));
+            if self.edges.leading() {
+                state.ready.push_back(value);
+            } else {
+                state.trailing = Some(value);
+            }
+        } else if self.edges.trailing() {
+            state.trailing = Some(value);
+        }
+        let waker = state.waker.take();
+        drop(state);
+
+        // Wake the stream so that it yields the leading value or polls the new window.
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Clone for Throttler<T> {
+    fn clone(&self) -> Self {
+        Self {
+            executor: self.executor.clone(),
+            duration: self.duration,
+            edges: self.edges,
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Throttler<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Throttler")
+            .field("duration", &self.duration)
+            .field("edges", &self.edges)
+            .field("window_open", &self.state.lock().window.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> Stream for Throttler<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let mut state = self.state.lock();
+        if let Some(value) = state.ready.pop_front() {
+            return Poll::Ready(Some(value));
+        }
+        if let Some(window) = state.window.as_mut() {
+            if Pin::new(window).poll(cx).is_ready() {
+                state.window = None;
+                if let Some(value) = state.trailing.take() {
+                    state.window = Some(self.executor.timer(self.duration));
+                    return Poll::Ready(Some(value));
+                }
+            }
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestDispatcher;
+    use futures::StreamExt;
+
+    #[test]
+    fn test_triggers_within_a_window() {
+        for (edges, expected) in [
+            (ThrottleEdges::Leading, vec![0]),
+            (ThrottleEdges::Trailing, vec![4]),
+            (ThrottleEdges::Both, vec![0, 4]),
+        ] {
+            let dispatcher = TestDispatcher::new(0);
+            let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+            let throttler = executor.throttle(Duration::from_millis(100), edges);
+            let yielded = Arc::new(Mutex::new(Vec::new()));
+            executor
+                .spawn({
+                    let mut throttler = throttler.clone();
+                    let yielded = yielded.clone();
+                    async move {
+                        while let Some(value) = throttler.next().await {
+                            yielded.lock().push(value);
+                        }
+                    }
+                })
+                .detach();
+
+            for value in 0..5 {
+                throttler.trigger(value);
+                dispatcher.advance_clock(Duration::from_millis(10));
+            }
+            dispatcher.advance_clock(Duration::from_secs(1));
+            assert_eq!(*yielded.lock(), expected, "{edges:?}");
+        }
+    }
+}
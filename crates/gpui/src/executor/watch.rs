@@ -0,0 +1,192 @@
+//! A channel holding a single value that receivers can observe as it changes, for binding UI
+//! to model state.
+//!
+//! Sending replaces the value and wakes every receiver waiting for a change, which schedules
+//! them as runnables like any other wakeup, so in tests they're resumed in the order the
+//! `TestDispatcher` picks for a given seed. A receiver only sees the latest value, so values
+//! sent in quick succession are coalesced. Receivers start out having seen nothing, so a new
+//! one, including one subscribed late with [`Sender::subscribe`], yields the current value
+//! right away.
+
+use futures::Stream;
+use parking_lot::Mutex;
+use std::{
+    collections::BTreeMap,
+    fmt, future, mem,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+use util::post_inc;
+
+/// Create a channel holding `initial`.
+pub fn channel<T: Clone>(initial: T) -> (Sender<T>, Receiver<T>) {
+    let state = Arc::new(Mutex::new(State {
+        value: initial,
+        version: 0,
+        sender_alive: true,
+        receiver_wakers: BTreeMap::default(),
+        next_receiver_id: 0,
+    }));
+    let sender = Sender { state };
+    let receiver = sender.subscribe();
+    (sender, receiver)
+}
+
+struct State<T> {
+    value: T,
+    /// Incremented every time a value is sent.
+    version: usize,
+    sender_alive: bool,
+    receiver_wakers: BTreeMap<usize, Waker>,
+    next_receiver_id: usize,
+}
+
+/// The sending half of a watch channel.
+pub struct Sender<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T: Clone> Sender<T> {
+    /// Replace the value, waking every receiver that's waiting for a change.
+    pub fn send(&self, value: T) {
+        let mut state = self.state.lock();
+        let old_value = mem::replace(&mut state.value, value);
+        state.version += 1;
+        let receiver_wakers = mem::take(&mut state.receiver_wakers);
+        drop(state);
+        // Drop the old value outside the lock, as dropping it may re-enter the channel.
+        drop(old_value);
+        for waker in receiver_wakers.into_values() {
+            waker.wake();
+        }
+    }
+
+    /// The current value.
+    pub fn get(&self) -> T {
+        self.state.lock().value.clone()
+    }
+
+    /// Create another receiver, which yields the current value right away.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let id = post_inc(&mut self.state.lock().next_receiver_id);
+        Receiver {
+            state: self.state.clone(),
+            id,
+            seen_version: None,
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        state.sender_alive = false;
+        let receiver_wakers = mem::take(&mut state.receiver_wakers);
+        drop(state);
+        for waker in receiver_wakers.into_values() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender").finish_non_exhaustive()
+    }
+}
+
+/// The receiving half of a watch channel. Also usable as a [`Stream`] of changed values.
+/// Cloning it creates a receiver that has seen the same values.
+pub struct Receiver<T> {
+    state: Arc<Mutex<State<T>>>,
+    id: usize,
+    seen_version: Option<usize>,
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Wait for a value this receiver hasn't seen, returning the latest one.
+    /// Returns `None` once the sender has been dropped and every value has been seen.
+    pub async fn changed(&mut self) -> Option<T> {
+        future::poll_fn(|cx| self.poll_changed(cx)).await
+    }
+
+    /// The current value, without marking it as seen.
+    pub fn get(&self) -> T {
+        self.state.lock().value.clone()
+    }
+
+    fn poll_changed(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        let mut state = self.state.lock();
+        if self.seen_version != Some(state.version) {
+            self.seen_version = Some(state.version);
+            Poll::Ready(Some(state.value.clone()))
+        } else if !state.sender_alive {
+            Poll::Ready(None)
+        } else {
+            state.receiver_wakers.insert(self.id, cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T: Clone> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_changed(cx)
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        let id = post_inc(&mut self.state.lock().next_receiver_id);
+        Self {
+            state: self.state.clone(),
+            id,
+            seen_version: self.seen_version,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.state.lock().receiver_wakers.remove(&self.id);
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BackgroundExecutor, TestDispatcher};
+    use futures::StreamExt;
+
+    #[test]
+    fn test_receivers_see_latest_value() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let (tx, rx) = channel(0);
+        let values = executor.spawn(rx.collect::<Vec<_>>());
+        dispatcher.run_until_parked();
+
+        // Values sent before the receiver runs again are coalesced.
+        tx.send(1);
+        tx.send(2);
+        dispatcher.run_until_parked();
+        tx.send(3);
+        dispatcher.run_until_parked();
+
+        let mut late = tx.subscribe();
+        assert_eq!(executor.block_test(late.changed()), Some(3));
+        drop(tx);
+        assert_eq!(executor.block_test(late.changed()), None);
+        assert_eq!(late.get(), 3);
+        assert_eq!(executor.block_test(values), [0, 2, 3]);
+    }
+}
@@ -18,14 +18,18 @@ mod blade;
 #[cfg(any(test, feature = "test-support"))]
 mod test;
 
+#[cfg(feature = "tokio")]
+mod tokio_dispatcher;
+
 #[cfg(target_os = "windows")]
 mod windows;
 
 use crate::{
     Action, AnyWindowHandle, AsyncWindowContext, BackgroundExecutor, Bounds, DevicePixels,
-    DispatchEventResult, Font, FontId, FontMetrics, FontRun, ForegroundExecutor, GlyphId, Keymap,
-    LineLayout, Pixels, PlatformInput, Point, RenderGlyphParams, RenderImageParams,
-    RenderSvgParams, Scene, SharedString, Size, Task, TaskLabel, WindowContext,
+    DispatchEventResult, DispatcherMetrics, Font, FontId, FontMetrics, FontRun, ForegroundExecutor,
+    GlyphId, Keymap, LineLayout, Overloaded, Pixels, PlatformInput, Point, Priority,
+    RenderGlyphParams, RenderImageParams, RenderSvgParams, Scene, SharedString, Size, Task,
+    TaskLabel, WindowContext,
 };
 use anyhow::Result;
 use async_task::Runnable;
@@ -36,14 +40,17 @@ use seahash::SeaHasher;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::hash::{Hash, Hasher};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{
     any::Any,
     fmt::{self, Debug},
     ops::Range,
     path::{Path, PathBuf},
     rc::Rc,
-    sync::Arc,
+    sync::{
+        atomic::{self, AtomicU64},
+        Arc, OnceLock,
+    },
 };
 use uuid::Uuid;
 
@@ -56,7 +63,14 @@ pub(crate) use mac::*;
 pub use semantic_version::SemanticVersion;
 #[cfg(any(test, feature = "test-support"))]
 pub(crate) use test::*;
+#[cfg(any(test, feature = "test-support"))]
+pub use test::{
+    DispatcherSnapshot, FairnessReport, ScheduleChoices, ScheduleEvent, ScheduleStep,
+    ScheduleTarget, SchedulingMode, TimerToken,
+};
 use time::UtcOffset;
+#[cfg(feature = "tokio")]
+pub(crate) use tokio_dispatcher::TokioDispatcher;
 #[cfg(target_os = "windows")]
 pub(crate) use windows::*;
 
@@ -223,19 +237,86 @@ pub(crate) trait PlatformWindow: HasWindowHandle + HasDisplayHandle {
 #[doc(hidden)]
 pub trait PlatformDispatcher: Send + Sync {
     fn is_main_thread(&self) -> bool;
-    fn dispatch(&self, runnable: Runnable, label: Option<TaskLabel>);
-    fn dispatch_on_main_thread(&self, runnable: Runnable);
+    fn dispatch(&self, runnable: Runnable, label: Option<TaskLabel>, priority: Priority);
+
+    /// Like `dispatch`, but drops the runnable and returns an error if the background queue is full.
+    fn try_dispatch(
+        &self,
+        runnable: Runnable,
+        label: Option<TaskLabel>,
+        priority: Priority,
+    ) -> Result<(), Overloaded> {
+        self.dispatch(runnable, label, priority);
+        Ok(())
+    }
+    fn dispatch_on_main_thread(&self, runnable: Runnable, label: Option<TaskLabel>);
     fn dispatch_after(&self, duration: Duration, runnable: Runnable);
     fn tick(&self, background_only: bool) -> bool;
     fn park(&self);
     fn unparker(&self) -> Unparker;
 
+    /// The current time, measured from an arbitrary point that's fixed for the lifetime of the process.
+    fn now(&self) -> Duration {
+        static START: OnceLock<Instant> = OnceLock::new();
+        START.get_or_init(Instant::now).elapsed()
+    }
+
+    /// Counters describing the work this dispatcher has handled, if it keeps them.
+    fn metrics(&self) -> Option<DispatcherMetrics> {
+        None
+    }
+
+    /// Log a warning whenever a runnable on the main thread takes longer than `threshold`
+    /// to run, see [`run_on_main_thread`]. Platform dispatchers share one threshold.
+    fn set_jank_threshold(&self, threshold: Duration) {
+        JANK_THRESHOLD_NANOS.store(
+            threshold.as_nanos().try_into().unwrap_or(u64::MAX),
+            atomic::Ordering::Relaxed,
+        );
+    }
+
     #[cfg(any(test, feature = "test-support"))]
     fn as_test(&self) -> Option<&TestDispatcher> {
         None
     }
 }
 
+/// The jank threshold of the platform dispatchers, which is disabled until one is set.
+static JANK_THRESHOLD_NANOS: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Run a runnable that was dispatched to the main thread, logging a warning naming its task's
+/// label if it takes longer than the threshold given to
+/// [`PlatformDispatcher::set_jank_threshold`].
+pub(crate) fn run_on_main_thread(runnable: Runnable, label: Option<TaskLabel>) {
+    let threshold = JANK_THRESHOLD_NANOS.load(atomic::Ordering::Relaxed);
+    if threshold == u64::MAX {
+        runnable.run();
+        return;
+    }
+    let start = Instant::now();
+    runnable.run();
+    report_jank(label, start.elapsed(), Duration::from_nanos(threshold));
+}
+
+/// Log a warning if a runnable on the main thread ran for longer than `threshold`,
+/// returning whether it did.
+pub(crate) fn report_jank(
+    label: Option<TaskLabel>,
+    elapsed: Duration,
+    threshold: Duration,
+) -> bool {
+    if elapsed <= threshold {
+        return false;
+    }
+    let name = label
+        .and_then(|label| label.name())
+        .unwrap_or("(unlabeled)");
+    log::warn!(
+        "main thread task {name} ran for {elapsed:?}, longer than the jank threshold of {threshold:?}"
+    );
+    true
+}
+
 pub(crate) trait PlatformTextSystem: Send + Sync {
     fn add_fonts(&self, fonts: Vec<Cow<'static, [u8]>>) -> Result<()>;
     fn all_font_names(&self) -> Vec<String>;
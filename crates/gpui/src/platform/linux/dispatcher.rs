@@ -4,7 +4,7 @@
 // todo(linux): remove
 #![allow(unused_variables)]
 
-use crate::{PlatformDispatcher, TaskLabel};
+use crate::{PlatformDispatcher, Priority, TaskLabel};
 use async_task::Runnable;
 use calloop::{
     channel::{self, Sender},
@@ -23,7 +23,7 @@ struct TimerAfter {
 
 pub(crate) struct LinuxDispatcher {
     parker: Mutex<Parker>,
-    main_sender: Sender<Runnable>,
+    main_sender: Sender<(Runnable, Option<TaskLabel>)>,
     timer_sender: Sender<TimerAfter>,
     background_sender: flume::Sender<Runnable>,
     _background_threads: Vec<thread::JoinHandle<()>>,
@@ -31,7 +31,7 @@ pub(crate) struct LinuxDispatcher {
 }
 
 impl LinuxDispatcher {
-    pub fn new(main_sender: Sender<Runnable>) -> Self {
+    pub fn new(main_sender: Sender<(Runnable, Option<TaskLabel>)>) -> Self {
         let (background_sender, background_receiver) = flume::unbounded::<Runnable>();
         let thread_count = std::thread::available_parallelism()
             .map(|i| i.get())
@@ -96,13 +96,13 @@ impl PlatformDispatcher for LinuxDispatcher {
         thread::current().id() == self.main_thread_id
     }
 
-    fn dispatch(&self, runnable: Runnable, _: Option<TaskLabel>) {
+    fn dispatch(&self, runnable: Runnable, _: Option<TaskLabel>, _: Priority) {
         self.background_sender.send(runnable).unwrap();
     }
 
-    fn dispatch_on_main_thread(&self, runnable: Runnable) {
+    fn dispatch_on_main_thread(&self, runnable: Runnable, label: Option<TaskLabel>) {
         self.main_sender
-            .send(runnable)
+            .send((runnable, label))
             .expect("Main thread is gone");
     }
 
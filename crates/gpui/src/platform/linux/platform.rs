@@ -22,11 +22,12 @@ use wayland_client::Connection;
 
 use crate::platform::linux::client::Client;
 use crate::platform::linux::wayland::WaylandClient;
+use crate::platform::run_on_main_thread;
 use crate::{
     px, Action, AnyWindowHandle, BackgroundExecutor, ClipboardItem, CursorStyle, DisplayId,
     ForegroundExecutor, Keymap, LinuxDispatcher, LinuxTextSystem, Menu, PathPromptOptions, Pixels,
     Platform, PlatformDisplay, PlatformInput, PlatformTextSystem, PlatformWindow, Result,
-    SemanticVersion, Task, WindowOptions, WindowParams,
+    SemanticVersion, Task, TaskLabel, WindowOptions, WindowParams,
 };
 
 use super::x11::X11Client;
@@ -77,7 +78,8 @@ impl LinuxPlatform {
         let wayland_display = env::var_os("WAYLAND_DISPLAY");
         let use_wayland = wayland_display.is_some_and(|display| !display.is_empty());
 
-        let (main_sender, main_receiver) = calloop::channel::channel::<Runnable>();
+        let (main_sender, main_receiver) =
+            calloop::channel::channel::<(Runnable, Option<TaskLabel>)>();
         let text_system = Arc::new(LinuxTextSystem::new());
         let callbacks = RefCell::new(Callbacks::default());
 
@@ -85,8 +87,8 @@ impl LinuxPlatform {
         event_loop
             .handle()
             .insert_source(main_receiver, |event, _, _| {
-                if let calloop::channel::Event::Msg(runnable) = event {
-                    runnable.run();
+                if let calloop::channel::Event::Msg((runnable, label)) = event {
+                    run_on_main_thread(runnable, label);
                 }
             });
 
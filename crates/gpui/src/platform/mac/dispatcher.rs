@@ -2,7 +2,7 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 
-use crate::{PlatformDispatcher, TaskLabel};
+use crate::{platform::run_on_main_thread, PlatformDispatcher, Priority, TaskLabel};
 use async_task::Runnable;
 use objc::{
     class, msg_send,
@@ -53,22 +53,30 @@ impl PlatformDispatcher for MacDispatcher {
         is_main_thread == YES
     }
 
-    fn dispatch(&self, runnable: Runnable, _: Option<TaskLabel>) {
+    fn dispatch(&self, runnable: Runnable, _: Option<TaskLabel>, priority: Priority) {
+        // Normal work has always run on the high priority queue, so only low priority
+        // work is moved to a lower QoS class.
+        let queue_priority = match priority {
+            Priority::High | Priority::Normal => DISPATCH_QUEUE_PRIORITY_HIGH,
+            Priority::Low => DISPATCH_QUEUE_PRIORITY_DEFAULT,
+        };
         unsafe {
             dispatch_async_f(
-                dispatch_get_global_queue(DISPATCH_QUEUE_PRIORITY_HIGH.try_into().unwrap(), 0),
+                dispatch_get_global_queue(queue_priority.try_into().unwrap(), 0),
                 runnable.into_raw().as_ptr() as *mut c_void,
                 Some(trampoline),
             );
         }
     }
 
-    fn dispatch_on_main_thread(&self, runnable: Runnable) {
+    fn dispatch_on_main_thread(&self, runnable: Runnable, label: Option<TaskLabel>) {
+        // The label travels with the runnable so that jank reports can name its task.
+        let context = Box::into_raw(Box::new((runnable, label)));
         unsafe {
             dispatch_async_f(
                 dispatch_get_main_queue(),
-                runnable.into_raw().as_ptr() as *mut c_void,
-                Some(trampoline),
+                context as *mut c_void,
+                Some(main_thread_trampoline),
             );
         }
     }
@@ -104,3 +112,8 @@ extern "C" fn trampoline(runnable: *mut c_void) {
     let task = unsafe { Runnable::<()>::from_raw(NonNull::new_unchecked(runnable as *mut ())) };
     task.run();
 }
+
+extern "C" fn main_thread_trampoline(context: *mut c_void) {
+    let (task, label) = *unsafe { Box::from_raw(context as *mut (Runnable, Option<TaskLabel>)) };
+    run_on_main_thread(task, label);
+}
@@ -5,6 +5,10 @@ mod text_system;
 mod window;
 
 pub(crate) use dispatcher::*;
+pub use dispatcher::{
+    DispatcherSnapshot, FairnessReport, ScheduleChoices, ScheduleEvent, ScheduleStep,
+    ScheduleTarget, SchedulingMode, TimerToken,
+};
 pub(crate) use display::*;
 pub(crate) use platform::*;
 pub(crate) use window::*;
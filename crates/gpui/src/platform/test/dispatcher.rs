@@ -1,16 +1,29 @@
-use crate::{PlatformDispatcher, TaskLabel};
+use crate::{
+    executor::{panic_message, YieldNow},
+    platform::report_jank,
+    DispatcherMetrics, Overloaded, PlatformDispatcher, Priority, TaskLabel,
+};
 use async_task::Runnable;
 use backtrace::Backtrace;
-use collections::{HashMap, HashSet, VecDeque};
+use collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use parking::{Parker, Unparker};
-use parking_lot::Mutex;
+use parking_lot::{Condvar, Mutex, MutexGuard};
 use rand::prelude::*;
 use std::{
+    any::Any,
+    cell::Cell,
+    fmt::Write,
     future::Future,
-    ops::RangeInclusive,
-    pin::Pin,
-    sync::Arc,
-    task::{Context, Poll},
+    mem,
+    ops::{Range, RangeInclusive},
+    panic::Location,
+    ptr,
+    sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        mpsc, Arc,
+    },
+    task::Waker,
+    thread::{self, ThreadId},
     time::Duration,
 };
 use util::post_inc;
@@ -27,11 +40,14 @@ pub struct TestDispatcher {
 }
 
 struct TestDispatcherState {
+    seed: u64,
     random: StdRng,
-    foreground: HashMap<TestDispatcherId, VecDeque<Runnable>>,
-    background: Vec<Runnable>,
-    deprioritized_background: Vec<Runnable>,
-    delayed: Vec<(Duration, Runnable)>,
+    data_random: StdRng,
+    foreground: HashMap<TestDispatcherId, VecDeque<QueuedRunnable>>,
+    background: BTreeMap<Priority, Vec<QueuedRunnable>>,
+    deprioritized_background: Vec<QueuedRunnable>,
+    delayed: Vec<(Duration, TimerToken, Runnable)>,
+    next_timer_token: usize,
     time: Duration,
     is_main_thread: bool,
     next_id: TestDispatcherId,
@@ -39,250 +55,3455 @@ struct TestDispatcherState {
     waiting_backtrace: Option<Backtrace>,
     deprioritized_task_labels: HashSet<TaskLabel>,
     block_on_ticks: RangeInclusive<usize>,
+    last_run_label: Option<TaskLabel>,
+    scheduling: SchedulingMode,
+    on_schedule: Option<Box<dyn Fn(ScheduleEvent) + Send>>,
+    delay_weights: Vec<(Range<usize>, u32)>,
+    is_polling: bool,
+    recording: Option<Vec<ScheduleStep>>,
+    detached_panic: Option<DetachedPanic>,
+    main_bias: f64,
+    time_budget: Option<Duration>,
+    recent_schedule: VecDeque<ScheduleEvent>,
+    scheduler: Option<Box<dyn FnMut(&ScheduleChoices) -> usize + Send>>,
+    fired_timers: Option<Vec<Duration>>,
+    background_threads: Option<BackgroundThreads>,
+    hang_reporter: Option<mpsc::Sender<()>>,
+    clock_frozen: bool,
+    round_robin_foreground: bool,
+    foreground_fifo_by_time: bool,
+    strict_main_thread: Option<ThreadId>,
+    next_main_thread_sequence: usize,
+    last_foreground_id: Option<usize>,
+    profile: Option<HashMap<&'static str, usize>>,
+    background_capacity: Option<usize>,
+    detached_tasks: BTreeMap<usize, (&'static str, &'static Location<'static>)>,
+    next_detached_task_id: usize,
+    clock_drift: f64,
+    drop_probability: f64,
+    dropped_count: usize,
+    promoted: Option<ScheduleStep>,
+    closing: bool,
+    total_dispatched: usize,
+    total_run: usize,
+    max_queue_depth: usize,
+    clock_target: Option<Duration>,
+    idle_wakers: Vec<Waker>,
+    idle_count: usize,
+    jank_threshold: Option<Duration>,
+    run_costs: HashMap<TaskLabel, Duration>,
+    jank_reports: Vec<(Option<&'static str>, Duration)>,
+    block_depth: usize,
+    max_concurrent_background: Option<usize>,
+    saturated_background: VecDeque<Runnable>,
+    background_streak: usize,
+    idle_callbacks: VecDeque<Box<dyn FnOnce() + Send>>,
+    entropy: Option<Box<dyn Iterator<Item = u8> + Send>>,
+    timer_jitter: Duration,
+    poll_count: usize,
+    latency_histogram: Option<BTreeMap<usize, usize>>,
+    /// The tasks that may have a runnable waiting in one of the queues, so that looking for
+    /// one that doesn't, such as when dropping a task that has finished, is cheap.
+    queued_tasks: HashSet<TaskId>,
+    /// The deadline of each pending timer, for finding it among `delayed`.
+    timer_deadlines: HashMap<TimerToken, Duration>,
 }
 
-impl TestDispatcher {
-    pub fn new(random: StdRng) -> Self {
-        let (parker, unparker) = parking::pair();
-        let state = TestDispatcherState {
-            random,
-            foreground: HashMap::default(),
-            background: Vec::new(),
-            deprioritized_background: Vec::new(),
-            delayed: Vec::new(),
-            time: Duration::ZERO,
-            is_main_thread: true,
-            next_id: TestDispatcherId(1),
-            allow_parking: false,
-            waiting_backtrace: None,
-            deprioritized_task_labels: Default::default(),
-            block_on_ticks: 0..=1000,
-        };
+/// The panic of a task whose output won't be read, because it was detached or dropped, held
+/// on to until the next `tick` re-raises it.
+struct DetachedPanic {
+    label: Option<TaskLabel>,
+    location: &'static Location<'static>,
+    payload: Box<dyn Any + Send>,
+}
 
-        TestDispatcher {
-            id: TestDispatcherId(0),
-            state: Arc::new(Mutex::new(state)),
-            parker: Arc::new(Mutex::new(parker)),
-            unparker,
-        }
+thread_local! {
+    static IS_BACKGROUND_THREAD: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Real OS threads that run background runnables, see [`TestDispatcher::set_background_parallelism`].
+struct BackgroundThreads {
+    sender: mpsc::Sender<Runnable>,
+    in_flight: Arc<InFlight>,
+}
+
+/// The runnables that the background threads are running, which the dispatcher can wait on.
+#[derive(Default)]
+struct InFlight {
+    count: AtomicUsize,
+    lock: Mutex<()>,
+    finished: Condvar,
+}
+
+impl InFlight {
+    fn count(&self) -> usize {
+        self.count.load(SeqCst)
     }
 
-    pub fn advance_clock(&self, by: Duration) {
-        let new_now = self.state.lock().time + by;
-        loop {
-            self.run_until_parked();
-            let state = self.state.lock();
-            let next_due_time = state.delayed.first().map(|(time, _)| *time);
-            drop(state);
-            if let Some(due_time) = next_due_time {
-                if due_time <= new_now {
-                    self.state.lock().time = due_time;
-                    continue;
-                }
-            }
-            break;
+    fn finish(&self) {
+        self.count.fetch_sub(1, SeqCst);
+        // Notify while holding the lock, so that a waiter that has just seen a non-zero
+        // count can't miss it.
+        let _lock = self.lock.lock();
+        self.finished.notify_all();
+    }
+
+    /// Block until one of the runnables in flight finishes, if there are any.
+    fn wait(&self) {
+        let mut lock = self.lock.lock();
+        if self.count() > 0 {
+            self.finished.wait(&mut lock);
         }
-        self.state.lock().time = new_now;
     }
+}
 
-    pub fn simulate_random_delay(&self) -> impl 'static + Send + Future<Output = ()> {
-        struct YieldNow {
-            pub(crate) count: usize,
+impl BackgroundThreads {
+    fn new(count: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Runnable>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let in_flight = Arc::new(InFlight::default());
+        for ix in 0..count {
+            let receiver = receiver.clone();
+            let in_flight = in_flight.clone();
+            thread::Builder::new()
+                .name(format!("TestDispatcher background {ix}"))
+                .spawn(move || {
+                    IS_BACKGROUND_THREAD.with(|is_background| is_background.set(true));
+                    loop {
+                        // Release the receiver before running, so the other threads can pick up work.
+                        let received = receiver.lock().recv();
+                        // The threads exit once the sender is dropped.
+                        let Ok(runnable) = received else {
+                            break;
+                        };
+                        runnable.run();
+                        in_flight.finish();
+                    }
+                })
+                .expect("failed to spawn background thread");
         }
+        Self { sender, in_flight }
+    }
+}
 
-        impl Future for YieldNow {
-            type Output = ();
+/// How many scheduling events to keep for reporting an exceeded time budget.
+const RECENT_SCHEDULE_LEN: usize = 16;
 
-            fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-                if self.count > 0 {
-                    self.count -= 1;
-                    cx.waker().wake_by_ref();
-                    Poll::Pending
-                } else {
-                    Poll::Ready(())
-                }
-            }
+/// How long a test may run for before [`TestDispatcher::install_hang_reporter`] reports it.
+const DEFAULT_HANG_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Controls how a [`TestDispatcher`] picks the next runnable to run.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SchedulingMode {
+    /// Interleave foreground and background work randomly, driven by the dispatcher's seed.
+    #[default]
+    Random,
+    /// Run all foreground work before any background work, each in the order it was dispatched.
+    Fifo,
+}
+
+impl TestDispatcherState {
+    fn gen_delay(&mut self) -> usize {
+        let range = if self.delay_weights.len() == 1 {
+            self.delay_weights[0].0.clone()
+        } else {
+            self.delay_weights
+                .choose_weighted(&mut self.random, |(_, weight)| *weight)
+                .unwrap()
+                .0
+                .clone()
+        };
+        if range.is_empty() {
+            0
+        } else {
+            self.random.gen_range(range)
         }
+    }
 
-        YieldNow {
-            count: self.state.lock().random.gen_range(0..10),
+    fn notify_schedule(&mut self, target: ScheduleTarget, label: Option<TaskLabel>) {
+        self.total_dispatched += 1;
+        let event = ScheduleEvent {
+            target,
+            time: self.time,
+            label,
+        };
+        if let Some(on_schedule) = &self.on_schedule {
+            on_schedule(event);
+        }
+        if self.time_budget.is_some() {
+            if self.recent_schedule.len() == RECENT_SCHEDULE_LEN {
+                self.recent_schedule.pop_front();
+            }
+            self.recent_schedule.push_back(event);
         }
     }
 
-    pub fn deprioritize(&self, task_label: TaskLabel) {
-        self.state
-            .lock()
-            .deprioritized_task_labels
-            .insert(task_label);
+    /// Decide whether to discard a runnable being dispatched, either because the dispatcher
+    /// has shut down or because of [`TestDispatcher::set_drop_probability`].
+    fn should_drop_runnable(&mut self) -> bool {
+        if self.closing {
+            true
+        } else if self.drop_probability > 0.0 && self.random.gen_bool(self.drop_probability) {
+            self.dropped_count += 1;
+            true
+        } else {
+            false
+        }
     }
 
-    pub fn run_until_parked(&self) {
-        while self.tick(false) {}
+    /// The time that moving the clock forward by `by` leads to, accounting for the clock's
+    /// drift, or `None` if the clock is frozen.
+    fn advanced_time(&self, by: Duration) -> Option<Duration> {
+        (!self.clock_frozen).then(|| self.time + by.mul_f64(self.clock_drift))
     }
 
-    pub fn parking_allowed(&self) -> bool {
-        self.state.lock().allow_parking
+    /// Describe how the clock overran the time budget, if it has.
+    fn time_budget_exceeded(&self) -> Option<String> {
+        let budget = self.time_budget?;
+        if self.time <= budget {
+            return None;
+        }
+        let mut message = format!(
+            "simulated time {:?} exceeded the time budget of {:?}. Recently scheduled:",
+            self.time, budget
+        );
+        for event in &self.recent_schedule {
+            write!(message, "\n  {:?} at {:?}", event.target, event.time).ok();
+            if let Some(name) = event.label.and_then(|label| label.name()) {
+                write!(message, " ({name})").ok();
+            }
+        }
+        Some(message)
     }
 
-    pub fn allow_parking(&self) {
-        self.state.lock().allow_parking = true
+    fn background_len(&self) -> usize {
+        self.background.values().map(Vec::len).sum()
     }
 
-    pub fn forbid_parking(&self) {
-        self.state.lock().allow_parking = false
+    /// The number of runnables waiting to run, including those handed to the background threads.
+    fn queue_depth(&self) -> usize {
+        self.foreground.values().map(VecDeque::len).sum::<usize>()
+            + self.background_len()
+            + self.deprioritized_background.len()
+            + self.saturated_background.len()
+            + self.background_in_flight()
     }
 
-    pub fn start_waiting(&self) {
-        self.state.lock().waiting_backtrace = Some(Backtrace::new_unresolved());
+    fn record_queue_depth(&mut self) {
+        self.max_queue_depth = self.max_queue_depth.max(self.queue_depth());
     }
 
-    pub fn finish_waiting(&self) {
-        self.state.lock().waiting_backtrace.take();
+    /// The number of runnables handed to the background threads that haven't finished running.
+    fn background_in_flight(&self) -> usize {
+        self.background_threads
+            .as_ref()
+            .map_or(0, |threads| threads.in_flight.count())
     }
 
-    pub fn waiting_backtrace(&self) -> Option<Backtrace> {
-        self.state.lock().waiting_backtrace.take().map(|mut b| {
-            b.resolve();
-            b
+    /// Whether as many background runnables as `max_concurrent_background` are in flight.
+    fn background_saturated(&self) -> bool {
+        self.max_concurrent_background.map_or(false, |max| {
+            let in_flight = if self.background_threads.is_some() {
+                self.background_in_flight()
+            } else {
+                self.background_streak
+            };
+            in_flight >= max
         })
     }
 
-    pub fn rng(&self) -> StdRng {
-        self.state.lock().random.clone()
+    /// Hand a runnable to the background threads, or hold it back until one of them is free
+    /// if the pool is saturated.
+    fn send_to_background_threads(&mut self, runnable: Runnable) {
+        if self.background_saturated() {
+            self.saturated_background.push_back(runnable);
+            return;
+        }
+        let threads = self.background_threads.as_ref().unwrap();
+        threads.in_flight.count.fetch_add(1, SeqCst);
+        threads.sender.send(runnable).ok();
+        // Counted as run once it's finished, see `metrics`.
+        self.total_run += 1;
     }
 
-    pub fn set_block_on_ticks(&self, range: std::ops::RangeInclusive<usize>) {
-        self.state.lock().block_on_ticks = range;
+    /// Hand the runnables held back by a saturated pool to the background threads, as far
+    /// as they have room for them.
+    fn release_saturated_background(&mut self) {
+        while self.background_threads.is_some() && !self.background_saturated() {
+            let Some(runnable) = self.saturated_background.pop_front() else {
+                break;
+            };
+            self.send_to_background_threads(runnable);
+        }
     }
 
-    pub fn gen_block_on_ticks(&self) -> usize {
-        let mut lock = self.state.lock();
-        let block_on_ticks = lock.block_on_ticks.clone();
-        lock.random.gen_range(block_on_ticks)
+    /// Move the timers that are due to the background queue, returning how many there were.
+    fn enqueue_due_timers(&mut self) -> usize {
+        if self.clock_frozen {
+            return 0;
+        }
+        let mut count = 0;
+        while let Some((deadline, _, _)) = self.delayed.first() {
+            if *deadline > self.time {
+                break;
+            }
+            let (deadline, token, runnable) = self.delayed.remove(0);
+            self.timer_deadlines.remove(&token);
+            if let Some(fired_timers) = self.fired_timers.as_mut() {
+                fired_timers.push(deadline);
+            }
+            self.background
+                .entry(Priority::Normal)
+                .or_default()
+                .push(QueuedRunnable {
+                    label: None,
+                    runnable,
+                    sequence: 0,
+                    queued_at_poll: self.poll_count,
+                });
+            count += 1;
+        }
+        if count > 0 {
+            self.record_queue_depth();
+        }
+        count
     }
-}
 
-impl Clone for TestDispatcher {
-    fn clone(&self) -> Self {
-        let id = post_inc(&mut self.state.lock().next_id.0);
-        Self {
-            id: TestDispatcherId(id),
-            state: self.state.clone(),
-            parker: self.parker.clone(),
-            unparker: self.unparker.clone(),
+    /// Decide which runnable to run next, without removing it from its queue.
+    fn choose_step(
+        &mut self,
+        background_only: bool,
+        foreground_id: Option<TestDispatcherId>,
+    ) -> Option<ScheduleStep> {
+        let foreground_len: usize = if background_only {
+            0
+        } else if let Some(id) = foreground_id {
+            self.foreground.get(&id).map_or(0, VecDeque::len)
+        } else {
+            self.foreground
+                .values()
+                .map(|runnables| runnables.len())
+                .sum()
+        };
+        let background_len = self.background_len();
+        let fifo = self.scheduling == SchedulingMode::Fifo;
+
+        if self.scheduler.is_some() {
+            return self.choose_custom_step(foreground_len, background_len, foreground_id);
         }
-    }
-}
 
-impl PlatformDispatcher for TestDispatcher {
-    fn is_main_thread(&self) -> bool {
-        self.state.lock().is_main_thread
-    }
+        if foreground_len == 0 && background_len == 0 {
+            let deprioritized_background_len = self.deprioritized_background.len();
+            if deprioritized_background_len == 0 {
+                return None;
+            }
+            let index = if fifo {
+                0
+            } else {
+                self.choose_below(deprioritized_background_len)
+            };
+            return Some(ScheduleStep::Deprioritized { index });
+        }
 
-    fn dispatch(&self, runnable: Runnable, label: Option<TaskLabel>) {
-        {
-            let mut state = self.state.lock();
-            if label.map_or(false, |label| {
-                state.deprioritized_task_labels.contains(&label)
-            }) {
-                state.deprioritized_background.push(runnable);
+        let main_thread = if foreground_len > 0 && self.background_saturated() {
+            // The background pool is saturated, so the main thread has to make progress.
+            true
+        } else if fifo {
+            foreground_len > 0
+        } else {
+            self.choose_main_thread(foreground_len, background_len)
+        };
+        let step = if main_thread {
+            let entropy_choice =
+                if self.foreground_fifo_by_time || self.round_robin_foreground || fifo {
+                    None
+                } else {
+                    let queue_count = self
+                        .foreground
+                        .iter()
+                        .filter(|(id, runnables)| {
+                            !runnables.is_empty() && foreground_id.map_or(true, |only| **id == only)
+                        })
+                        .count();
+                    self.choose_with_entropy(queue_count)
+                };
+            let queues = self.foreground.iter().filter(|(id, runnables)| {
+                !runnables.is_empty() && foreground_id.map_or(true, |only| **id == only)
+            });
+            let ids = queues.clone().map(|(id, _)| id.0);
+            let id = if self.foreground_fifo_by_time {
+                // Take the queue holding the runnable that was dispatched first.
+                queues
+                    .min_by_key(|(_, runnables)| runnables[0].sequence)
+                    .map(|(id, _)| id.0)
+            } else if self.round_robin_foreground {
+                // Take the next id after the one that ran last, wrapping around to the lowest.
+                let ids = ids.collect::<Vec<_>>();
+                let last = self.last_foreground_id;
+                let next = ids.iter().filter(|id| Some(**id) > last).min();
+                next.or_else(|| ids.iter().min()).copied()
+            } else if fifo {
+                ids.min()
+            } else if let Some(ix) = entropy_choice {
+                ids.clone().nth(ix)
             } else {
-                state.background.push(runnable);
+                ids.choose(&mut self.random)
             }
-        }
-        self.unparker.unpark();
+            .unwrap();
+            self.last_foreground_id = Some(id);
+            ScheduleStep::Main { id }
+        } else if fifo {
+            let (priority, _) = self
+                .background
+                .iter()
+                .find(|(_, runnables)| !runnables.is_empty())
+                .unwrap();
+            ScheduleStep::Background {
+                priority: *priority,
+                index: 0,
+            }
+        } else {
+            self.choose_random_background()
+        };
+        Some(step)
     }
 
-    fn dispatch_on_main_thread(&self, runnable: Runnable) {
-        self.state
-            .lock()
-            .foreground
-            .entry(self.id)
-            .or_default()
-            .push_back(runnable);
-        self.unparker.unpark();
+    /// Let the scheduler installed with [`TestDispatcher::set_scheduler`] pick the next runnable.
+    fn choose_custom_step(
+        &mut self,
+        foreground_len: usize,
+        background_len: usize,
+        foreground_id: Option<TestDispatcherId>,
+    ) -> Option<ScheduleStep> {
+        let mut choices = Vec::new();
+        if foreground_len > 0 {
+            let mut foreground = self
+                .foreground
+                .iter()
+                .filter(|(id, runnables)| {
+                    !runnables.is_empty() && foreground_id.map_or(true, |only| **id == only)
+                })
+                .collect::<Vec<_>>();
+            foreground.sort_by_key(|(id, _)| id.0);
+            for (id, runnables) in foreground {
+                choices.push((ScheduleStep::Main { id: id.0 }, runnables[0].label));
+            }
+        }
+        for (priority, runnables) in &self.background {
+            for (index, queued) in runnables.iter().enumerate() {
+                let step = ScheduleStep::Background {
+                    priority: *priority,
+                    index,
+                };
+                choices.push((step, queued.label));
+            }
+        }
+        if foreground_len == 0 && background_len == 0 {
+            for (index, queued) in self.deprioritized_background.iter().enumerate() {
+                choices.push((ScheduleStep::Deprioritized { index }, queued.label));
+            }
+        }
+        if choices.is_empty() {
+            return None;
+        }
+
+        let choices = ScheduleChoices { choices };
+        let scheduler = self.scheduler.as_mut().unwrap();
+        let ix = scheduler(&choices);
+        assert!(
+            ix < choices.choices.len(),
+            "scheduler chose runnable {ix}, but there are only {}",
+            choices.choices.len()
+        );
+        Some(choices.choices[ix].0)
     }
 
-    fn dispatch_after(&self, duration: std::time::Duration, runnable: Runnable) {
-        let mut state = self.state.lock();
-        let next_time = state.time + duration;
-        let ix = match state.delayed.binary_search_by_key(&next_time, |e| e.0) {
-            Ok(ix) | Err(ix) => ix,
+    /// Remove the runnable chosen by the given step from its queue, if it's still there.
+    ///
+    /// The rest of the queue keeps its order. Swapping the last runnable into the gap would be
+    /// cheaper, but would tie the position of each runnable, and so the steps of a recording,
+    /// to the order in which earlier ones happened to be picked.
+    fn take_step(&mut self, step: ScheduleStep) -> Option<QueuedRunnable> {
+        let (runnables, index) = match step {
+            ScheduleStep::Main { id } => {
+                return self.foreground.get_mut(&TestDispatcherId(id))?.pop_front();
+            }
+            ScheduleStep::Background { priority, index } => {
+                (self.background.get_mut(&priority)?, index)
+            }
+            ScheduleStep::Deprioritized { index } => (&mut self.deprioritized_background, index),
         };
-        state.delayed.insert(ix, (next_time, runnable));
+        if index >= runnables.len() {
+            None
+        } else {
+            Some(runnables.remove(index))
+        }
     }
 
-    fn tick(&self, background_only: bool) -> bool {
-        let mut state = self.state.lock();
-
-        while let Some((deadline, _)) = state.delayed.first() {
-            if *deadline > state.time {
-                break;
+    /// Take the runnable moved to the front of its queue by [`TestDispatcher::promote_runnable`],
+    /// unless it can't run in this tick.
+    fn take_promoted(
+        &mut self,
+        background_only: bool,
+        foreground_id: Option<TestDispatcherId>,
+    ) -> Option<(ScheduleStep, QueuedRunnable)> {
+        let step = self.promoted?;
+        if let ScheduleStep::Main { id } = step {
+            if background_only || foreground_id.map_or(false, |only| only.0 != id) {
+                return None;
             }
-            let (_, runnable) = state.delayed.remove(0);
-            state.background.push(runnable);
         }
+        self.promoted = None;
+        Some((step, self.take_step(step)?))
+    }
 
-        let foreground_len: usize = if background_only {
-            0
+    /// Pick a background runnable at random, weighting each priority so that
+    /// higher priorities run far more often without starving the lower ones.
+    /// Within a priority, every queued runnable is equally likely to be picked
+    /// regardless of its position, so any order of runnables can be reached.
+    fn choose_random_background(&mut self) -> ScheduleStep {
+        let nonempty_priorities = self
+            .background
+            .values()
+            .filter(|runnables| !runnables.is_empty())
+            .count();
+        let (priority, runnables) = if nonempty_priorities == 1 {
+            self.background
+                .iter()
+                .find(|(_, runnables)| !runnables.is_empty())
+                .unwrap()
         } else {
-            state
-                .foreground
-                .values()
-                .map(|runnables| runnables.len())
-                .sum()
+            let total_weight: usize = self
+                .background
+                .iter()
+                .map(|(priority, runnables)| priority_weight(*priority) * runnables.len())
+                .sum();
+            let mut choice = self.choose_below(total_weight);
+            self.background
+                .iter()
+                .find(|(priority, runnables)| {
+                    let weight = priority_weight(**priority) * runnables.len();
+                    if choice < weight {
+                        true
+                    } else {
+                        choice -= weight;
+                        false
+                    }
+                })
+                .unwrap()
         };
-        let background_len = state.background.len();
+        let priority = *priority;
+        let len = runnables.len();
+        let index = self.choose_below(len);
+        ScheduleStep::Background { priority, index }
+    }
 
-        let runnable;
-        let main_thread;
-        if foreground_len == 0 && background_len == 0 {
-            let deprioritized_background_len = state.deprioritized_background.len();
-            if deprioritized_background_len == 0 {
-                return false;
+    /// Decide whether to run main thread work rather than background work, in proportion to
+    /// how much of each is queued, weighted by the main bias.
+    fn choose_main_thread(&mut self, foreground_len: usize, background_len: usize) -> bool {
+        if foreground_len > 0 && background_len > 0 {
+            if let Some(byte) = self.next_entropy_byte() {
+                let main_weight = foreground_len as f64 * self.main_bias;
+                let share = main_weight / (main_weight + background_len as f64);
+                return (byte as f64 + 0.5) / 256.0 < share;
             }
-            let ix = state.random.gen_range(0..deprioritized_background_len);
-            main_thread = false;
-            runnable = state.deprioritized_background.swap_remove(ix);
-        } else {
-            main_thread = state.random.gen_ratio(
+        }
+        if self.main_bias == 1.0 {
+            self.random.gen_ratio(
                 foreground_len as u32,
                 (foreground_len + background_len) as u32,
-            );
-            if main_thread {
-                let state = &mut *state;
-                runnable = state
-                    .foreground
-                    .values_mut()
-                    .filter(|runnables| !runnables.is_empty())
-                    .choose(&mut state.random)
-                    .unwrap()
-                    .pop_front()
-                    .unwrap();
-            } else {
-                let ix = state.random.gen_range(0..background_len);
-                runnable = state.background.swap_remove(ix);
-            };
-        };
+            )
+        } else if background_len == 0 {
+            true
+        } else if foreground_len == 0 {
+            false
+        } else {
+            let main_weight = foreground_len as f64 * self.main_bias;
+            self.random
+                .gen_bool(main_weight / (main_weight + background_len as f64))
+        }
+    }
 
-        let was_main_thread = state.is_main_thread;
-        state.is_main_thread = main_thread;
-        drop(state);
-        runnable.run();
-        self.state.lock().is_main_thread = was_main_thread;
+    /// Pick one of `len` options for a scheduling decision, from the entropy source if it
+    /// has a byte left, or from the rng otherwise.
+    fn choose_below(&mut self, len: usize) -> usize {
+        self.choose_with_entropy(len)
+            .unwrap_or_else(|| self.random.gen_range(0..len))
+    }
 
-        true
+    /// Pick one of `len` options with a byte from the entropy source, if there's more than one
+    /// option and the source has a byte left.
+    fn choose_with_entropy(&mut self, len: usize) -> Option<usize> {
+        if len <= 1 {
+            return None;
+        }
+        Some(self.next_entropy_byte()? as usize % len)
     }
 
-    fn park(&self) {
-        self.parker.lock().park();
+    fn next_entropy_byte(&mut self) -> Option<u8> {
+        let byte = self.entropy.as_mut()?.next();
+        if byte.is_none() {
+            // Go back to the rng for good once the source runs out.
+            self.entropy = None;
+        }
+        byte
     }
+}
 
-    fn unparker(&self) -> Unparker {
-        self.unparker.clone()
+fn priority_weight(priority: Priority) -> usize {
+    match priority {
+        Priority::High => 16,
+        Priority::Normal => 4,
+        Priority::Low => 1,
     }
+}
 
-    fn as_test(&self) -> Option<&TestDispatcher> {
-        Some(self)
+/// Describes a runnable being handed to a [`TestDispatcher`], see [`TestDispatcher::on_schedule`].
+#[derive(Copy, Clone, Debug)]
+pub struct ScheduleEvent {
+    /// Where the runnable was queued.
+    pub target: ScheduleTarget,
+    /// The simulated time at which it was queued.
+    pub time: Duration,
+    /// The label of the task, if it was spawned with one.
+    pub label: Option<TaskLabel>,
+}
+
+/// Identifies a timer created with [`TestDispatcher::dispatch_after_with_token`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TimerToken(usize);
+
+/// The queue a runnable was scheduled on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScheduleTarget {
+    /// The main thread.
+    Main,
+    /// The background thread pool.
+    Background,
+    /// A timer, which will move the runnable to the background once the given duration has elapsed.
+    Delayed(Duration),
+}
+
+/// A single scheduling decision made by a [`TestDispatcher`], see [`TestDispatcher::start_recording`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScheduleStep {
+    /// Run the next runnable on the main thread of the dispatcher with the given [`TestDispatcher::id`].
+    Main {
+        /// The id of the dispatcher whose foreground queue was chosen.
+        id: usize,
+    },
+    /// Run a runnable from the background queue for the given priority.
+    Background {
+        /// The priority of the chosen queue.
+        priority: Priority,
+        /// The position of the runnable within that queue.
+        index: usize,
+    },
+    /// Run a runnable from the deprioritized background queue.
+    Deprioritized {
+        /// The position of the runnable within that queue.
+        index: usize,
+    },
+}
+
+/// A copy of a [`TestDispatcher`]'s queue lengths and timers at one instant, see
+/// [`TestDispatcher::snapshot`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DispatcherSnapshot {
+    /// The simulated time.
+    pub time: Duration,
+    /// The number of runnables queued for each [`TestDispatcher::id`] with foreground work.
+    pub foreground: BTreeMap<usize, usize>,
+    /// The number of runnables queued in the background, including deprioritized ones.
+    pub background: usize,
+    /// How long until each pending timer is due, soonest first.
+    pub timers: Vec<Duration>,
+}
+
+/// How many runnables each foreground queue ran while recording, see
+/// [`TestDispatcher::audit_fairness`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FairnessReport {
+    /// The number of runnables run by each [`TestDispatcher::id`] that had foreground work.
+    pub foreground_runs: BTreeMap<usize, usize>,
+}
+
+impl FairnessReport {
+    /// The fewest runnables run by any foreground queue, or 0 if none ran.
+    pub fn min_runs(&self) -> usize {
+        self.foreground_runs.values().copied().min().unwrap_or(0)
+    }
+}
+
+/// The runnables that could run next, passed to the scheduler installed with
+/// [`TestDispatcher::set_scheduler`].
+#[derive(Clone, Debug)]
+pub struct ScheduleChoices {
+    /// Each runnable that could run next, along with the label of its task. Main thread
+    /// queues come first, followed by the background queues from highest priority.
+    pub choices: Vec<(ScheduleStep, Option<TaskLabel>)>,
+}
+
+/// Marks the dispatcher as polling for as long as it's alive, restoring the previous
+/// value on drop so that nested and panicking ticks leave the flag intact.
+struct PollingGuard<'a> {
+    state: &'a Mutex<TestDispatcherState>,
+    was_polling: bool,
+}
+
+impl<'a> PollingGuard<'a> {
+    fn new(state: &'a Mutex<TestDispatcherState>) -> Self {
+        let was_polling = mem::replace(&mut state.lock().is_polling, true);
+        Self { state, was_polling }
+    }
+}
+
+impl Drop for PollingGuard<'_> {
+    fn drop(&mut self) {
+        self.state.lock().is_polling = self.was_polling;
+    }
+}
+
+/// Restores the dispatcher's main thread flag once a runnable finishes, even if it panics.
+struct MainThreadGuard<'a> {
+    state: &'a Mutex<TestDispatcherState>,
+    was_main_thread: bool,
+}
+
+impl Drop for MainThreadGuard<'_> {
+    fn drop(&mut self) {
+        self.state.lock().is_main_thread = self.was_main_thread;
+    }
+}
+
+/// Tracks a call to `BackgroundExecutor::block` for as long as it's alive, restoring the
+/// main thread flag and the nesting depth on drop, even if the blocked future panics.
+pub(crate) struct BlockGuard<'a> {
+    state: &'a Mutex<TestDispatcherState>,
+    was_main_thread: bool,
+    in_main_thread_runnable: bool,
+}
+
+impl BlockGuard<'_> {
+    /// Whether the block was entered from within a runnable on the main thread, in which
+    /// case it drives the main thread's queues too.
+    pub(crate) fn in_main_thread_runnable(&self) -> bool {
+        self.in_main_thread_runnable
+    }
+}
+
+impl Drop for BlockGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        state.block_depth -= 1;
+        state.is_main_thread = self.was_main_thread;
+    }
+}
+
+/// Marks the clock as no longer being advanced, even if a timer panics.
+struct ClockTargetGuard<'a> {
+    state: &'a Mutex<TestDispatcherState>,
+}
+
+impl Drop for ClockTargetGuard<'_> {
+    fn drop(&mut self) {
+        self.state.lock().clock_target = None;
+    }
+}
+
+/// Describe the dispatcher's state, see [`TestDispatcher::debug_dump`].
+fn debug_dump(state: &Mutex<TestDispatcherState>) -> String {
+    const MAX_TIMERS: usize = 5;
+
+    let Some(state) = state.try_lock() else {
+        return "TestDispatcher: state is locked\n".to_string();
+    };
+    let mut dump = String::new();
+    writeln!(
+        dump,
+        "TestDispatcher with seed {} at {:?}",
+        state.seed, state.time
+    )
+    .ok();
+
+    let mut foreground = state.foreground.iter().collect::<Vec<_>>();
+    foreground.sort_by_key(|(id, _)| id.0);
+    for (id, runnables) in foreground {
+        writeln!(
+            dump,
+            "  main thread {}: {} runnables",
+            id.0,
+            runnables.len()
+        )
+        .ok();
+    }
+    for (priority, runnables) in &state.background {
+        writeln!(
+            dump,
+            "  background {:?}: {} runnables",
+            priority,
+            runnables.len()
+        )
+        .ok();
+    }
+    writeln!(
+        dump,
+        "  deprioritized background: {} runnables",
+        state.deprioritized_background.len()
+    )
+    .ok();
+
+    writeln!(dump, "  timers: {}", state.delayed.len()).ok();
+    for (deadline, _, _) in state.delayed.iter().take(MAX_TIMERS) {
+        writeln!(dump, "    due in {:?}", deadline.saturating_sub(state.time)).ok();
+    }
+    if state.delayed.len() > MAX_TIMERS {
+        writeln!(dump, "    ...").ok();
+    }
+
+    writeln!(dump, "  parking allowed: {}", state.allow_parking).ok();
+    let waiting_backtrace = state.waiting_backtrace.clone();
+    drop(state);
+    if let Some(mut backtrace) = waiting_backtrace {
+        backtrace.resolve();
+        writeln!(dump, "  backtrace of waiting future:\n{:?}", backtrace).ok();
+    }
+    dump
+}
+
+struct QueuedRunnable {
+    label: Option<TaskLabel>,
+    runnable: Runnable,
+    /// The order in which the runnable was dispatched to the main thread, across every
+    /// foreground queue. Always 0 for background runnables.
+    sequence: usize,
+    /// How many runnables the dispatcher had run when this one was queued, for measuring how
+    /// long it waited, see [`TestDispatcher::latency_histogram`].
+    queued_at_poll: usize,
+}
+
+impl QueuedRunnable {
+    fn is_task(&self, task: TaskId) -> bool {
+        TaskId::of(&self.runnable) == task
+    }
+}
+
+/// Identifies the task a runnable belongs to, as all of a task's runnables share its
+/// allocation. Unlike a waker, holding on to it doesn't keep the task alive, so it's only
+/// unique while the task is.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub(crate) struct TaskId(usize);
+
+impl TaskId {
+    pub(crate) fn of(runnable: &Runnable) -> Self {
+        // SAFETY: `into_raw` forgets the copy, so the runnable is still only dropped once.
+        let ptr = unsafe { ptr::read(runnable) }.into_raw();
+        Self(ptr.as_ptr() as usize)
+    }
+}
+
+impl TestDispatcher {
+    pub fn new(seed: u64) -> Self {
+        let (parker, unparker) = parking::pair();
+        let state = TestDispatcherState {
+            seed,
+            random: StdRng::seed_from_u64(seed),
+            // Keep data randomness separate, so that drawing from it doesn't change the schedule.
+            data_random: StdRng::seed_from_u64(!seed),
+            foreground: HashMap::default(),
+            background: BTreeMap::new(),
+            deprioritized_background: Vec::new(),
+            delayed: Vec::new(),
+            next_timer_token: 0,
+            time: Duration::ZERO,
+            is_main_thread: true,
+            next_id: TestDispatcherId(1),
+            allow_parking: false,
+            waiting_backtrace: None,
+            deprioritized_task_labels: Default::default(),
+            block_on_ticks: 0..=1000,
+            last_run_label: None,
+            scheduling: SchedulingMode::Random,
+            on_schedule: None,
+            delay_weights: vec![(0..10, 1)],
+            is_polling: false,
+            recording: None,
+            detached_panic: None,
+            main_bias: 1.0,
+            time_budget: None,
+            recent_schedule: VecDeque::new(),
+            scheduler: None,
+            fired_timers: None,
+            background_threads: None,
+            hang_reporter: None,
+            clock_frozen: false,
+            round_robin_foreground: false,
+            foreground_fifo_by_time: false,
+            strict_main_thread: None,
+            next_main_thread_sequence: 0,
+            last_foreground_id: None,
+            profile: None,
+            background_capacity: None,
+            detached_tasks: BTreeMap::new(),
+            next_detached_task_id: 0,
+            clock_drift: 1.0,
+            drop_probability: 0.0,
+            dropped_count: 0,
+            promoted: None,
+            closing: false,
+            total_dispatched: 0,
+            total_run: 0,
+            max_queue_depth: 0,
+            clock_target: None,
+            idle_wakers: Vec::new(),
+            idle_count: 0,
+            jank_threshold: None,
+            run_costs: HashMap::default(),
+            jank_reports: Vec::new(),
+            block_depth: 0,
+            max_concurrent_background: None,
+            saturated_background: VecDeque::new(),
+            background_streak: 0,
+            idle_callbacks: VecDeque::new(),
+            entropy: None,
+            timer_jitter: Duration::ZERO,
+            poll_count: 0,
+            latency_histogram: None,
+            queued_tasks: HashSet::default(),
+            timer_deadlines: HashMap::default(),
+        };
+
+        TestDispatcher {
+            id: TestDispatcherId(0),
+            state: Arc::new(Mutex::new(state)),
+            parker: Arc::new(Mutex::new(parker)),
+            unparker,
+        }
+    }
+
+    /// Move the clock forward by `by`, running work and timers as they become due. When
+    /// called by a runnable while the clock is already advancing, this extends that advance.
+    pub fn advance_clock(&self, by: Duration) {
+        self.advance_clock_report(by);
+    }
+
+    /// Like [`TestDispatcher::advance_clock`], but returns the deadlines of the timers
+    /// that fired along the way, in the order they fired.
+    pub fn advance_clock_report(&self, by: Duration) -> Vec<Duration> {
+        let previous = self.state.lock().fired_timers.replace(Vec::new());
+        self.advance_clock_internal(by);
+        let mut state = self.state.lock();
+        let fired_timers = mem::replace(&mut state.fired_timers, previous).unwrap_or_default();
+        if let Some(previous) = state.fired_timers.as_mut() {
+            previous.extend_from_slice(&fired_timers);
+        }
+        fired_timers
+    }
+
+    fn advance_clock_internal(&self, by: Duration) {
+        let Some(new_now) = self.state.lock().advanced_time(by) else {
+            return;
+        };
+        self.advance_clock_to(new_now);
+    }
+
+    /// Move the clock forward to `new_now`, firing every timer that's due along the way.
+    ///
+    /// If this is called by a runnable while the clock is already being advanced, such as
+    /// a timer that advances the clock itself, it extends the advance that's in progress
+    /// rather than starting its own, and returns before the clock has moved.
+    fn advance_clock_to(&self, new_now: Duration) {
+        {
+            let mut state = self.state.lock();
+            if let Some(target) = state.clock_target.as_mut() {
+                // A nested advance would fire timers against a stale `new_now`, and then the
+                // outer one would move the clock back, so leave the timers to the outer one.
+                *target = (*target).max(new_now);
+                return;
+            }
+            state.clock_target = Some(new_now);
+        }
+        let _clock_target = ClockTargetGuard { state: &self.state };
+        loop {
+            self.run_until_parked();
+            let state = self.state.lock();
+            let new_now = state.clock_target.unwrap_or(new_now);
+            let next_due_time = state.delayed.first().map(|(time, _, _)| *time);
+            drop(state);
+            if let Some(due_time) = next_due_time {
+                if due_time <= new_now {
+                    self.set_time(due_time);
+                    continue;
+                }
+            }
+            self.set_time(new_now);
+            break;
+        }
+    }
+
+    #[track_caller]
+    fn set_time(&self, time: Duration) {
+        let mut state = self.state.lock();
+        state.time = time;
+        if let Some(message) = state.time_budget_exceeded() {
+            drop(state);
+            panic!("{message}");
+        }
+    }
+
+    /// Move the clock forward to the earliest pending timer without running anything, if it's
+    /// due by `limit`, so that a caller that's blocked and idle can wait out a simulated
+    /// timeout. Otherwise, move the clock forward to `limit` and return false. Does nothing
+    /// and returns false if the clock is frozen.
+    pub(crate) fn skip_to_next_timer(&self, limit: Duration) -> bool {
+        let mut state = self.state.lock();
+        if state.clock_frozen {
+            return false;
+        }
+        let now = state.time;
+        let next_due_time = state.delayed.first().map(|(time, _, _)| *time);
+        let (new_now, skipped) = match next_due_time {
+            Some(due_time) if due_time <= limit => (due_time.max(now), true),
+            _ => (limit.max(now), false),
+        };
+        // Don't let an advance that's in progress move the clock back afterwards.
+        if let Some(target) = state.clock_target.as_mut() {
+            *target = (*target).max(new_now);
+        }
+        drop(state);
+        self.set_time(new_now);
+        skipped
+    }
+
+    /// Panic if the simulated clock is ever advanced past `max`, for example by a retry
+    /// loop that keeps rescheduling its timer. There's no budget by default.
+    pub fn set_time_budget(&self, max: Duration) {
+        self.state.lock().time_budget = Some(max);
+    }
+
+    /// Make the clock run fast or slow, so that `advance_clock(by)` moves it forward by
+    /// `by * factor`, while timers stay due at the deadline they were created with. A factor
+    /// of 0 stops `advance_clock` from moving the clock at all. Defaults to 1.0.
+    pub fn set_clock_drift(&self, factor: f64) {
+        assert!(
+            factor.is_finite() && factor >= 0.0,
+            "clock drift must be a non-negative number, got {factor}"
+        );
+        self.state.lock().clock_drift = factor;
+    }
+
+    /// Delay each timer created from now on by a random amount of up to `max`, drawn from the
+    /// rng when it's created, so that timers due at the same time fire in an order that
+    /// depends on the seed. This catches code that assumes timers fire in the order they were
+    /// created. There's no jitter by default.
+    pub fn set_timer_jitter(&self, max: Duration) {
+        self.state.lock().timer_jitter = max;
+    }
+
+    /// Stop the clock, so that `advance_clock` does nothing and no timers fire, even
+    /// those that are already due. Timers can still be created. This allows running
+    /// all of the work that's ready without a timer interleaving with it.
+    pub fn freeze_clock(&self) {
+        self.state.lock().clock_frozen = true;
+    }
+
+    /// Undo [`TestDispatcher::freeze_clock`]. Timers that became due while the clock
+    /// was frozen fire the next time the dispatcher runs.
+    pub fn unfreeze_clock(&self) {
+        self.state.lock().clock_frozen = false;
+    }
+
+    /// Advance the clock exactly to the earliest pending timer and run everything
+    /// that becomes due. Returns how far the clock moved, or `None` if no timers
+    /// are pending or the clock is frozen.
+    pub fn advance_clock_to_next_timer(&self) -> Option<Duration> {
+        self.run_until_parked();
+        let (now, next_due_time) = {
+            let state = self.state.lock();
+            if state.clock_frozen {
+                return None;
+            }
+            (state.time, state.delayed.first().map(|(time, _, _)| *time)?)
+        };
+        self.advance_clock_to(next_due_time);
+        Some(next_due_time.saturating_sub(now))
+    }
+
+    /// Run everything, advancing the clock to each timer's deadline in turn, until nothing is
+    /// queued and no timers are pending, and return the time at which that happened. Unlike
+    /// `run_until_parked`, this lets timers fire, so it suits draining work at the end of a
+    /// test. To stop timers that keep re-arming themselves, this stops short of any timer
+    /// that's due after the budget set with [`TestDispatcher::set_time_budget`], leaving it
+    /// pending, and it also stops if the clock is frozen.
+    pub fn run_to_completion(&self) -> Duration {
+        loop {
+            self.run_until_parked();
+            let state = self.state.lock();
+            let Some((next_due_time, _, _)) = state.delayed.first() else {
+                break;
+            };
+            let past_budget = state
+                .time_budget
+                .map_or(false, |budget| *next_due_time > budget);
+            if state.clock_frozen || past_budget {
+                break;
+            }
+            let next_due_time = *next_due_time;
+            drop(state);
+            self.advance_clock_to(next_due_time);
+        }
+        self.now()
+    }
+
+    /// Like `dispatch_after`, but returns a token that can be passed to
+    /// [`TestDispatcher::cancel_timer`] to remove the timer before it fires.
+    pub fn dispatch_after_with_token(&self, duration: Duration, runnable: Runnable) -> TimerToken {
+        let mut state = self.state.lock();
+        state.notify_schedule(ScheduleTarget::Delayed(duration), None);
+        let token = TimerToken(post_inc(&mut state.next_timer_token));
+        if state.closing {
+            drop(state);
+            drop(runnable);
+            return token;
+        }
+        let mut next_time = state.time + duration;
+        if !state.timer_jitter.is_zero() {
+            let max_jitter = state.timer_jitter.as_nanos() as u64;
+            next_time += Duration::from_nanos(state.random.gen_range(0..=max_jitter));
+        }
+        let ix = match state.delayed.binary_search_by_key(&next_time, |e| e.0) {
+            Ok(ix) | Err(ix) => ix,
+        };
+        state.queued_tasks.insert(TaskId::of(&runnable));
+        state.timer_deadlines.insert(token, next_time);
+        state.delayed.insert(ix, (next_time, token, runnable));
+        token
+    }
+
+    /// Run all of the work that's queued, including timers that are pending, then discard
+    /// anything dispatched from then on. Timers created while draining that are due after
+    /// the original ones, like those of an interval, are left pending. This must not be
+    /// called from within a runnable.
+    pub fn shutdown(&self) {
+        let last_deadline = self
+            .state
+            .lock()
+            .delayed
+            .last()
+            .map(|(deadline, _, _)| *deadline);
+        self.run_until_parked();
+        if let Some(last_deadline) = last_deadline {
+            while self
+                .next_timer_deadline()
+                .map_or(false, |deadline| deadline <= last_deadline)
+            {
+                if self.advance_clock_to_next_timer().is_none() {
+                    break;
+                }
+            }
+        }
+        self.run_until_parked();
+        self.state.lock().closing = true;
+    }
+
+    /// Queue the runnables of all timers that are due, without running anything, and
+    /// return how many there were. This happens anyway whenever the dispatcher runs,
+    /// but doing it separately lets a test inspect the queues in between.
+    pub fn fire_due_timers(&self) -> usize {
+        self.state.lock().enqueue_due_timers()
+    }
+
+    /// Remove the timer with the given token, returning whether it was still pending.
+    pub fn cancel_timer(&self, token: TimerToken) -> bool {
+        let mut state = self.state.lock();
+        let Some(deadline) = state.timer_deadlines.remove(&token) else {
+            return false;
+        };
+        let start = state
+            .delayed
+            .partition_point(|(time, _, _)| *time < deadline);
+        let Some(ix) = state.delayed[start..]
+            .iter()
+            .position(|(_, t, _)| *t == token)
+        else {
+            return false;
+        };
+        let (_, _, runnable) = state.delayed.remove(start + ix);
+        state.queued_tasks.remove(&TaskId::of(&runnable));
+        drop(state);
+        // Dropping the runnable can run arbitrary code, so do it outside the lock.
+        drop(runnable);
+        true
+    }
+
+    /// Panic if any pending timer is due within `duration` of the current time.
+    /// Useful before advancing the clock up to a debounce or throttle boundary.
+    #[track_caller]
+    pub fn assert_no_timer_before(&self, duration: Duration) {
+        let state = self.state.lock();
+        let limit = state.time + duration;
+        let early_deadlines = state
+            .delayed
+            .iter()
+            .map(|(deadline, _, _)| *deadline)
+            .take_while(|deadline| *deadline < limit)
+            .collect::<Vec<_>>();
+        if !early_deadlines.is_empty() {
+            let now = state.time;
+            drop(state);
+            panic!(
+                "expected no timers due before {:?}, but found timers due at {:?} (now is {:?})",
+                limit, early_deadlines, now
+            );
+        }
+    }
+
+    /// Move the queued runnable of the given task to the front of its queue, and run it next
+    /// regardless of the rng. Does nothing if it isn't waiting to run.
+    pub(crate) fn promote_runnable(&self, task: TaskId) {
+        let mut lock = self.state.lock();
+        let state = &mut *lock;
+        if !state.queued_tasks.contains(&task) {
+            return;
+        }
+        let mut step = None;
+        for (priority, runnables) in &mut state.background {
+            if let Some(ix) = runnables.iter().position(|queued| queued.is_task(task)) {
+                let queued = runnables.remove(ix);
+                runnables.insert(0, queued);
+                step = Some(ScheduleStep::Background {
+                    priority: *priority,
+                    index: 0,
+                });
+                break;
+            }
+        }
+        if step.is_none() {
+            let runnables = &mut state.deprioritized_background;
+            if let Some(ix) = runnables.iter().position(|queued| queued.is_task(task)) {
+                let queued = runnables.remove(ix);
+                runnables.insert(0, queued);
+                step = Some(ScheduleStep::Deprioritized { index: 0 });
+            }
+        }
+        if step.is_none() {
+            for (id, runnables) in &mut state.foreground {
+                if let Some(ix) = runnables.iter().position(|queued| queued.is_task(task)) {
+                    let queued = runnables.remove(ix).unwrap();
+                    runnables.push_front(queued);
+                    step = Some(ScheduleStep::Main { id: id.0 });
+                    break;
+                }
+            }
+        }
+        if step.is_some() {
+            state.promoted = step;
+        }
+    }
+
+    /// The number of times the dispatcher has run out of work while something was waiting
+    /// for it to, see [`TestDispatcher::wake_when_idle`].
+    pub(crate) fn idle_count(&self) -> usize {
+        self.state.lock().idle_count
+    }
+
+    /// Wake the given waker the next time there's no work queued, after which
+    /// [`TestDispatcher::idle_count`] will have increased.
+    pub(crate) fn wake_when_idle(&self, waker: &Waker) {
+        let mut state = self.state.lock();
+        if !state.idle_wakers.iter().any(|idle| idle.will_wake(waker)) {
+            state.idle_wakers.push(waker.clone());
+        }
+    }
+
+    /// Run `f` the next time there's no work queued, once the callbacks registered before it
+    /// have run. Each callback runs on its own tick, so work it queues runs before the next.
+    pub(crate) fn on_idle(&self, f: Box<dyn FnOnce() + Send>) {
+        self.state.lock().idle_callbacks.push_back(f);
+    }
+
+    /// Remove the queued runnable of the given task, whether it is a pending timer or
+    /// waiting to run in the foreground or background.
+    pub(crate) fn cancel_runnable(&self, task: TaskId) {
+        let mut lock = self.state.lock();
+        let state = &mut *lock;
+        if !state.queued_tasks.remove(&task) {
+            return;
+        }
+        let removed = if let Some(ix) = state
+            .delayed
+            .iter()
+            .position(|(_, _, runnable)| TaskId::of(runnable) == task)
+        {
+            let (_, token, runnable) = state.delayed.remove(ix);
+            state.timer_deadlines.remove(&token);
+            Some(runnable)
+        } else if let Some(runnable) = state.background.values_mut().find_map(|runnables| {
+            let ix = runnables.iter().position(|queued| queued.is_task(task))?;
+            Some(runnables.remove(ix).runnable)
+        }) {
+            Some(runnable)
+        } else if let Some(ix) = state
+            .deprioritized_background
+            .iter()
+            .position(|queued| queued.is_task(task))
+        {
+            Some(state.deprioritized_background.remove(ix).runnable)
+        } else {
+            state.foreground.values_mut().find_map(|runnables| {
+                let ix = runnables.iter().position(|queued| queued.is_task(task))?;
+                runnables.remove(ix).map(|queued| queued.runnable)
+            })
+        };
+        // Drop the runnable outside the lock, since dropping its future may schedule more work.
+        drop(lock);
+        drop(removed);
+    }
+
+    /// Move the queued background runnable of the given task to the given priority, as if it
+    /// had been dispatched with it.
+    pub(crate) fn reprioritize_runnable(&self, task: TaskId, priority: Priority) {
+        let mut state = self.state.lock();
+        if !state.queued_tasks.contains(&task) {
+            return;
+        }
+        let queued = state.background.values_mut().find_map(|runnables| {
+            let ix = runnables.iter().position(|queued| queued.is_task(task))?;
+            Some(runnables.remove(ix))
+        });
+        if let Some(queued) = queued {
+            state.background.entry(priority).or_default().push(queued);
+        }
+    }
+
+    pub fn simulate_random_delay(&self) -> impl 'static + Send + Future<Output = ()> {
+        YieldNow {
+            count: self.state.lock().gen_delay(),
+        }
+    }
+
+    /// Set the range from which `simulate_random_delay` picks how many times to yield.
+    /// Defaults to `0..10`.
+    pub fn set_delay_range(&self, range: Range<usize>) {
+        self.state.lock().delay_weights = vec![(range, 1)];
+    }
+
+    /// Like [`TestDispatcher::set_delay_range`], but picks among several ranges according
+    /// to their weights, e.g. `vec![(0..10, 99), (100..1000, 1)]` to make most delays short
+    /// but occasionally very long. At least one range must have a non-zero weight.
+    pub fn set_delay_weights(&self, weights: Vec<(Range<usize>, u32)>) {
+        assert!(!weights.is_empty(), "delay weights must not be empty");
+        assert!(
+            weights.iter().any(|(_, weight)| *weight > 0),
+            "delay weights must not all be zero, got {weights:?}"
+        );
+        self.state.lock().delay_weights = weights;
+    }
+
+    pub fn deprioritize(&self, task_label: TaskLabel) {
+        self.state
+            .lock()
+            .deprioritized_task_labels
+            .insert(task_label);
+    }
+
+    /// Register a callback that is invoked whenever a runnable is dispatched, before
+    /// it is queued. The callback runs while the dispatcher is locked, so it must not
+    /// call back into the dispatcher.
+    pub fn on_schedule(&self, f: impl Fn(ScheduleEvent) + Send + 'static) {
+        self.state.lock().on_schedule = Some(Box::new(f));
+    }
+
+    /// Draw scheduling decisions from `bytes` rather than the rng, so that a coverage guided
+    /// fuzzer can steer interleavings directly, going back to the rng once it runs out. Each
+    /// decision with more than one option consumes one byte:
+    ///
+    /// - Whether to run main thread or background work, when both are queued. The main thread
+    ///   is picked if `(byte + 0.5) / 256` is less than its share of the queued work, weighted
+    ///   by [`TestDispatcher::set_main_bias`].
+    /// - Which main thread queue to run, when several have work, as `byte % queues`, with
+    ///   queues in the order of their ids.
+    /// - Which background priority to run, when several have work, as `byte % total_weight`,
+    ///   where each priority's weight is its number of runnables times 16, 4 or 1 for high,
+    ///   normal and low priority, in that order.
+    /// - Which runnable to run within that priority, or among the deprioritized runnables once
+    ///   nothing else is queued, as `byte % runnables`.
+    ///
+    /// So a tick consumes at most three bytes. Other random choices, such as delays and which
+    /// waiter to wake, still use the rng. The source is ignored in `SchedulingMode::Fifo` and
+    /// while a scheduler is installed with [`TestDispatcher::set_scheduler`].
+    pub fn set_entropy_source(&self, bytes: impl Iterator<Item = u8> + Send + 'static) {
+        self.state.lock().entropy = Some(Box::new(bytes));
+    }
+
+    /// Weight the choice between main thread and background work in random scheduling.
+    /// With the default of 1.0, each queued runnable is equally likely to run next. Greater
+    /// values favor the main thread, and smaller ones favor the background. A bias of 0
+    /// starves the main thread whenever there's background work, which is useful for
+    /// testing timeouts and watchdogs.
+    pub fn set_main_bias(&self, bias: f64) {
+        assert!(
+            bias.is_finite() && bias >= 0.0,
+            "main bias must be a non-negative number, got {bias}"
+        );
+        self.state.lock().main_bias = bias;
+    }
+
+    /// Take over scheduling decisions, for example to explore interleavings exhaustively.
+    /// Whenever the dispatcher runs a runnable, `f` is given the runnables that could run
+    /// and returns the index of the one to run. The rng is not used while it's installed.
+    /// `f` is called with the dispatcher's state locked, so it must not call back into it.
+    pub fn set_scheduler(&self, f: impl FnMut(&ScheduleChoices) -> usize + Send + 'static) {
+        self.state.lock().scheduler = Some(Box::new(f));
+    }
+
+    /// Run background runnables on `n` real OS threads rather than simulating them, or
+    /// go back to simulating them if `n` is 0. Foreground runnables, timers and
+    /// `advance_clock` stay on the simulated path, so timing remains deterministic.
+    ///
+    /// The order in which background work interleaves is then up to the OS, so a seed
+    /// no longer reproduces a run exactly, and recordings can't be replayed.
+    /// `run_until_parked` waits for runnables that are running on these threads.
+    pub fn set_background_parallelism(&self, n: usize) {
+        let mut state = self.state.lock();
+        let previous = mem::replace(
+            &mut state.background_threads,
+            (n > 0).then(|| BackgroundThreads::new(n)),
+        );
+        // Runnables held back by a saturated pool are simulated from now on.
+        if n == 0 {
+            let saturated = mem::take(&mut state.saturated_background);
+            let poll_count = state.poll_count;
+            state.queued_tasks.extend(saturated.iter().map(TaskId::of));
+            state
+                .background
+                .entry(Priority::Normal)
+                .or_default()
+                .extend(saturated.into_iter().map(|runnable| QueuedRunnable {
+                    label: None,
+                    runnable,
+                    sequence: 0,
+                    queued_at_poll: poll_count,
+                }));
+        }
+        drop(state);
+        // Dropping the previous threads' sender lets them exit once their queue is drained.
+        drop(previous);
+    }
+
+    /// Model a saturated thread pool, by allowing at most `n` background runnables to be in
+    /// flight at once. With real threads, see [`TestDispatcher::set_background_parallelism`],
+    /// further runnables wait until a thread is free. In simulation, where background
+    /// runnables run one at a time, the main thread is made to run next once `n` background
+    /// runnables have run in a row, if it has work. Either way, this lets tests check that the
+    /// app degrades gracefully rather than deadlocking when background work backs up.
+    pub fn set_max_concurrent_background(&self, n: usize) {
+        assert!(
+            n > 0,
+            "at least one background runnable must be able to run"
+        );
+        self.state.lock().max_concurrent_background = Some(n);
+    }
+
+    /// The number of background runnables in flight, as limited by
+    /// [`TestDispatcher::set_max_concurrent_background`]. With real threads, it's how many
+    /// are running on them. In simulation, it's how many have run since the main thread
+    /// last ran.
+    pub fn background_in_flight(&self) -> usize {
+        let state = self.state.lock();
+        if state.background_threads.is_some() {
+            state.background_in_flight()
+        } else {
+            state.background_streak
+        }
+    }
+
+    /// Limit how many runnables can wait in the background queues, so that
+    /// `try_dispatch` rejects new work once they're full. `dispatch` always succeeds,
+    /// as it's also used to reschedule tasks that have already been accepted.
+    /// The queues are unbounded by default.
+    pub fn set_background_capacity(&self, capacity: usize) {
+        self.state.lock().background_capacity = Some(capacity);
+    }
+
+    /// Simulate a platform that loses work, by discarding each runnable passed to `dispatch`
+    /// or `dispatch_on_main_thread` with probability `p`, as decided by the rng. Dropping a
+    /// runnable cancels its task, and awaiting a cancelled task panics, so this is for testing
+    /// that the timeouts and retries of other tasks kick in. Defaults to 0.
+    pub fn set_drop_probability(&self, p: f64) {
+        assert!(
+            (0.0..=1.0).contains(&p),
+            "drop probability must be between 0 and 1, got {p}"
+        );
+        self.state.lock().drop_probability = p;
+    }
+
+    /// The number of runnables discarded because of [`TestDispatcher::set_drop_probability`].
+    pub fn dropped_count(&self) -> usize {
+        self.state.lock().dropped_count
+    }
+
+    /// Switch between randomized and fully predictable scheduling.
+    pub fn set_scheduling(&self, mode: SchedulingMode) {
+        self.state.lock().scheduling = mode;
+    }
+
+    /// The scheduling mode set with [`TestDispatcher::set_scheduling`].
+    pub fn scheduling(&self) -> SchedulingMode {
+        self.state.lock().scheduling
+    }
+
+    pub fn run_until_parked(&self) {
+        debug_assert!(
+            !self.is_polling(),
+            "run_until_parked called reentrantly from within a runnable"
+        );
+        while self.tick(false) {}
+    }
+
+    /// Run up to `n` runnables, stopping early if nothing is left to run, and return how many ran.
+    /// The clock isn't advanced, so only timers whose deadline has already passed will fire.
+    pub fn poll_n(&self, n: usize) -> usize {
+        let mut ran = 0;
+        while ran < n && self.tick(false) {
+            ran += 1;
+        }
+        ran
+    }
+
+    /// Like [`TestDispatcher::run_until_parked`], but only runs foreground work queued by the
+    /// dispatcher with the given [`TestDispatcher::id`], alongside the shared background work.
+    pub fn run_until_parked_for(&self, id: usize) {
+        debug_assert!(
+            !self.is_polling(),
+            "run_until_parked_for called reentrantly from within a runnable"
+        );
+        while self.tick_internal(false, Some(TestDispatcherId(id))) {}
+    }
+
+    /// Run the work of the window whose foreground queue is the one of the dispatcher with
+    /// the given [`TestDispatcher::id`], along with the shared background work, until it's
+    /// parked. Other windows' main thread work is left queued, so one window that keeps
+    /// redrawing doesn't stop a test from making assertions about another.
+    /// See [`TestDispatcher::run_until_parked_for`].
+    pub fn run_window_until_parked(&self, id: usize) {
+        self.run_until_parked_for(id);
+    }
+
+    /// Create a dispatcher with its own foreground queue, modeling another main thread.
+    /// It shares the background pool, clock and rng with this one.
+    pub fn fork(&self) -> TestDispatcher {
+        self.clone()
+    }
+
+    /// The identity of this dispatcher's foreground queue.
+    pub fn id(&self) -> usize {
+        self.id.0
+    }
+
+    /// A handle on the dispatcher with the given [`TestDispatcher::id`], sharing this one's
+    /// state, so that work can be queued for another foreground queue.
+    pub(crate) fn with_id(&self, id: usize) -> TestDispatcher {
+        assert!(
+            id < self.state.lock().next_id.0,
+            "no foreground queue with id {id}"
+        );
+        Self {
+            id: TestDispatcherId(id),
+            state: self.state.clone(),
+            parker: self.parker.clone(),
+            unparker: self.unparker.clone(),
+        }
+    }
+
+    /// Start recording every scheduling decision, discarding anything recorded so far.
+    pub fn start_recording(&self) {
+        self.state.lock().recording = Some(Vec::new());
+    }
+
+    /// Stop recording and return the decisions made since [`TestDispatcher::start_recording`].
+    pub fn take_recording(&self) -> Vec<ScheduleStep> {
+        self.state.lock().recording.take().unwrap_or_default()
+    }
+
+    /// Start counting how many times runnables are run, grouped by the name of their
+    /// task's label, see [`TestDispatcher::profile_report`], and how long they waited to run,
+    /// see [`TestDispatcher::latency_histogram`]. This doesn't affect scheduling.
+    pub fn enable_profiling(&self) {
+        let mut state = self.state.lock();
+        state.profile.get_or_insert_with(HashMap::default);
+        state.latency_histogram.get_or_insert_with(BTreeMap::new);
+    }
+
+    /// Stop counting runs and discard the counts.
+    pub fn disable_profiling(&self) {
+        let mut state = self.state.lock();
+        state.profile = None;
+        state.latency_histogram = None;
+    }
+
+    /// How many other runnables ran while each runnable was waiting to run, since
+    /// [`TestDispatcher::enable_profiling`], as pairs of that wait and the number of runnables
+    /// that waited that long, ordered by the wait. Comparing these across settings like
+    /// [`TestDispatcher::set_main_bias`] and task priorities shows how fairly work is
+    /// scheduled, with a long tail indicating starvation. A runnable queued by a timer starts
+    /// waiting when the timer fires.
+    pub fn latency_histogram(&self) -> Vec<(usize, usize)> {
+        self.state
+            .lock()
+            .latency_histogram
+            .iter()
+            .flatten()
+            .map(|(latency, count)| (*latency, *count))
+            .collect()
+    }
+
+    /// The number of times runnables of each task label ran since
+    /// [`TestDispatcher::enable_profiling`], with the most frequent first. Runnables
+    /// whose task has no named label are counted as `"(unlabeled)"`.
+    pub fn profile_report(&self) -> Vec<(&'static str, usize)> {
+        let mut report = self
+            .state
+            .lock()
+            .profile
+            .iter()
+            .flatten()
+            .map(|(name, count)| (*name, *count))
+            .collect::<Vec<_>>();
+        report.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        report
+    }
+
+    /// Count how many runnables each foreground queue has run since
+    /// [`TestDispatcher::start_recording`], to detect one window starving another.
+    /// Call this before [`TestDispatcher::take_recording`]. Queues that haven't run
+    /// anything are omitted.
+    pub fn audit_fairness(&self) -> FairnessReport {
+        let mut report = FairnessReport::default();
+        for step in self.state.lock().recording.iter().flatten() {
+            if let ScheduleStep::Main { id } = step {
+                *report.foreground_runs.entry(*id).or_default() += 1;
+            }
+        }
+        report
+    }
+
+    /// Cycle through the foreground queues that have work in order of their
+    /// [`TestDispatcher::id`], rather than picking one at random. This only affects
+    /// which foreground queue runs, not the choice between foreground and background.
+    pub fn set_round_robin_foreground(&self, round_robin: bool) {
+        self.state.lock().round_robin_foreground = round_robin;
+    }
+
+    /// Make every runnable with the given label take `cost` of simulated time to run on the
+    /// main thread, moving the clock forward once it has run, so that slow tasks can be
+    /// caught by the jank threshold deterministically, see [`TestDispatcher::take_jank_reports`].
+    /// The clock moves as it would for `advance_clock(cost)`, so the cost is scaled by the
+    /// clock's drift, isn't charged while the clock is frozen, and counts against the time
+    /// budget. Timers that become due fire on a later step.
+    pub fn set_run_cost(&self, label: TaskLabel, cost: Duration) {
+        self.state.lock().run_costs.insert(label, cost);
+    }
+
+    /// The label names and run times of the main thread runnables that have run for longer
+    /// than the jank threshold since the last call.
+    pub fn take_jank_reports(&self) -> Vec<(Option<&'static str>, Duration)> {
+        mem::take(&mut self.state.lock().jank_reports)
+    }
+
+    /// Hold the dispatcher to the constraints of a real platform's threads, taking the calling
+    /// thread as the main thread. By default, `is_main_thread` only reflects whether the
+    /// runnable being run was dispatched to the main thread, so code on any other thread, such
+    /// as one spawned by the test, sees `true` while foreground work runs. In strict mode, every
+    /// thread but the main one sees `false`, and running foreground work from another thread
+    /// panics.
+    pub fn strict_mode(&self) {
+        self.state.lock().strict_main_thread = Some(thread::current().id());
+    }
+
+    /// Run foreground work in the order it was dispatched across every foreground queue,
+    /// rather than picking a queue at random, so that main thread ordering is the same for
+    /// every seed. Takes precedence over [`TestDispatcher::set_round_robin_foreground`], and
+    /// like it, doesn't affect the choice between foreground and background.
+    pub fn set_foreground_fifo_by_time(&self, fifo: bool) {
+        self.state.lock().foreground_fifo_by_time = fifo;
+    }
+
+    /// Run runnables in the order given by a recording, rather than picking them with the rng.
+    /// The dispatcher should be in the same state and scheduling mode as when the recording
+    /// started. Panics if a step refers to a runnable that isn't queued.
+    pub fn replay(&self, steps: Vec<ScheduleStep>) {
+        for (ix, step) in steps.into_iter().enumerate() {
+            let _polling = PollingGuard::new(&self.state);
+            let mut state = self.state.lock();
+            state.enqueue_due_timers();
+            let Some(queued) = state.take_step(step) else {
+                drop(state);
+                panic!("replay diverged at step {ix}: nothing to run for {step:?}");
+            };
+            self.run_queued(state, step, queued);
+        }
+    }
+
+    fn run_queued(
+        &self,
+        mut state: MutexGuard<TestDispatcherState>,
+        step: ScheduleStep,
+        queued: QueuedRunnable,
+    ) {
+        if let Some(main_thread) = state.strict_main_thread {
+            if matches!(step, ScheduleStep::Main { .. }) && thread::current().id() != main_thread {
+                drop(state);
+                drop(queued);
+                panic!("strict mode: a foreground runnable was run on a thread other than the main thread");
+            }
+        }
+        if let Some(recording) = state.recording.as_mut() {
+            recording.push(step);
+        }
+        state.queued_tasks.remove(&TaskId::of(&queued.runnable));
+        let _main_thread = MainThreadGuard {
+            state: &self.state,
+            was_main_thread: state.is_main_thread,
+        };
+        state.is_main_thread = matches!(step, ScheduleStep::Main { .. });
+        if state.is_main_thread {
+            state.background_streak = 0;
+        } else {
+            state.background_streak += 1;
+        }
+        state.last_run_label = queued.label;
+        state.total_run += 1;
+        if let Some(profile) = state.profile.as_mut() {
+            let name = queued.label.and_then(|label| label.name());
+            *profile.entry(name.unwrap_or("(unlabeled)")).or_default() += 1;
+        }
+        let latency = state.poll_count - queued.queued_at_poll;
+        if let Some(histogram) = state.latency_histogram.as_mut() {
+            *histogram.entry(latency).or_default() += 1;
+        }
+        state.poll_count += 1;
+        let start = state.time;
+        drop(state);
+        queued.runnable.run();
+        if matches!(step, ScheduleStep::Main { .. }) {
+            self.finish_main_thread_run(queued.label, start);
+        }
+    }
+
+    /// Charge a runnable that just ran on the main thread its simulated cost, and report it
+    /// if it ran for longer than the jank threshold.
+    fn finish_main_thread_run(&self, label: Option<TaskLabel>, start: Duration) {
+        let cost = label.and_then(|label| self.state.lock().run_costs.get(&label).copied());
+        if let Some(cost) = cost {
+            self.charge_run_cost(cost);
+        }
+        let mut state = self.state.lock();
+        let Some(threshold) = state.jank_threshold else {
+            return;
+        };
+        let elapsed = state.time.saturating_sub(start);
+        if report_jank(label, elapsed, threshold) {
+            state
+                .jank_reports
+                .push((label.and_then(|label| label.name()), elapsed));
+        }
+    }
+
+    /// Move the clock forward by a runnable's simulated cost, as `advance_clock` would but
+    /// without running anything, since the dispatcher is still polling.
+    #[track_caller]
+    fn charge_run_cost(&self, cost: Duration) {
+        let mut state = self.state.lock();
+        let Some(new_now) = state.advanced_time(cost) else {
+            return;
+        };
+        // Don't let an advance that's in progress move the clock back afterwards.
+        if let Some(target) = state.clock_target.as_mut() {
+            *target = (*target).max(new_now);
+        }
+        drop(state);
+        self.set_time(new_now);
+    }
+
+    /// Re-raise the panic of a detached or dropped task, if one has panicked since the last call.
+    #[track_caller]
+    pub(crate) fn raise_detached_panic(&self) {
+        let detached_panic = self.state.lock().detached_panic.take();
+        if let Some(DetachedPanic {
+            label,
+            location,
+            payload,
+        }) = detached_panic
+        {
+            panic!(
+                "detached or dropped task {} spawned at {} panicked: {}",
+                label
+                    .and_then(|label| label.name())
+                    .map_or_else(|| "(unlabeled)".to_string(), |name| format!("{name:?}")),
+                location,
+                panic_message(&*payload)
+            );
+        }
+    }
+
+    /// Hold on to the panic of a detached or dropped task, to be re-raised by the next `tick`.
+    pub(crate) fn record_detached_panic(
+        &self,
+        label: Option<TaskLabel>,
+        location: &'static Location<'static>,
+        payload: Box<dyn Any + Send>,
+    ) {
+        let mut state = self.state.lock();
+        if state.detached_panic.is_none() {
+            state.detached_panic = Some(DetachedPanic {
+                label,
+                location,
+                payload,
+            });
+        }
+    }
+
+    /// Start tracking a task spawned with `BackgroundExecutor::spawn_detached`, returning
+    /// an id to pass to `unregister_detached_task` once it completes or is dropped.
+    pub(crate) fn register_detached_task(
+        &self,
+        name: &'static str,
+        location: &'static Location<'static>,
+    ) -> usize {
+        let mut state = self.state.lock();
+        let id = post_inc(&mut state.next_detached_task_id);
+        state.detached_tasks.insert(id, (name, location));
+        id
+    }
+
+    pub(crate) fn unregister_detached_task(&self, id: usize) {
+        self.state.lock().detached_tasks.remove(&id);
+    }
+
+    /// The label names of tasks spawned with `BackgroundExecutor::spawn_detached` that
+    /// haven't completed, and where they were spawned, in the order they were spawned.
+    /// Asserting that this is empty once a test has run until parked catches detached tasks
+    /// that never finish.
+    pub fn leaked_detached_tasks(&self) -> Vec<(&'static str, &'static Location<'static>)> {
+        self.state.lock().detached_tasks.values().copied().collect()
+    }
+
+    /// How many calls to `BackgroundExecutor::block` are in progress, counting each one nested
+    /// within a runnable run by an outer block.
+    pub fn block_depth(&self) -> usize {
+        self.state.lock().block_depth
+    }
+
+    /// Enter a call to `BackgroundExecutor::block`, which is left when the guard is dropped.
+    pub(crate) fn enter_block(&self) -> BlockGuard<'_> {
+        let mut state = self.state.lock();
+        state.block_depth += 1;
+        BlockGuard {
+            state: &self.state,
+            was_main_thread: state.is_main_thread,
+            in_main_thread_runnable: state.is_polling && state.is_main_thread,
+        }
+    }
+
+    /// Whether a call to `tick` is in progress, meaning the caller is running inside a runnable.
+    pub fn is_polling(&self) -> bool {
+        self.state.lock().is_polling
+    }
+
+    /// Like [`TestDispatcher::run_until_parked`], but runs at most `max_steps`
+    /// runnables. Returns `false` if work was still queued once the cap was hit,
+    /// which usually means a task keeps rescheduling itself.
+    pub fn run_until_parked_or(&self, max_steps: usize) -> bool {
+        debug_assert!(
+            !self.is_polling(),
+            "run_until_parked_or called reentrantly from within a runnable"
+        );
+        for _ in 0..max_steps {
+            if !self.tick(false) {
+                return true;
+            }
+        }
+        let mut state = self.state.lock();
+        state.enqueue_due_timers();
+        state.queue_depth() == 0 && state.idle_callbacks.is_empty() && state.idle_wakers.is_empty()
+    }
+
+    /// The number of runnables waiting to run, including timers that haven't fired yet.
+    pub fn pending_task_count(&self) -> usize {
+        let state = self.state.lock();
+        state.foreground.values().map(VecDeque::len).sum::<usize>()
+            + state.background_len()
+            + state.deprioritized_background.len()
+            + state.delayed.len()
+            + state.background_in_flight()
+    }
+
+    /// Panic with a [`TestDispatcher::debug_dump`] if anything is queued, any timer is
+    /// pending, or a runnable is being polled. Unlike checking `pending_task_count`, this also
+    /// catches being called from within a runnable, so it suits an end-of-test guard.
+    #[track_caller]
+    pub fn assert_idle(&self) {
+        let state = self.state.lock();
+        let idle = !state.is_polling && state.queue_depth() == 0 && state.delayed.is_empty();
+        drop(state);
+        if !idle {
+            panic!("expected the dispatcher to be idle\n{}", self.debug_dump());
+        }
+    }
+
+    /// The number of timers that haven't fired yet.
+    pub fn pending_timer_count(&self) -> usize {
+        self.state.lock().delayed.len()
+    }
+
+    /// The simulated time at which the earliest pending timer is due, if any.
+    pub fn next_timer_deadline(&self) -> Option<Duration> {
+        self.state
+            .lock()
+            .delayed
+            .first()
+            .map(|(deadline, _, _)| *deadline)
+    }
+
+    pub fn parking_allowed(&self) -> bool {
+        self.state.lock().allow_parking
+    }
+
+    pub fn allow_parking(&self) {
+        self.state.lock().allow_parking = true
+    }
+
+    pub fn forbid_parking(&self) {
+        self.state.lock().allow_parking = false
+    }
+
+    pub fn start_waiting(&self) {
+        self.state.lock().waiting_backtrace = Some(Backtrace::new_unresolved());
+    }
+
+    pub fn finish_waiting(&self) {
+        self.state.lock().waiting_backtrace.take();
+    }
+
+    pub fn waiting_backtrace(&self) -> Option<Backtrace> {
+        self.state.lock().waiting_backtrace.take().map(|mut b| {
+            b.resolve();
+            b
+        })
+    }
+
+    /// Copy the lengths of the dispatcher's queues and the deadlines of its timers, for
+    /// tools that visualize what's pending. No runnables are exposed.
+    pub fn snapshot(&self) -> DispatcherSnapshot {
+        let state = self.state.lock();
+        DispatcherSnapshot {
+            time: state.time,
+            foreground: state
+                .foreground
+                .iter()
+                .filter(|(_, runnables)| !runnables.is_empty())
+                .map(|(id, runnables)| (id.0, runnables.len()))
+                .collect(),
+            background: state.background_len() + state.deprioritized_background.len(),
+            timers: state
+                .delayed
+                .iter()
+                .map(|(deadline, _, _)| deadline.saturating_sub(state.time))
+                .collect(),
+        }
+    }
+
+    /// Describe the dispatcher's queues, pending timers and waiting backtrace, to help
+    /// diagnose a hung test. This doesn't block if the dispatcher's state is locked,
+    /// so it's safe to call from a panic hook.
+    pub fn debug_dump(&self) -> String {
+        debug_dump(&self.state)
+    }
+
+    /// Like [`TestDispatcher::install_hang_reporter_with_timeout`], with a timeout of
+    /// one minute.
+    pub fn install_hang_reporter(&self) {
+        self.install_hang_reporter_with_timeout(DEFAULT_HANG_TIMEOUT);
+    }
+
+    /// Watch for the test hanging, for example on CI. If the dispatcher is still alive
+    /// after `timeout` of real time, print [`TestDispatcher::debug_dump`] and abort the
+    /// process. The reporter does nothing once every clone of the dispatcher is dropped,
+    /// and installing a new one replaces it.
+    pub fn install_hang_reporter_with_timeout(&self, timeout: Duration) {
+        let (finished_tx, finished_rx) = mpsc::channel::<()>();
+        let state = Arc::downgrade(&self.state);
+        thread::Builder::new()
+            .name("TestDispatcher hang reporter".into())
+            .spawn(move || {
+                // Nothing is ever sent, so this only returns early once the sender is dropped.
+                if finished_rx.recv_timeout(timeout) != Err(mpsc::RecvTimeoutError::Timeout) {
+                    return;
+                }
+                let Some(state) = state.upgrade() else {
+                    return;
+                };
+                eprintln!(
+                    "test did not finish within {:?}\n{}",
+                    timeout,
+                    debug_dump(&state)
+                );
+                std::process::abort();
+            })
+            .expect("failed to spawn hang reporter thread");
+        self.state.lock().hang_reporter = Some(finished_tx);
+    }
+
+    /// Returns a copy of the scheduling rng in its current state. Consider using
+    /// [`TestDispatcher::data_rng`] instead, as the values a copy yields depend on how much
+    /// scheduling has happened so far.
+    pub fn rng(&self) -> StdRng {
+        self.state.lock().random.clone()
+    }
+
+    /// Returns a new rng for generating test data, like ids or random edits. Each call
+    /// forks a different rng from a stream derived from the seed, so all of a test's
+    /// randomness is reproducible from the seed, but using it never affects scheduling.
+    pub fn data_rng(&self) -> StdRng {
+        StdRng::from_rng(&mut self.state.lock().data_random).unwrap()
+    }
+
+    /// The seed this dispatcher was constructed with. Passing it back to
+    /// [`TestDispatcher::new`] reproduces the same interleaving.
+    pub fn seed(&self) -> u64 {
+        self.state.lock().seed
+    }
+
+    /// Replace the scheduling rng with one seeded from `seed`. Runnables that
+    /// are already queued stay where they are, only subsequent scheduling
+    /// choices are affected. This does not change the value returned by [`TestDispatcher::seed`].
+    pub fn reseed(&self, seed: u64) {
+        self.state.lock().random = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn set_block_on_ticks(&self, range: std::ops::RangeInclusive<usize>) {
+        self.state.lock().block_on_ticks = range;
+    }
+
+    /// The name of the label attached to the most recently run runnable, if any.
+    /// See [`TaskLabel::named`].
+    pub fn last_run_label(&self) -> Option<&'static str> {
+        self.state
+            .lock()
+            .last_run_label
+            .and_then(|label| label.name())
+    }
+
+    /// Pick a number in `0..len` using the scheduling rng. Used by executor
+    /// primitives that need to break ties deterministically.
+    pub(crate) fn gen_index(&self, len: usize) -> usize {
+        self.state.lock().random.gen_range(0..len)
+    }
+
+    pub fn gen_block_on_ticks(&self) -> usize {
+        let mut lock = self.state.lock();
+        let block_on_ticks = lock.block_on_ticks.clone();
+        lock.random.gen_range(block_on_ticks)
+    }
+
+    fn tick_internal(
+        &self,
+        background_only: bool,
+        foreground_id: Option<TestDispatcherId>,
+    ) -> bool {
+        self.raise_detached_panic();
+        let _polling = PollingGuard::new(&self.state);
+        let mut state = self.state.lock();
+        let next = loop {
+            state.enqueue_due_timers();
+            state.release_saturated_background();
+            if let Some(message) = state.time_budget_exceeded() {
+                drop(state);
+                panic!("{message}");
+            }
+            let promoted = state.take_promoted(background_only, foreground_id);
+            let next = promoted.or_else(|| {
+                let step = state.choose_step(background_only, foreground_id)?;
+                Some((step, state.take_step(step).unwrap()))
+            });
+            let in_flight = state
+                .background_threads
+                .as_ref()
+                .map(|threads| threads.in_flight.clone())
+                .filter(|in_flight| in_flight.count() > 0);
+            match (next, in_flight) {
+                (None, Some(in_flight)) => {
+                    // Work is still running on the background threads, and may queue more,
+                    // so wait for some of it to finish and look again. Waiting isn't a step.
+                    drop(state);
+                    in_flight.wait();
+                    state = self.state.lock();
+                }
+                (next, _) => break next,
+            }
+        };
+        let Some((step, queued)) = next else {
+            if state.queue_depth() > 0 {
+                return false;
+            }
+            if let Some(callback) = state.idle_callbacks.pop_front() {
+                drop(state);
+                callback();
+                return true;
+            }
+            if !state.idle_wakers.is_empty() {
+                state.idle_count += 1;
+                let wakers = mem::take(&mut state.idle_wakers);
+                drop(state);
+                for waker in wakers {
+                    waker.wake();
+                }
+                return true;
+            }
+            return false;
+        };
+        self.run_queued(state, step, queued);
+        true
+    }
+}
+
+impl Clone for TestDispatcher {
+    fn clone(&self) -> Self {
+        let id = post_inc(&mut self.state.lock().next_id.0);
+        Self {
+            id: TestDispatcherId(id),
+            state: self.state.clone(),
+            parker: self.parker.clone(),
+            unparker: self.unparker.clone(),
+        }
+    }
+}
+
+impl PlatformDispatcher for TestDispatcher {
+    fn is_main_thread(&self) -> bool {
+        if IS_BACKGROUND_THREAD.with(Cell::get) {
+            return false;
+        }
+        let state = self.state.lock();
+        if let Some(main_thread) = state.strict_main_thread {
+            if thread::current().id() != main_thread {
+                return false;
+            }
+        }
+        state.is_main_thread
+    }
+
+    fn dispatch(&self, runnable: Runnable, label: Option<TaskLabel>, priority: Priority) {
+        {
+            let mut state = self.state.lock();
+            state.notify_schedule(ScheduleTarget::Background, label);
+            if state.should_drop_runnable() {
+                drop(state);
+                drop(runnable);
+                return;
+            }
+            let deprioritized = label.map_or(false, |label| {
+                state.deprioritized_task_labels.contains(&label)
+            });
+            let queued = QueuedRunnable {
+                label,
+                runnable,
+                sequence: 0,
+                queued_at_poll: state.poll_count,
+            };
+            if deprioritized {
+                state.queued_tasks.insert(TaskId::of(&queued.runnable));
+                state.deprioritized_background.push(queued);
+            } else if state.background_threads.is_some() {
+                state.send_to_background_threads(queued.runnable);
+            } else {
+                state.queued_tasks.insert(TaskId::of(&queued.runnable));
+                state.background.entry(priority).or_default().push(queued);
+            }
+            state.record_queue_depth();
+        }
+        self.unparker.unpark();
+    }
+
+    fn try_dispatch(
+        &self,
+        runnable: Runnable,
+        label: Option<TaskLabel>,
+        priority: Priority,
+    ) -> Result<(), Overloaded> {
+        let state = self.state.lock();
+        if let Some(capacity) = state.background_capacity {
+            let queued = state.background_len()
+                + state.deprioritized_background.len()
+                + state.background_in_flight();
+            if queued >= capacity {
+                drop(state);
+                // Dropping the runnable can run arbitrary code, so do it outside the lock.
+                drop(runnable);
+                return Err(Overloaded);
+            }
+        }
+        drop(state);
+        self.dispatch(runnable, label, priority);
+        Ok(())
+    }
+
+    fn dispatch_on_main_thread(&self, runnable: Runnable, label: Option<TaskLabel>) {
+        let mut state = self.state.lock();
+        state.notify_schedule(ScheduleTarget::Main, label);
+        if state.should_drop_runnable() {
+            drop(state);
+            drop(runnable);
+            return;
+        }
+        let sequence = post_inc(&mut state.next_main_thread_sequence);
+        let queued_at_poll = state.poll_count;
+        state.queued_tasks.insert(TaskId::of(&runnable));
+        state
+            .foreground
+            .entry(self.id)
+            .or_default()
+            .push_back(QueuedRunnable {
+                label,
+                runnable,
+                sequence,
+                queued_at_poll,
+            });
+        state.record_queue_depth();
+        drop(state);
+        self.unparker.unpark();
+    }
+
+    fn dispatch_after(&self, duration: std::time::Duration, runnable: Runnable) {
+        self.dispatch_after_with_token(duration, runnable);
+    }
+
+    fn tick(&self, background_only: bool) -> bool {
+        self.tick_internal(background_only, None)
+    }
+
+    fn now(&self) -> Duration {
+        self.state.lock().time
+    }
+
+    fn set_jank_threshold(&self, threshold: Duration) {
+        self.state.lock().jank_threshold = Some(threshold);
+    }
+
+    fn metrics(&self) -> Option<DispatcherMetrics> {
+        let state = self.state.lock();
+        Some(DispatcherMetrics {
+            dispatched: state.total_dispatched,
+            run: state.total_run - state.background_in_flight(),
+            queue_depth: state.queue_depth(),
+            max_queue_depth: state.max_queue_depth,
+        })
+    }
+
+    fn park(&self) {
+        self.parker.lock().park();
+    }
+
+    fn unparker(&self) -> Unparker {
+        self.unparker.clone()
+    }
+
+    fn as_test(&self) -> Option<&TestDispatcher> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BackgroundExecutor, ForegroundExecutor};
+    use collections::BTreeSet;
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[test]
+    fn test_every_background_order_is_reachable() {
+        const TASKS: usize = 3;
+
+        let mut orders = BTreeSet::new();
+        for seed in 0..200 {
+            let dispatcher = TestDispatcher::new(seed);
+            let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+            let order = Arc::new(Mutex::new(Vec::new()));
+            for ix in 0..TASKS {
+                let order = order.clone();
+                executor
+                    .spawn(async move { order.lock().push(ix) })
+                    .detach();
+            }
+            dispatcher.run_until_parked();
+            let order = order.lock().clone();
+            assert_eq!(order.len(), TASKS);
+            orders.insert(order);
+        }
+
+        // Every permutation of the tasks, including each of them running first.
+        assert_eq!(orders.len(), 6);
+    }
+
+    #[test]
+    fn test_sleep_resumes_when_clock_reaches_deadline() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let wakes = Arc::new(AtomicUsize::new(0));
+        executor
+            .spawn({
+                let executor = executor.clone();
+                let wakes = wakes.clone();
+                async move {
+                    executor.sleep(Duration::from_secs(1)).await;
+                    wakes.fetch_add(1, SeqCst);
+                }
+            })
+            .detach();
+
+        dispatcher.run_until_parked();
+        assert_eq!(wakes.load(SeqCst), 0);
+        dispatcher.advance_clock(Duration::from_millis(999));
+        assert_eq!(wakes.load(SeqCst), 0);
+        dispatcher.advance_clock(Duration::from_millis(1));
+        assert_eq!(wakes.load(SeqCst), 1);
+        dispatcher.advance_clock(Duration::from_secs(1));
+        assert_eq!(wakes.load(SeqCst), 1);
+    }
+
+    #[test]
+    fn test_metrics_count_dispatched_and_run_runnables() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        for _ in 0..3 {
+            executor.spawn(async {}).detach();
+        }
+        assert_eq!(
+            dispatcher.metrics(),
+            Some(DispatcherMetrics {
+                dispatched: 3,
+                run: 0,
+                queue_depth: 3,
+                max_queue_depth: 3,
+            })
+        );
+
+        dispatcher.run_until_parked();
+        assert_eq!(
+            dispatcher.metrics(),
+            Some(DispatcherMetrics {
+                dispatched: 3,
+                run: 3,
+                queue_depth: 0,
+                max_queue_depth: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn test_advance_clock_from_timer() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        for delay in [1, 3, 5] {
+            executor
+                .spawn({
+                    let executor = executor.clone();
+                    let fired = fired.clone();
+                    async move {
+                        executor.timer(Duration::from_secs(delay)).await;
+                        fired.lock().push((delay, executor.now()));
+                    }
+                })
+                .detach();
+        }
+        executor
+            .spawn({
+                let executor = executor.clone();
+                let dispatcher = dispatcher.clone();
+                async move {
+                    executor.timer(Duration::from_secs(2)).await;
+                    dispatcher.advance_clock(Duration::from_secs(2));
+                }
+            })
+            .detach();
+
+        dispatcher.advance_clock(Duration::from_secs(3));
+        // The nested advance carried the clock on to 4s, firing each timer once, in order.
+        assert_eq!(dispatcher.now(), Duration::from_secs(4));
+        assert_eq!(
+            *fired.lock(),
+            [(1, Duration::from_secs(1)), (3, Duration::from_secs(3))]
+        );
+
+        dispatcher.advance_clock(Duration::from_secs(1));
+        assert_eq!(dispatcher.now(), Duration::from_secs(5));
+        assert_eq!(fired.lock().len(), 3);
+    }
+
+    #[test]
+    fn test_spawn_on_another_foreground_queue() {
+        let dispatcher = TestDispatcher::new(0);
+        let window = dispatcher.fork();
+        let executor = ForegroundExecutor::new(Arc::new(dispatcher.clone()));
+        let ran = Arc::new(AtomicUsize::new(0));
+        executor
+            .spawn_on(window.id(), {
+                let ran = ran.clone();
+                async move {
+                    ran.fetch_add(1, SeqCst);
+                }
+            })
+            .detach();
+
+        dispatcher.run_until_parked_for(dispatcher.id());
+        assert_eq!(ran.load(SeqCst), 0);
+        dispatcher.run_until_parked_for(window.id());
+        assert_eq!(ran.load(SeqCst), 1);
+    }
+
+    #[test]
+    fn test_run_window_until_parked_ignores_other_windows() {
+        let dispatcher = TestDispatcher::new(0);
+        let window = dispatcher.fork();
+        let executor = ForegroundExecutor::new(Arc::new(dispatcher.clone()));
+        // A window that redraws forever.
+        executor
+            .spawn(async {
+                loop {
+                    YieldNow { count: 1 }.await;
+                }
+            })
+            .detach();
+        let ran = Arc::new(AtomicUsize::new(0));
+        executor
+            .spawn_on(window.id(), {
+                let ran = ran.clone();
+                async move {
+                    YieldNow { count: 2 }.await;
+                    ran.fetch_add(1, SeqCst);
+                }
+            })
+            .detach();
+
+        dispatcher.run_window_until_parked(window.id());
+        assert_eq!(ran.load(SeqCst), 1);
+        assert_eq!(dispatcher.pending_task_count(), 1);
+    }
+
+    #[test]
+    fn test_assert_idle_with_a_sleeping_task() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        executor
+            .spawn(executor.sleep(Duration::from_secs(1)))
+            .detach();
+        dispatcher.run_until_parked();
+        assert_eq!(dispatcher.pending_task_count(), 1);
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| dispatcher.assert_idle())).is_err());
+
+        dispatcher.advance_clock(Duration::from_secs(1));
+        dispatcher.assert_idle();
+    }
+
+    #[test]
+    fn test_foreground_fifo_by_time_across_queues() {
+        for seed in 0..10 {
+            let dispatcher = TestDispatcher::new(seed);
+            dispatcher.set_foreground_fifo_by_time(true);
+            let executors = [dispatcher.clone(), dispatcher.fork(), dispatcher.fork()]
+                .map(|dispatcher| ForegroundExecutor::new(Arc::new(dispatcher)));
+            let order = Arc::new(Mutex::new(Vec::new()));
+            for ix in 0..9 {
+                let order = order.clone();
+                executors[(ix * 2) % 3]
+                    .spawn(async move { order.lock().push(ix) })
+                    .detach();
+            }
+            dispatcher.run_until_parked();
+            assert_eq!(*order.lock(), (0..9).collect::<Vec<_>>(), "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn test_strict_mode_main_thread_is_an_os_thread() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = ForegroundExecutor::new(Arc::new(dispatcher.clone()));
+        let observed = Arc::new(Mutex::new(Vec::new()));
+        let spawn_observer = |dispatcher: TestDispatcher| {
+            let observed = observed.clone();
+            executor.spawn(async move {
+                let on_other_thread = thread::scope(|scope| {
+                    scope.spawn(|| dispatcher.is_main_thread()).join().unwrap()
+                });
+                observed
+                    .lock()
+                    .push((dispatcher.is_main_thread(), on_other_thread));
+            })
+        };
+
+        spawn_observer(dispatcher.clone()).detach();
+        dispatcher.run_until_parked();
+        dispatcher.strict_mode();
+        spawn_observer(dispatcher.clone()).detach();
+        dispatcher.run_until_parked();
+        assert_eq!(*observed.lock(), [(true, true), (true, false)]);
+    }
+
+    #[test]
+    fn test_next_tick_waits_for_queued_work() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        executor
+            .spawn({
+                let executor = executor.clone();
+                let events = events.clone();
+                async move {
+                    executor.spawn(executor.simulate_random_delay()).detach();
+                    executor.next_tick().await;
+                    events.lock().push("first tick");
+                    executor.next_tick().await;
+                    events.lock().push("second tick");
+                }
+            })
+            .detach();
+        executor
+            .spawn({
+                let events = events.clone();
+                async move {
+                    for _ in 0..10 {
+                        YieldNow { count: 1 }.await;
+                    }
+                    events.lock().push("busy work");
+                }
+            })
+            .detach();
+
+        dispatcher.run_until_parked();
+        assert_eq!(*events.lock(), ["busy work", "first tick", "second tick"]);
+        dispatcher.assert_idle();
+    }
+
+    #[test]
+    fn test_nested_block_runs_main_thread_work() {
+        let dispatcher = TestDispatcher::new(0);
+        let background = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let foreground = ForegroundExecutor::new(Arc::new(dispatcher.clone()));
+        let observed = Arc::new(Mutex::new(Vec::new()));
+        foreground
+            .spawn({
+                let dispatcher = dispatcher.clone();
+                let foreground = foreground.clone();
+                let observed = observed.clone();
+                async move {
+                    let inner = background.block(async {
+                        let on_main_thread = foreground.spawn({
+                            let dispatcher = dispatcher.clone();
+                            async move { (dispatcher.is_main_thread(), dispatcher.block_depth()) }
+                        });
+                        let on_background = background.spawn({
+                            let dispatcher = dispatcher.clone();
+                            async move { dispatcher.is_main_thread() }
+                        });
+                        (on_main_thread.await, on_background.await)
+                    });
+                    observed.lock().push((
+                        inner,
+                        dispatcher.is_main_thread(),
+                        dispatcher.block_depth(),
+                    ));
+                }
+            })
+            .detach();
+
+        dispatcher.run_until_parked();
+        assert_eq!(*observed.lock(), [(((true, 1), false), true, 0)]);
+        dispatcher.assert_idle();
+    }
+
+    #[test]
+    fn test_saturated_background_forces_main_thread_progress() {
+        let dispatcher = TestDispatcher::new(0);
+        let background = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let foreground = ForegroundExecutor::new(Arc::new(dispatcher.clone()));
+        dispatcher.set_main_bias(0.0);
+        dispatcher.set_max_concurrent_background(2);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for _ in 0..5 {
+            let order = order.clone();
+            background
+                .spawn(async move { order.lock().push('b') })
+                .detach();
+        }
+        let order_clone = order.clone();
+        foreground
+            .spawn(async move { order_clone.lock().push('m') })
+            .detach();
+
+        dispatcher.run_until_parked();
+        assert_eq!(order.lock().iter().collect::<String>(), "bbmbbb");
+        assert_eq!(dispatcher.background_in_flight(), 3);
+    }
+
+    #[test]
+    fn test_waiting_on_background_threads_is_not_a_step() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        dispatcher.set_background_parallelism(1);
+        let task = executor.spawn(async {
+            thread::sleep(Duration::from_millis(20));
+            7
+        });
+
+        // Handing the runnable to the thread is the only step. Then the dispatcher waits for
+        // it to finish, rather than spinning, and parks once nothing is left.
+        assert_eq!(dispatcher.poll_n(100), 1);
+        assert_eq!(dispatcher.background_in_flight(), 0);
+        assert_eq!(executor.block_test(task), 7);
+    }
+
+    #[test]
+    fn test_idle_callbacks_wait_for_queued_work() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        executor
+            .spawn({
+                let executor = executor.clone();
+                let events = events.clone();
+                async move {
+                    executor.timer(Duration::from_secs(1)).await;
+                    events.lock().push("timer");
+                }
+            })
+            .detach();
+        executor.on_idle({
+            let executor = executor.clone();
+            let events = events.clone();
+            move || {
+                events.lock().push("first idle");
+                executor
+                    .spawn(async move { events.lock().push("work") })
+                    .detach();
+            }
+        });
+        executor.on_idle({
+            let events = events.clone();
+            move || events.lock().push("second idle")
+        });
+
+        dispatcher.run_until_parked();
+        assert_eq!(*events.lock(), ["first idle", "work", "second idle"]);
+        dispatcher.advance_clock(Duration::from_secs(1));
+        assert_eq!(
+            *events.lock(),
+            ["first idle", "work", "second idle", "timer"]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "a task spawned on the main thread was run in the background")]
+    fn test_main_thread_task_routed_to_background_panics() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = ForegroundExecutor::new(Arc::new(dispatcher.clone()));
+        executor.spawn(async {}).detach();
+
+        // Simulate a routing bug by moving the task's runnable to the background queue.
+        let mut state = dispatcher.state.lock();
+        let queued = state
+            .foreground
+            .values_mut()
+            .find_map(VecDeque::pop_front)
+            .unwrap();
+        state
+            .background
+            .entry(Priority::Normal)
+            .or_default()
+            .push(queued);
+        drop(state);
+        dispatcher.run_until_parked();
+    }
+
+    #[test]
+    fn test_latency_histogram_counts_polls_waited() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        dispatcher.set_scheduling(SchedulingMode::Fifo);
+        dispatcher.enable_profiling();
+        for _ in 0..3 {
+            executor.spawn(async {}).detach();
+        }
+        dispatcher.run_until_parked();
+        assert_eq!(dispatcher.latency_histogram(), [(0, 1), (1, 1), (2, 1)]);
+
+        dispatcher.disable_profiling();
+        assert!(dispatcher.latency_histogram().is_empty());
+    }
+
+    #[test]
+    fn test_run_to_completion_fires_timers() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let period = Duration::from_millis(100);
+        let spawn_ticker = |ticks: Option<usize>| {
+            let executor = executor.clone();
+            executor.spawn(async move {
+                let mut count = 0;
+                while ticks.map_or(true, |ticks| count < ticks) {
+                    executor.timer(period).await;
+                    count += 1;
+                }
+            })
+        };
+
+        let ticker = spawn_ticker(Some(3));
+        assert_eq!(dispatcher.run_to_completion(), period * 3);
+        assert!(ticker.is_finished());
+
+        // A timer that keeps re-arming is only run up to the time budget.
+        dispatcher.set_time_budget(Duration::from_secs(1));
+        let _ticker = spawn_ticker(None);
+        assert_eq!(dispatcher.run_to_completion(), Duration::from_secs(1));
+        assert_eq!(dispatcher.pending_timer_count(), 1);
+    }
+
+    #[test]
+    fn test_timer_jitter_reorders_simultaneous_timers() {
+        let mut orders = BTreeSet::new();
+        for seed in 0..20 {
+            let dispatcher = TestDispatcher::new(seed);
+            let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+            dispatcher.set_scheduling(SchedulingMode::Fifo);
+            dispatcher.set_timer_jitter(Duration::from_millis(5));
+            let order = Arc::new(Mutex::new(Vec::new()));
+            for ix in 0..2 {
+                let order = order.clone();
+                executor
+                    .spawn_after(Duration::from_millis(10), async move {
+                        order.lock().push(ix);
+                    })
+                    .detach();
+            }
+            dispatcher.advance_clock(Duration::from_millis(15));
+            let order = order.lock().clone();
+            assert_eq!(order.len(), 2, "seed {seed}");
+            orders.insert(order);
+        }
+        assert_eq!(orders.len(), 2);
+    }
+
+    #[test]
+    fn test_entropy_source_picks_runnables() {
+        for (bytes, expected) in [(vec![2, 0], [2, 0, 1]), (vec![1, 1], [1, 2, 0])] {
+            let dispatcher = TestDispatcher::new(0);
+            let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+            dispatcher.set_entropy_source(bytes.into_iter());
+            let order = Arc::new(Mutex::new(Vec::new()));
+            for ix in 0..3 {
+                let order = order.clone();
+                executor
+                    .spawn(async move { order.lock().push(ix) })
+                    .detach();
+            }
+            dispatcher.run_until_parked();
+            assert_eq!(*order.lock(), expected);
+        }
+    }
+
+    #[test]
+    fn test_jank_reports_use_simulated_run_costs() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = ForegroundExecutor::new(Arc::new(dispatcher.clone()));
+        let slow = TaskLabel::named("slow");
+        let fast = TaskLabel::named("fast");
+        dispatcher.set_jank_threshold(Duration::from_millis(16));
+        dispatcher.set_run_cost(slow, Duration::from_millis(50));
+        dispatcher.set_run_cost(fast, Duration::from_millis(1));
+
+        executor.spawn_labeled(slow, async {}).detach();
+        executor.spawn_labeled(fast, async {}).detach();
+        dispatcher.run_until_parked();
+        assert_eq!(
+            dispatcher.take_jank_reports(),
+            [(Some("slow"), Duration::from_millis(50))]
+        );
+        assert_eq!(dispatcher.now(), Duration::from_millis(51));
+    }
+
+    #[test]
+    fn test_run_costs_move_the_clock_like_advance_clock() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = ForegroundExecutor::new(Arc::new(dispatcher.clone()));
+        let slow = TaskLabel::named("slow");
+        dispatcher.set_run_cost(slow, Duration::from_millis(50));
+
+        dispatcher.set_clock_drift(0.5);
+        executor.spawn_labeled(slow, async {}).detach();
+        dispatcher.run_until_parked();
+        assert_eq!(dispatcher.now(), Duration::from_millis(25));
+
+        dispatcher.freeze_clock();
+        executor.spawn_labeled(slow, async {}).detach();
+        dispatcher.run_until_parked();
+        assert_eq!(dispatcher.now(), Duration::from_millis(25));
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeded the time budget")]
+    fn test_run_costs_count_against_the_time_budget() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = ForegroundExecutor::new(Arc::new(dispatcher.clone()));
+        let slow = TaskLabel::named("slow");
+        dispatcher.set_run_cost(slow, Duration::from_millis(50));
+        dispatcher.set_time_budget(Duration::from_millis(10));
+        executor.spawn_labeled(slow, async {}).detach();
+        dispatcher.run_until_parked();
+    }
+
+    #[test]
+    #[should_panic(expected = "delay weights must not be empty")]
+    fn test_empty_delay_weights_are_rejected() {
+        TestDispatcher::new(0).set_delay_weights(Vec::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "delay weights must not all be zero")]
+    fn test_zero_delay_weights_are_rejected() {
+        TestDispatcher::new(0).set_delay_weights(vec![(0..10, 0), (100..1000, 0)]);
+    }
+
+    #[test]
+    fn test_run_until_parked_or_runs_at_most_max_steps() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = ForegroundExecutor::new(Arc::new(dispatcher.clone()));
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..3 {
+            executor
+                .spawn({
+                    let ran = ran.clone();
+                    async move {
+                        ran.fetch_add(1, SeqCst);
+                    }
+                })
+                .detach();
+        }
+
+        assert!(!dispatcher.run_until_parked_or(2));
+        assert_eq!(ran.load(SeqCst), 2);
+        assert!(dispatcher.run_until_parked_or(1));
+        assert_eq!(ran.load(SeqCst), 3);
+        assert!(dispatcher.run_until_parked_or(0));
+    }
+
+    #[test]
+    fn test_data_rngs_do_not_affect_scheduling() {
+        let dispatcher = TestDispatcher::new(7);
+        let expected = dispatcher.rng().gen::<u64>();
+        let first = dispatcher.data_rng().gen::<u64>();
+        assert_ne!(dispatcher.data_rng().gen::<u64>(), first);
+        assert_eq!(dispatcher.rng().gen::<u64>(), expected);
+        assert_eq!(
+            TestDispatcher::new(7).rng().gen::<u64>(),
+            StdRng::seed_from_u64(7).gen::<u64>()
+        );
+        assert_eq!(TestDispatcher::new(7).data_rng().gen::<u64>(), first);
+    }
+
+    #[test]
+    fn test_seed_and_reseed_reproduce_the_schedule() {
+        fn background_order(dispatcher: &TestDispatcher) -> Vec<usize> {
+            let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+            let order = Arc::new(Mutex::new(Vec::new()));
+            for ix in 0..8 {
+                let order = order.clone();
+                executor
+                    .spawn(async move { order.lock().push(ix) })
+                    .detach();
+            }
+            dispatcher.run_until_parked();
+            let mut order = order.lock();
+            mem::take(&mut *order)
+        }
+
+        let dispatcher = TestDispatcher::new(42);
+        assert_eq!(dispatcher.seed(), 42);
+        let order = background_order(&dispatcher);
+        assert_eq!(
+            background_order(&TestDispatcher::new(dispatcher.seed())),
+            order
+        );
+
+        dispatcher.reseed(7);
+        assert_eq!(dispatcher.seed(), 42);
+        assert_eq!(
+            background_order(&dispatcher),
+            background_order(&TestDispatcher::new(7))
+        );
+        dispatcher.reseed(42);
+        assert_eq!(background_order(&dispatcher), order);
+    }
+
+    #[test]
+    fn test_last_run_label_names_the_runnable_that_ran() {
+        let dispatcher = TestDispatcher::new(0);
+        dispatcher.set_scheduling(SchedulingMode::Fifo);
+        let foreground = ForegroundExecutor::new(Arc::new(dispatcher.clone()));
+        let background = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        foreground
+            .spawn_labeled(TaskLabel::named("render"), async {})
+            .detach();
+        foreground.spawn(async {}).detach();
+        background
+            .spawn_labeled(TaskLabel::named("index"), async {})
+            .detach();
+        assert_eq!(dispatcher.last_run_label(), None);
+
+        dispatcher.poll_n(1);
+        assert_eq!(dispatcher.last_run_label(), Some("render"));
+        dispatcher.poll_n(1);
+        assert_eq!(dispatcher.last_run_label(), None);
+        dispatcher.poll_n(1);
+        assert_eq!(dispatcher.last_run_label(), Some("index"));
+    }
+
+    #[test]
+    fn test_advance_clock_to_next_timer_stops_at_each_deadline() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        for delay in [3, 1] {
+            executor
+                .spawn({
+                    let executor = executor.clone();
+                    let fired = fired.clone();
+                    async move {
+                        executor.timer(Duration::from_secs(delay)).await;
+                        fired.lock().push(delay);
+                    }
+                })
+                .detach();
+        }
+
+        assert_eq!(
+            dispatcher.advance_clock_to_next_timer(),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(dispatcher.now(), Duration::from_secs(1));
+        assert_eq!(*fired.lock(), [1]);
+        assert_eq!(
+            dispatcher.advance_clock_to_next_timer(),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(dispatcher.now(), Duration::from_secs(3));
+        assert_eq!(*fired.lock(), [1, 3]);
+        assert_eq!(dispatcher.advance_clock_to_next_timer(), None);
+        assert_eq!(dispatcher.now(), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_fifo_scheduling_ignores_the_seed() {
+        for seed in 0..10 {
+            let dispatcher = TestDispatcher::new(seed);
+            dispatcher.set_scheduling(SchedulingMode::Fifo);
+            let foreground = ForegroundExecutor::new(Arc::new(dispatcher.clone()));
+            let background = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+            let order = Arc::new(Mutex::new(Vec::new()));
+            for ix in 0..2 {
+                background
+                    .spawn({
+                        let order = order.clone();
+                        async move { order.lock().push(format!("background {ix}")) }
+                    })
+                    .detach();
+                foreground
+                    .spawn({
+                        let order = order.clone();
+                        async move { order.lock().push(format!("foreground {ix}")) }
+                    })
+                    .detach();
+            }
+
+            dispatcher.run_until_parked();
+            assert_eq!(
+                *order.lock(),
+                [
+                    "foreground 0",
+                    "foreground 1",
+                    "background 0",
+                    "background 1"
+                ],
+                "seed {seed}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_pending_counts_include_timers() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        executor.spawn(async {}).detach();
+        executor
+            .spawn_after(Duration::from_secs(1), async {})
+            .detach();
+        assert_eq!(dispatcher.pending_task_count(), 2);
+        assert_eq!(dispatcher.pending_timer_count(), 1);
+
+        dispatcher.run_until_parked();
+        assert_eq!(dispatcher.pending_task_count(), 1);
+        assert_eq!(dispatcher.pending_timer_count(), 1);
+
+        dispatcher.advance_clock(Duration::from_secs(1));
+        assert_eq!(dispatcher.pending_task_count(), 0);
+        assert_eq!(dispatcher.pending_timer_count(), 0);
+    }
+
+    #[test]
+    fn test_high_priority_usually_runs_first_without_starving_low() {
+        let mut high_first = 0;
+        for seed in 0..200 {
+            let dispatcher = TestDispatcher::new(seed);
+            let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+            let order = Arc::new(Mutex::new(Vec::new()));
+            for priority in [Priority::Low, Priority::High] {
+                let order = order.clone();
+                executor
+                    .spawn_with_priority(priority, async move { order.lock().push(priority) })
+                    .detach();
+            }
+            dispatcher.run_until_parked();
+            if order.lock()[0] == Priority::High {
+                high_first += 1;
+            }
+        }
+
+        // High priority work is weighted 16 to 1 against low priority work.
+        assert!(high_first > 150, "{high_first}");
+        assert!(high_first < 200, "{high_first}");
+    }
+
+    #[test]
+    fn test_on_schedule_observes_every_dispatch() {
+        let dispatcher = TestDispatcher::new(0);
+        let foreground = ForegroundExecutor::new(Arc::new(dispatcher.clone()));
+        let background = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        dispatcher.on_schedule({
+            let events = events.clone();
+            move |event| {
+                events
+                    .lock()
+                    .push((event.target, event.time, event.label.and_then(|l| l.name())))
+            }
+        });
+
+        dispatcher.advance_clock(Duration::from_secs(1));
+        foreground.spawn(async {}).detach();
+        background
+            .spawn_labeled(TaskLabel::named("index"), async {})
+            .detach();
+        background
+            .spawn_after(Duration::from_secs(2), async {})
+            .detach();
+        assert_eq!(
+            *events.lock(),
+            [
+                (ScheduleTarget::Main, Duration::from_secs(1), None),
+                (
+                    ScheduleTarget::Background,
+                    Duration::from_secs(1),
+                    Some("index")
+                ),
+                (
+                    ScheduleTarget::Delayed(Duration::from_secs(2)),
+                    Duration::from_secs(1),
+                    None
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_polling_only_within_a_runnable() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let polling = executor.spawn({
+            let dispatcher = dispatcher.clone();
+            async move { dispatcher.is_polling() }
+        });
+        assert!(!dispatcher.is_polling());
+        assert!(executor.block_test(polling));
+        assert!(!dispatcher.is_polling());
+    }
+
+    #[test]
+    #[should_panic(expected = "run_until_parked called reentrantly from within a runnable")]
+    fn test_reentrant_run_until_parked_panics() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        executor
+            .spawn({
+                let dispatcher = dispatcher.clone();
+                async move { dispatcher.run_until_parked() }
+            })
+            .detach();
+        dispatcher.run_until_parked();
+    }
+
+    #[test]
+    fn test_forked_dispatchers_have_their_own_foreground_queue() {
+        let dispatcher = TestDispatcher::new(0);
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let mut ids = Vec::new();
+        for name in ["first", "second"] {
+            let fork = dispatcher.fork();
+            ids.push(fork.id());
+            let ran = ran.clone();
+            ForegroundExecutor::new(Arc::new(fork))
+                .spawn(async move { ran.lock().push(name) })
+                .detach();
+        }
+        assert_ne!(ids[0], ids[1]);
+        BackgroundExecutor::new(Arc::new(dispatcher.clone()))
+            .spawn({
+                let ran = ran.clone();
+                async move { ran.lock().push("background") }
+            })
+            .detach();
+
+        dispatcher.run_until_parked_for(ids[1]);
+        ran.lock().sort();
+        assert_eq!(*ran.lock(), ["background", "second"]);
+        dispatcher.run_until_parked_for(ids[0]);
+        assert_eq!(ran.lock().last(), Some(&"first"));
+
+        // The clock is shared too.
+        dispatcher.fork().advance_clock(Duration::from_secs(1));
+        assert_eq!(dispatcher.now(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_poll_n_stops_after_n_runnables() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        for _ in 0..3 {
+            executor.spawn(async {}).detach();
+        }
+        executor
+            .spawn_after(Duration::from_millis(1), async {})
+            .detach();
+
+        assert_eq!(dispatcher.poll_n(2), 2);
+        assert_eq!(dispatcher.pending_task_count(), 2);
+        // The clock isn't advanced, so the timer doesn't fire.
+        assert_eq!(dispatcher.poll_n(5), 1);
+        assert_eq!(dispatcher.poll_n(1), 0);
+        assert_eq!(dispatcher.pending_timer_count(), 1);
+        assert_eq!(dispatcher.now(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_assert_no_timer_before_allows_a_timer_at_the_boundary() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let _timer = executor.timer(Duration::from_secs(1));
+        dispatcher.assert_no_timer_before(Duration::from_secs(1));
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            dispatcher.assert_no_timer_before(Duration::from_secs(2))
+        }));
+        let message = panic_message(&*result.unwrap_err()).to_string();
+        assert!(
+            message.starts_with("expected no timers due before 2s, but found timers due at [1s]"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn test_replaying_a_recording_reproduces_the_order() {
+        fn spawn_tasks(dispatcher: &TestDispatcher) -> Arc<Mutex<Vec<usize>>> {
+            let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+            let order = Arc::new(Mutex::new(Vec::new()));
+            for ix in 0..6 {
+                let order = order.clone();
+                executor
+                    .spawn(async move { order.lock().push(ix) })
+                    .detach();
+            }
+            order
+        }
+
+        let dispatcher = TestDispatcher::new(1);
+        let order = spawn_tasks(&dispatcher);
+        dispatcher.start_recording();
+        dispatcher.run_until_parked();
+        let recording = dispatcher.take_recording();
+        assert_eq!(recording.len(), 6);
+
+        // The recording overrides whatever order the rng of another seed would pick.
+        let replayed = TestDispatcher::new(2);
+        let replayed_order = spawn_tasks(&replayed);
+        replayed.replay(recording);
+        assert_eq!(*replayed_order.lock(), *order.lock());
+        assert_eq!(replayed.pending_task_count(), 0);
+    }
+
+    #[test]
+    fn test_debug_dump_describes_queues_and_timers() {
+        let dispatcher = TestDispatcher::new(3);
+        dispatcher.advance_clock(Duration::from_secs(1));
+        let window = dispatcher.fork();
+        let window_id = window.id();
+        ForegroundExecutor::new(Arc::new(window))
+            .spawn(async {})
+            .detach();
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        executor.spawn(async {}).detach();
+        let _timer = executor.timer(Duration::from_secs(2));
+
+        let dump = dispatcher.debug_dump();
+        for line in [
+            "TestDispatcher with seed 3 at 1s".to_string(),
+            format!("  main thread {window_id}: 1 runnables"),
+            "  background Normal: 1 runnables".to_string(),
+            "  timers: 1".to_string(),
+            "    due in 2s".to_string(),
+        ] {
+            assert!(
+                dump.lines().any(|dumped| dumped == line),
+                "{line:?} in {dump}"
+            );
+        }
+
+        let _state = dispatcher.state.lock();
+        assert_eq!(dispatcher.debug_dump(), "TestDispatcher: state is locked\n");
+    }
+
+    #[test]
+    fn test_zero_main_bias_runs_background_work_first() {
+        for seed in 0..20 {
+            let dispatcher = TestDispatcher::new(seed);
+            dispatcher.set_main_bias(0.0);
+            let order = Arc::new(Mutex::new(Vec::new()));
+            ForegroundExecutor::new(Arc::new(dispatcher.clone()))
+                .spawn({
+                    let order = order.clone();
+                    async move { order.lock().push("main") }
+                })
+                .detach();
+            BackgroundExecutor::new(Arc::new(dispatcher.clone()))
+                .spawn({
+                    let order = order.clone();
+                    async move { order.lock().push("background") }
+                })
+                .detach();
+
+            dispatcher.run_until_parked();
+            assert_eq!(*order.lock(), ["background", "main"], "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn test_time_budget_catches_a_retry_loop() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        dispatcher.set_time_budget(Duration::from_secs(5));
+        executor
+            .spawn_labeled(TaskLabel::named("retry"), {
+                let executor = executor.clone();
+                async move {
+                    loop {
+                        executor.timer(Duration::from_secs(1)).await;
+                    }
+                }
+            })
+            .detach();
+
+        dispatcher.advance_clock(Duration::from_secs(5));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            dispatcher.advance_clock(Duration::from_secs(1))
+        }));
+        let message = panic_message(&*result.unwrap_err()).to_string();
+        assert!(
+            message.starts_with("simulated time 6s exceeded the time budget of 5s"),
+            "{message}"
+        );
+        assert!(message.contains("Background at 5s (retry)"), "{message}");
+    }
+
+    #[test]
+    fn test_custom_scheduler_picks_the_next_runnable() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let offered = Arc::new(Mutex::new(Vec::new()));
+        dispatcher.set_scheduler({
+            let offered = offered.clone();
+            move |choices| {
+                let names = choices
+                    .choices
+                    .iter()
+                    .map(|(_, label)| label.and_then(|label| label.name()))
+                    .collect::<Vec<_>>();
+                offered.lock().push(names);
+                choices.choices.len() - 1
+            }
+        });
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for name in ["a", "b", "c"] {
+            executor
+                .spawn_labeled(TaskLabel::named(name), {
+                    let order = order.clone();
+                    async move { order.lock().push(name) }
+                })
+                .detach();
+        }
+
+        dispatcher.run_until_parked();
+        assert_eq!(*order.lock(), ["c", "b", "a"]);
+        assert_eq!(
+            *offered.lock(),
+            [
+                vec![Some("a"), Some("b"), Some("c")],
+                vec![Some("a"), Some("b")],
+                vec![Some("a")],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_advance_clock_report_lists_the_timers_that_fired() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let _timers = [5, 1, 3].map(|secs| executor.timer(Duration::from_secs(secs)));
+        executor
+            .spawn({
+                let executor = executor.clone();
+                async move {
+                    executor.timer(Duration::from_secs(2)).await;
+                    executor.timer(Duration::from_secs(2)).await;
+                }
+            })
+            .detach();
+        dispatcher.run_until_parked();
+
+        assert_eq!(
+            dispatcher.advance_clock_report(Duration::from_secs(4)),
+            [1, 2, 3, 4].map(Duration::from_secs)
+        );
+        assert_eq!(
+            dispatcher.advance_clock_report(Duration::from_secs(2)),
+            [Duration::from_secs(5)]
+        );
+        assert!(dispatcher
+            .advance_clock_report(Duration::from_secs(1))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_hang_reporter_is_disarmed_by_replacing_or_dropping_it() {
+        // If either reporter were still armed, it would abort the test process.
+        let dispatcher = TestDispatcher::new(0);
+        dispatcher.install_hang_reporter_with_timeout(Duration::from_millis(10));
+        dispatcher.install_hang_reporter_with_timeout(Duration::from_secs(60 * 60));
+        thread::sleep(Duration::from_millis(100));
+        drop(dispatcher);
+
+        let dispatcher = TestDispatcher::new(0);
+        dispatcher.install_hang_reporter_with_timeout(Duration::from_millis(10));
+        drop(dispatcher);
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_frozen_clock_holds_back_timers() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        for secs in [0, 1] {
+            executor
+                .spawn({
+                    let executor = executor.clone();
+                    let fired = fired.clone();
+                    async move {
+                        executor.timer(Duration::from_secs(secs)).await;
+                        fired.lock().push(secs);
+                    }
+                })
+                .detach();
+        }
+
+        dispatcher.freeze_clock();
+        dispatcher.run_until_parked();
+        dispatcher.advance_clock(Duration::from_secs(2));
+        assert_eq!(dispatcher.advance_clock_to_next_timer(), None);
+        assert_eq!(executor.now(), Duration::ZERO);
+        assert!(fired.lock().is_empty());
+
+        dispatcher.unfreeze_clock();
+        dispatcher.run_until_parked();
+        assert_eq!(*fired.lock(), [0]);
+        dispatcher.advance_clock(Duration::from_secs(1));
+        assert_eq!(*fired.lock(), [0, 1]);
+    }
+
+    #[test]
+    fn test_round_robin_foreground_is_fair() {
+        let dispatcher = TestDispatcher::new(0);
+        dispatcher.set_round_robin_foreground(true);
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let mut ids = Vec::new();
+        for name in ["a", "b", "c"] {
+            let fork = dispatcher.fork();
+            ids.push(fork.id());
+            let foreground = ForegroundExecutor::new(Arc::new(fork));
+            for ix in 0..3 {
+                foreground
+                    .spawn({
+                        let ran = ran.clone();
+                        async move { ran.lock().push(format!("{name}{ix}")) }
+                    })
+                    .detach();
+            }
+        }
+
+        dispatcher.start_recording();
+        dispatcher.run_until_parked();
+        assert_eq!(
+            *ran.lock(),
+            ["a0", "b0", "c0", "a1", "b1", "c1", "a2", "b2", "c2"]
+        );
+        let report = dispatcher.audit_fairness();
+        assert_eq!(
+            report.foreground_runs,
+            ids.iter().map(|id| (*id, 3)).collect::<BTreeMap<_, _>>()
+        );
+        assert_eq!(report.min_runs(), 3);
+    }
+
+    #[test]
+    fn test_profile_report_counts_runs_by_label() {
+        let dispatcher = TestDispatcher::new(0);
+        let executor = BackgroundExecutor::new(Arc::new(dispatcher.clone()));
+        executor
+            .spawn_labeled(TaskLabel::named("worker"), async {})
+            .detach();
+        dispatcher.run_until_parked();
+
+        dispatcher.enable_profiling();
+        for _ in 0..3 {
+            executor
+                .spawn_labeled(TaskLabel::named("worker"), async {})
+                .detach();
+        }
+        executor.spawn(async {}).detach();
+        dispatcher.run_until_parked();
+        assert_eq!(
+            dispatcher.profile_report(),
+            [("worker", 3), ("(unlabeled)", 1)]
+        );
+
+        dispatcher.disable_profiling();
+        assert!(dispatcher.profile_report().is_empty());
     }
 }
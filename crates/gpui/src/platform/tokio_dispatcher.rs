@@ -0,0 +1,111 @@
+use crate::{platform::run_on_main_thread, PlatformDispatcher, Priority, TaskLabel};
+use async_task::Runnable;
+use parking::{Parker, Unparker};
+use parking_lot::Mutex;
+use std::{
+    sync::mpsc,
+    thread::{self, ThreadId},
+    time::Duration,
+};
+
+/// A dispatcher that runs background work and timers on a tokio runtime, for using gpui's
+/// executors without a platform, see `BackgroundExecutor::with_tokio`.
+///
+/// The thread that creates it acts as the main thread. Nothing else drives that thread, so
+/// its runnables are queued until it runs them in `tick`, which it does while blocked on the
+/// executor.
+pub(crate) struct TokioDispatcher {
+    handle: tokio::runtime::Handle,
+    main_thread_id: ThreadId,
+    main_sender: mpsc::Sender<(Runnable, Option<TaskLabel>)>,
+    main_receiver: Mutex<mpsc::Receiver<(Runnable, Option<TaskLabel>)>>,
+    parker: Mutex<Parker>,
+    unparker: Unparker,
+}
+
+impl TokioDispatcher {
+    pub fn new(handle: tokio::runtime::Handle) -> Self {
+        let (main_sender, main_receiver) = mpsc::channel();
+        let parker = Parker::new();
+        let unparker = parker.unparker();
+        Self {
+            handle,
+            main_thread_id: thread::current().id(),
+            main_sender,
+            main_receiver: Mutex::new(main_receiver),
+            parker: Mutex::new(parker),
+            unparker,
+        }
+    }
+}
+
+impl PlatformDispatcher for TokioDispatcher {
+    fn is_main_thread(&self) -> bool {
+        thread::current().id() == self.main_thread_id
+    }
+
+    fn dispatch(&self, runnable: Runnable, _: Option<TaskLabel>, _: Priority) {
+        self.handle.spawn(async move {
+            runnable.run();
+        });
+    }
+
+    fn dispatch_on_main_thread(&self, runnable: Runnable, label: Option<TaskLabel>) {
+        self.main_sender
+            .send((runnable, label))
+            .expect("Main thread is gone");
+        // Wake the main thread in case it's blocked on the executor.
+        self.unparker.unpark();
+    }
+
+    fn dispatch_after(&self, duration: Duration, runnable: Runnable) {
+        self.handle.spawn(async move {
+            tokio::time::sleep(duration).await;
+            runnable.run();
+        });
+    }
+
+    fn tick(&self, background_only: bool) -> bool {
+        if background_only || !self.is_main_thread() {
+            return false;
+        }
+        let Ok((runnable, label)) = self.main_receiver.lock().try_recv() else {
+            return false;
+        };
+        run_on_main_thread(runnable, label);
+        true
+    }
+
+    fn park(&self) {
+        self.parker.lock().park()
+    }
+
+    fn unparker(&self) -> Unparker {
+        self.unparker.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BackgroundExecutor, ForegroundExecutor};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_tasks_and_timers_run_on_tokio() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let dispatcher: Arc<dyn PlatformDispatcher> =
+            Arc::new(TokioDispatcher::new(runtime.handle().clone()));
+        let executor = BackgroundExecutor::new(dispatcher.clone());
+        let foreground = ForegroundExecutor::new(dispatcher);
+        let task = executor.spawn({
+            let executor = executor.clone();
+            async move {
+                executor.timer(Duration::from_millis(10)).await;
+                7
+            }
+        });
+        let doubled = foreground.spawn(async move { task.await * 2 });
+        assert_eq!(executor.block_on_main(doubled), 14);
+    }
+}
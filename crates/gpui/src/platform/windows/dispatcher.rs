@@ -12,18 +12,21 @@ use parking::Parker;
 use parking_lot::Mutex;
 use windows::Win32::{Foundation::*, System::Threading::*};
 
-use crate::{PlatformDispatcher, TaskLabel};
+use crate::{PlatformDispatcher, Priority, TaskLabel};
 
 pub(crate) struct WindowsDispatcher {
     threadpool: PTP_POOL,
-    main_sender: Sender<Runnable>,
+    main_sender: Sender<(Runnable, Option<TaskLabel>)>,
     parker: Mutex<Parker>,
     main_thread_id: ThreadId,
     dispatch_event: HANDLE,
 }
 
 impl WindowsDispatcher {
-    pub(crate) fn new(main_sender: Sender<Runnable>, dispatch_event: HANDLE) -> Self {
+    pub(crate) fn new(
+        main_sender: Sender<(Runnable, Option<TaskLabel>)>,
+        dispatch_event: HANDLE,
+    ) -> Self {
         let parker = Mutex::new(Parker::new());
         let threadpool = unsafe {
             let ret = CreateThreadpool(None);
@@ -74,16 +77,16 @@ impl PlatformDispatcher for WindowsDispatcher {
         current().id() == self.main_thread_id
     }
 
-    fn dispatch(&self, runnable: Runnable, label: Option<TaskLabel>) {
+    fn dispatch(&self, runnable: Runnable, label: Option<TaskLabel>, _: Priority) {
         self.dispatch_on_threadpool(runnable);
         if let Some(label) = label {
             log::debug!("TaskLabel: {label:?}");
         }
     }
 
-    fn dispatch_on_main_thread(&self, runnable: Runnable) {
+    fn dispatch_on_main_thread(&self, runnable: Runnable, label: Option<TaskLabel>) {
         self.main_sender
-            .send(runnable)
+            .send((runnable, label))
             .inspect_err(|e| log::error!("Dispatch failed: {e}"))
             .ok();
         unsafe { SetEvent(self.dispatch_event) }.ok();
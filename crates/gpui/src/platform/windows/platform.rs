@@ -36,6 +36,7 @@ use windows::{
     },
 };
 
+use crate::platform::run_on_main_thread;
 use crate::*;
 
 pub(crate) struct WindowsPlatform {
@@ -56,7 +57,7 @@ pub(crate) struct WindowsPlatformSystemSettings {
 pub(crate) struct WindowsPlatformInner {
     background_executor: BackgroundExecutor,
     pub(crate) foreground_executor: ForegroundExecutor,
-    main_receiver: flume::Receiver<Runnable>,
+    main_receiver: flume::Receiver<(Runnable, Option<TaskLabel>)>,
     text_system: Arc<WindowsTextSystem>,
     callbacks: Mutex<Callbacks>,
     pub raw_window_handles: RwLock<SmallVec<[HWND; 4]>>,
@@ -149,7 +150,7 @@ impl WindowsPlatform {
         unsafe {
             OleInitialize(None).expect("unable to initialize Windows OLE");
         }
-        let (main_sender, main_receiver) = flume::unbounded::<Runnable>();
+        let (main_sender, main_receiver) = flume::unbounded::<(Runnable, Option<TaskLabel>)>();
         let dispatch_event =
             OwnedHandle::new(unsafe { CreateEventW(None, false, false, None) }.unwrap());
         let dispatcher = Arc::new(WindowsDispatcher::new(main_sender, dispatch_event.to_raw()));
@@ -177,8 +178,8 @@ impl WindowsPlatform {
     }
 
     fn run_foreground_tasks(&self) {
-        for runnable in self.inner.main_receiver.drain() {
-            runnable.run();
+        for (runnable, label) in self.inner.main_receiver.drain() {
+            run_on_main_thread(runnable, label);
         }
     }
 
@@ -27,9 +27,9 @@
 //! ```
 use crate::{Entity, Subscription, TestAppContext, TestDispatcher};
 use futures::StreamExt as _;
-use rand::prelude::*;
 use smol::channel;
 use std::{
+    any::Any,
     env,
     panic::{self, RefUnwindSafe},
 };
@@ -58,7 +58,7 @@ pub fn run_test(
                 eprintln!("seed = {seed}");
             }
             let result = panic::catch_unwind(|| {
-                let dispatcher = TestDispatcher::new(StdRng::seed_from_u64(seed));
+                let dispatcher = TestDispatcher::new(seed);
                 test_fn(dispatcher, seed);
             });
 
@@ -83,6 +83,47 @@ pub fn run_test(
     }
 }
 
+/// Run `f` with a dispatcher seeded with each of `0..iterations`, for fuzzing code that
+/// doesn't need a full app context. On the first failure, this tries to shrink it by
+/// re-running the failing seed with smaller random delays, prints a line for reproducing it,
+/// and then re-raises the original panic.
+pub fn run_fuzz(iterations: u64, f: impl RefUnwindSafe + Fn(TestDispatcher)) {
+    if let Some((repro, error)) = find_fuzz_failure(iterations, &f) {
+        eprintln!("{repro}");
+        panic::resume_unwind(error);
+    }
+}
+
+/// Find the first seed for which `f` panics, returning a line describing how to reproduce
+/// the failure along with its panic.
+fn find_fuzz_failure(
+    iterations: u64,
+    f: &(impl RefUnwindSafe + Fn(TestDispatcher)),
+) -> Option<(String, Box<dyn Any + Send>)> {
+    for seed in 0..iterations {
+        let Err(error) = panic::catch_unwind(|| f(TestDispatcher::new(seed))) else {
+            continue;
+        };
+
+        let mut repro = format!("failing seed: {seed} (SEED={seed}, TestDispatcher::new({seed}))");
+        for max_delay in [5, 2, 1, 0] {
+            let result = panic::catch_unwind(|| {
+                let dispatcher = TestDispatcher::new(seed);
+                dispatcher.set_delay_range(0..max_delay);
+                f(dispatcher);
+            });
+            if result.is_ok() {
+                break;
+            }
+            repro = format!(
+                "failing seed: {seed} (SEED={seed}, TestDispatcher::new({seed})) with set_delay_range(0..{max_delay})"
+            );
+        }
+        return Some((repro, error));
+    }
+    None
+}
+
 /// A test struct for converting an observation callback into a stream.
 pub struct Observation<T> {
     rx: channel::Receiver<T>,
@@ -111,3 +152,23 @@ pub fn observe<T: 'static>(entity: &impl Entity<T>, cx: &mut TestAppContext) ->
 
     Observation { rx, _subscription }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuzz_failure_reports_the_failing_seed() {
+        let (repro, error) = find_fuzz_failure(10, &|dispatcher: TestDispatcher| {
+            assert_ne!(dispatcher.seed(), 3, "seed 3 fails");
+        })
+        .unwrap();
+        assert!(
+            repro.starts_with("failing seed: 3 (SEED=3, TestDispatcher::new(3))"),
+            "{repro}"
+        );
+        assert!(!repro.contains("StdRng"), "{repro}");
+        let message = error.downcast_ref::<String>().unwrap();
+        assert!(message.contains("seed 3 fails"), "{message}");
+    }
+}
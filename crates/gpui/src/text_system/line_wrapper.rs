@@ -144,11 +144,10 @@ impl Boundary {
 mod tests {
     use super::*;
     use crate::{font, TestAppContext, TestDispatcher, TextRun, WindowTextSystem, WrapBoundary};
-    use rand::prelude::*;
 
     #[test]
     fn test_wrap_line() {
-        let dispatcher = TestDispatcher::new(StdRng::seed_from_u64(0));
+        let dispatcher = TestDispatcher::new(0);
         let cx = TestAppContext::new(dispatcher, None);
 
         cx.update(|cx| {